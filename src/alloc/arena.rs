@@ -0,0 +1,184 @@
+//! Arenas whose values are freed all at once, with pointers that can be checked at runtime.
+
+use std::cell::Cell;
+use std::rc::{Rc, Weak};
+
+use typed_arena::Arena;
+
+/// A model for checking that pointers into an arena are not used after the arena is freed.
+/// This comes in safe and unchecked versions.
+pub trait CheckModel {
+    /// Held by an arena for as long as its values live.
+    type Token;
+    /// Held by each pointer into an arena.
+    type Ptr: CheckPtr;
+
+    fn token() -> Self::Token;
+
+    /// Gets a CheckPtr for the arena holding the given token.
+    fn ptr(token: &Self::Token) -> Self::Ptr;
+}
+
+/// A pointer to something that cannot be dereferenced. It can optionally be implemented
+/// to check that an arena is still alive, to enforce runtime invariants.
+pub trait CheckPtr: Clone {
+    /// A no-op, but optionally panics if the arena this handle refers to was freed.
+    fn check(&self);
+}
+
+/// Helper for SafeCheckModel.
+#[derive(Clone)]
+pub struct SafeCheckPtr {
+    inner: Weak<()>,
+}
+
+impl CheckPtr for SafeCheckPtr {
+    fn check(&self) {
+        assert!(self.inner.upgrade().is_some(), "pointer outlived its arena");
+    }
+}
+
+/// A CheckModel whose pointers panic if used after their arena is freed.
+pub struct SafeCheckModel;
+
+impl CheckModel for SafeCheckModel {
+    type Token = Rc<()>;
+    type Ptr = SafeCheckPtr;
+
+    fn token() -> Rc<()> {
+        Rc::new(())
+    }
+
+    fn ptr(token: &Rc<()>) -> SafeCheckPtr {
+        SafeCheckPtr {
+            inner: Rc::downgrade(token),
+        }
+    }
+}
+
+/// Helper for UncheckedModel.
+#[derive(Clone)]
+pub struct UncheckedPtr;
+
+impl CheckPtr for UncheckedPtr {
+    fn check(&self) {}
+}
+
+/// A CheckModel with no runtime checks. Using a pointer after its arena is freed is undefined behavior.
+pub struct UncheckedModel;
+
+impl CheckModel for UncheckedModel {
+    type Token = ();
+    type Ptr = UncheckedPtr;
+
+    fn token() {}
+
+    fn ptr(_: &()) -> UncheckedPtr {
+        UncheckedPtr
+    }
+}
+
+/// A typed arena. Values are dropped together, when the arena is dropped.
+pub struct CheckedArena<T, M: CheckModel> {
+    values: Arena<T>,
+    len: Cell<usize>,
+    token: M::Token,
+}
+
+impl<T, M: CheckModel> CheckedArena<T, M> {
+    pub fn new() -> Self {
+        CheckedArena {
+            values: Arena::new(),
+            len: Cell::new(0),
+            token: M::token(),
+        }
+    }
+
+    pub fn alloc(&self, t: T) -> ArenaPtr<T, M> {
+        self.len.set(self.len.get() + 1);
+
+        ArenaPtr {
+            value: self.values.alloc(t),
+            check: M::ptr(&self.token),
+        }
+    }
+
+    /// The number of values allocated from this arena.
+    pub fn len(&self) -> usize {
+        self.len.get()
+    }
+}
+
+/// A pointer to a value in a CheckedArena.
+pub struct ArenaPtr<T, M: CheckModel> {
+    value: *const T,
+    check: M::Ptr,
+}
+
+impl<T, M: CheckModel> ArenaPtr<T, M> {
+    /// Gets the value this pointer points to. The arena must outlive the returned reference.
+    pub fn get(&self) -> &T {
+        self.check.check();
+
+        unsafe {
+            &*self.value
+        }
+    }
+
+    /// True if the given pointers point to the same value.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl<T, M: CheckModel> Clone for ArenaPtr<T, M> {
+    fn clone(&self) -> Self {
+        ArenaPtr {
+            value: self.value,
+            check: self.check.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn test_alloc() {
+        let arena: CheckedArena<_, SafeCheckModel> = CheckedArena::new();
+        let p1 = arena.alloc(Cell::new(1));
+        let p2 = arena.alloc(Cell::new(2));
+        p1.clone().get().set(3);
+
+        assert_eq!(p1.get().get(), 3);
+        assert_eq!(p2.get().get(), 2);
+        assert!(p1.ptr_eq(&p1.clone()) && !p1.ptr_eq(&p2));
+        assert_eq!(arena.len(), 2);
+    }
+
+    #[test]
+    fn test_drop_together() {
+        let counter = Rc::new(());
+        let arena: CheckedArena<_, SafeCheckModel> = CheckedArena::new();
+        for _ in 0..10 {
+            arena.alloc(counter.clone());
+        }
+
+        assert_eq!(Rc::strong_count(&counter), 11);
+        drop(arena);
+        assert_eq!(Rc::strong_count(&counter), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "pointer outlived its arena")]
+    fn test_checked_after_free() {
+        let arena: CheckedArena<_, SafeCheckModel> = CheckedArena::new();
+        let p = arena.alloc(1);
+        drop(arena);
+        p.get();
+    }
+}
@@ -1,4 +1,4 @@
-//! Right now, these are not used, they're just a design sketch.
+//! Right now, these are not used, they're just a design sketch. The check models now live in arena.rs.
 
 use std::cell::UnsafeCell;
 use std::mem::{forget, size_of, swap};
@@ -7,6 +7,8 @@ use std::rc::{Rc, Weak};
 
 use typed_arena::Arena;
 
+use alloc::arena::{CheckModel, CheckPtr};
+
 /// An object whose lifetime may be tied to the existence of a different 'owning' object.
 pub trait Scoped {
     type T;
@@ -18,23 +20,6 @@ pub trait Scoped {
     fn get_mut<X>(&mut self, owner: &X) -> &mut T;
 }
 
-/// A model trait providing CheckPtrs. This comes in safe and unsafe versions.
-// TODO: a whole pointer safety model?
-pub trait CheckModel {
-    /// The CheckPtr type.
-    type Ptr: CheckPtr;
-
-    /// Get a WeakPtr to the given value from this WeakModel.
-    fn ptr<X>(x: &X) -> Self::Ptr;
-}
-
-/// A pointer to something that cannot be dereferenced. It can optionally be implemented
-/// to help check pointer equality, to enforce runtime invariants.
-pub trait CheckPtr: 'static + Send + Sized {
-    /// A no-op, but optionally panics if the given object doesn't match the one this handle refers to.
-    fn assert_eq<X>(&self, x: &X);
-}
-
 /// An allocated T that belongs to an instance of an Alloc. You must hold a reference to the Alloc
 /// to access the information within.
 ///
@@ -97,36 +82,6 @@ impl<M: CheckModel, T: ?Sized> CheckScoped<M, T> {
     }
 }
 
-/// Helper for SafeAlloc.
-pub struct SafeCheckPtr {
-    inner: usize,
-}
-
-impl SafeCheckPtr {
-    fn new<X>(x: &X) -> Self {
-        SafeCheckPtr {
-            inner: x as *const _ as usize,
-        }
-    }
-}
-
-impl CheckPtr for SafeCheckPtr {
-    fn assert_eq<X>(&self, x: &X) {
-        assert!(self.inner == x as *const _ as usize);
-    }
-}
-
-/// A CheckModel that uses safe pointers.
-pub struct SafeCheckModel;
-
-impl CheckModel for SafeCheckModel  {
-    type Ptr = SafeCheckPtr;
-
-    fn ptr<X>(x: &X) -> Self::Ptr {
-        SafeCheckPtr::new(x)
-    }
-}
-
 /// A allocator with runtime checks enabled, returned by safe_alloc.
 pub struct ArenaAlloc<T> {
     inner: Arena<T>,
@@ -0,0 +1,76 @@
+//! Byte buffers that callers fill in place and then hand over without copying.
+
+use std::borrow::{Borrow, BorrowMut};
+use std::rc::{Rc, Weak};
+
+use data::RcBytes;
+use data::util::ByteWriter;
+
+/// A writable buffer for a key or value, allocated from a transaction. Borrow it mutably through `ScopedMut`,
+/// or write to it with `writer`. It may start with bytes given at allocation, such as a key prefix, which
+/// are not writable. Unwritten bytes are zero.
+pub struct AllocBytes {
+    bytes: Box<[u8]>,
+    /// The length of the given bytes, which the writable part follows.
+    start: usize,
+    /// The token of the transaction this buffer was allocated from.
+    txn: Weak<()>,
+}
+
+impl AllocBytes {
+    /// Allocates the given bytes followed by `len` zeroes, for the transaction holding the given token.
+    pub fn new(txn: &Rc<()>, given: &[u8], len: usize) -> AllocBytes {
+        let mut bytes = Vec::with_capacity(given.len() + len);
+        bytes.extend_from_slice(given);
+        bytes.resize(given.len() + len, 0);
+
+        AllocBytes {
+            bytes: bytes.into_boxed_slice(),
+            start: given.len(),
+            txn: Rc::downgrade(txn),
+        }
+    }
+
+    /// The length of the writable part of this buffer.
+    pub fn len(&self) -> usize {
+        self.bytes.len() - self.start
+    }
+
+    /// Gets the bytes given at allocation.
+    pub fn given(&self) -> &[u8] {
+        &self.bytes[..self.start]
+    }
+
+    /// True if this buffer was allocated from the transaction holding the given token.
+    pub fn is_from(&self, txn: &Rc<()>) -> bool {
+        self.txn.upgrade().map_or(false, |t| Rc::ptr_eq(&t, txn))
+    }
+
+    /// Gets a ByteWriter that writes this buffer from the start of its writable part.
+    pub fn writer(&mut self) -> ByteWriter<'_> {
+        ByteWriter::wrap(&mut self.bytes[self.start..])
+    }
+
+    /// Moves these bytes, given ones included, into an RcBytes without copying them.
+    pub fn into_rcbytes(self) -> RcBytes {
+        RcBytes::from_box(self.bytes)
+    }
+}
+
+impl Borrow<[u8]> for AllocBytes {
+    fn borrow(&self) -> &[u8] {
+        &self.bytes[self.start..]
+    }
+}
+
+impl BorrowMut<[u8]> for AllocBytes {
+    fn borrow_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes[self.start..]
+    }
+}
+
+impl AsRef<[u8]> for AllocBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes[self.start..]
+    }
+}
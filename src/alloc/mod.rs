@@ -1,3 +1,9 @@
+mod arena;
+pub use self::arena::*;
+
+mod bytes;
+pub use self::bytes::*;
+
 mod scoped;
 pub use self::scoped::*;
 
@@ -22,11 +22,21 @@ impl Counter {
         Counter { data: self.data.wrapping_add(1) }
     }
 
+    /// Decrements this counter by one, wrapping around to u64::max_value() if needed.
+    pub fn dec(self) -> Counter {
+        Counter { data: self.data.wrapping_sub(1) }
+    }
+
     /// Returns this counter as an array of bytes, in big-endian.
     pub fn to_bytes(self) -> [u8; 8] {
         unsafe { std::mem::transmute(self.data.to_be()) }
     }
 
+    /// Reads a counter from an array of bytes, in big-endian.
+    pub fn from_bytes(b: [u8; 8]) -> Counter {
+        Counter { data: u64::from_be_bytes(b) }
+    }
+
     /// Returns true if this counter is less than the given counter. A counter x is 'less than'
     /// a counter y if y - x < u64::max_value() / 2 - 1, using wrapping arithmetic.
     /// Informally, x must be "behind" y by less than maximum distance.
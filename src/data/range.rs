@@ -4,6 +4,7 @@ use std::cmp::Ordering;
 /// A range of keys, exclusive or inclusive.
 ///
 /// TODO: consider using alloc.rs for these.
+#[derive(Clone)]
 pub struct Range {
     open_left: bool,
     open_right: bool,
@@ -46,6 +47,16 @@ impl Range {
         }
     }
 
+    /// Returns an empty range at the given key: every key is either before or after it.
+    pub fn empty(at: Box<[u8]>) -> Self {
+        Range {
+            open_left: false,
+            open_right: true,
+            left: at.clone(),
+            right: at,
+        }
+    }
+
     /// N. B.: This is the only range constructor where left and right are allowed to be equal.
     pub fn closed(left: Box<[u8]>, right: Box<[u8]>) -> Self {
         assert!(left <= right);
@@ -58,21 +69,105 @@ impl Range {
         }
     }
 
-    pub fn contains<K: Borrow<[u8]>>(&self, k: &K) -> bool {
-        match k.borrow().cmp(self.left.borrow()) {
-            Ordering::Less => return false,
-            Ordering::Equal => if self.open_left {
-                return false
-            } else {
-                () // proceed to right comparison
-            },
-            Ordering::Greater => (), // proceed to right comparison
-        }
+    pub fn left(&self) -> &[u8] {
+        self.left.borrow()
+    }
 
-        match k.borrow().cmp(self.right.borrow()) {
+    pub fn right(&self) -> &[u8] {
+        self.right.borrow()
+    }
+
+    /// Returns true if the given key is less than every key in this range.
+    pub fn is_before(&self, k: &[u8]) -> bool {
+        match k.cmp(self.left.borrow()) {
             Ordering::Less => true,
-            Ordering::Equal => !self.open_right,
+            Ordering::Equal => self.open_left,
             Ordering::Greater => false,
         }
     }
+
+    /// Returns true if the given key is greater than every key in this range.
+    pub fn is_after(&self, k: &[u8]) -> bool {
+        match k.cmp(self.right.borrow()) {
+            Ordering::Less => false,
+            Ordering::Equal => self.open_right,
+            Ordering::Greater => true,
+        }
+    }
+
+    pub fn contains<K: Borrow<[u8]>>(&self, k: &K) -> bool {
+        !self.is_before(k.borrow()) && !self.is_after(k.borrow())
+    }
+
+    /// Returns a copy of this range with the given prefix prepended to both ends.
+    pub fn with_prefix(&self, prefix: &[u8]) -> Range {
+        let prepend = |b: &[u8]| {
+            let mut r = prefix.to_vec();
+            r.extend_from_slice(b);
+            r.into_boxed_slice()
+        };
+
+        Range {
+            open_left: self.open_left,
+            open_right: self.open_right,
+            left: prepend(self.left.borrow()),
+            right: prepend(self.right.borrow()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Range;
+
+    fn bytes(s: &str) -> Box<[u8]> {
+        s.as_bytes().to_vec().into_boxed_slice()
+    }
+
+    #[test]
+    fn test_contains() {
+        let r = Range::right_open(bytes("b"), bytes("d"));
+        assert!(!r.contains(&bytes("a")));
+        assert!(r.contains(&bytes("b")));
+        assert!(r.contains(&bytes("c")));
+        assert!(r.contains(&bytes("cz")));
+        assert!(!r.contains(&bytes("d")));
+
+        let r = Range::left_open(bytes("b"), bytes("d"));
+        assert!(!r.contains(&bytes("b")));
+        assert!(r.contains(&bytes("ba")));
+        assert!(r.contains(&bytes("d")));
+        assert!(!r.contains(&bytes("da")));
+
+        let r = Range::closed(bytes("b"), bytes("b"));
+        assert!(r.contains(&bytes("b")));
+        assert!(!r.contains(&bytes("ba")));
+
+        let r = Range::empty(bytes("b"));
+        assert!(r.is_before(b"a"));
+        assert!(r.is_after(b"b"));
+        assert!(!r.contains(&bytes("b")));
+    }
+
+    #[test]
+    fn test_before_after() {
+        let r = Range::open(bytes("b"), bytes("d"));
+        assert!(r.is_before(b"a"));
+        assert!(r.is_before(b"b"));
+        assert!(!r.is_before(b"c"));
+        assert!(!r.is_after(b"c"));
+        assert!(r.is_after(b"d"));
+        assert!(r.is_after(b"e"));
+    }
+
+    #[test]
+    fn test_with_prefix() {
+        let r = Range::right_open(bytes("b"), bytes("d")).with_prefix(b"x");
+        assert!(!r.contains(&bytes("b")));
+        assert!(r.contains(&bytes("xb")));
+        assert!(r.contains(&bytes("xc")));
+        assert!(!r.contains(&bytes("xd")));
+        assert_eq!(r.left(), b"xb");
+        assert_eq!(r.right(), b"xd");
+    }
 }
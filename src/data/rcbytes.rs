@@ -26,6 +26,13 @@ impl RcBytes {
         }
     }
 
+    /// Wraps the given boxed bytes without copying them.
+    pub fn from_box(b: Box<[u8]>) -> RcBytes {
+        RcBytes {
+            data: RcSlice::new(b),
+        }
+    }
+
     // TODO these are redundant
     pub fn from_key<K: Key + ?Sized>(k: &K) -> RcBytes {
         Self::new(k.bytes())
@@ -42,6 +49,18 @@ impl RcBytes {
             data: self.data.downgrade(),
         }
     }
+
+    /// Mutably borrows these bytes, if no other RcBytes or WeakBytes share them.
+    pub fn get_mut(&mut self) -> Option<&mut [u8]> {
+        self.data.get_mut()
+    }
+
+    /// Gets these bytes from the given index on, without copying them.
+    pub fn slice_from(&self, start: usize) -> RcBytes {
+        RcBytes {
+            data: self.data.slice_from(start),
+        }
+    }
 }
 
 impl Borrow<[u8]> for RcBytes {
@@ -83,6 +83,22 @@ impl<T> RcSlice<T> {
             counts: Rc::downgrade(&self.counts)
         }
     }
+
+    /// Returns a mutable reference to the contained slice, if there are no other `RcSlice`s
+    /// or `WeakSlice`s pointing to the same allocation. Like `Rc::get_mut`.
+    pub fn get_mut(&mut self) -> Option<&mut [T]> {
+        let data = self.data as *mut [T];
+        // No other pointer can see the allocation, so neither can they see our part of it.
+        Rc::get_mut(&mut self.counts).map(|_| unsafe { &mut *data })
+    }
+
+    /// Returns the part of this slice from the given index on, sharing its allocation.
+    pub fn slice_from(&self, start: usize) -> RcSlice<T> {
+        RcSlice {
+            data: &self[start..],
+            counts: self.counts.clone(),
+        }
+    }
 }
 
 impl<T> Deref for RcSlice<T> {
@@ -167,6 +183,28 @@ mod tests {
         assert_eq!(y[0].get(), true);
     }
 
+    #[test]
+    fn test_get_mut() {
+        let mut x = RcSlice::new(Box::new([1, 2, 3]));
+        x.get_mut().unwrap()[0] = 4;
+        assert_eq!(&*x, &[4, 2, 3]);
+
+        let w = x.downgrade();
+        assert!(x.get_mut().is_none());
+        drop(w);
+
+        let y = x.clone();
+        assert!(x.get_mut().is_none());
+        drop(y);
+        assert!(x.get_mut().is_some());
+
+        let mut z = x.slice_from(1);
+        assert!(z.get_mut().is_none());
+        drop(x);
+        z.get_mut().unwrap()[0] = 5;
+        assert_eq!(&*z, &[5, 3]);
+    }
+
     #[test]
     fn test_upgrade_downgrade() {
         let x = RcSlice::new(Box::new([1]));
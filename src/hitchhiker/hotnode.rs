@@ -1,6 +1,5 @@
-struct NodeAddress {
-
-}
+// Persistent nodes are stored in this format, addressed by NodeAddress. See tree::page.
+use tree::page::NodeAddress;
 
 struct HitchhikerChildPointer {
 	addr: NodeAddress,
@@ -17,5 +16,5 @@ struct HitchhikerHotNode {
 
 	// The on-disk format of the key table is such that in-memory operations can be stored inline.
 
-	// TODO: implement this format.
+	// TODO: implement this format for hot nodes. tree::page implements it for persistent nodes.
 }
@@ -44,6 +44,8 @@ pub use data::Range;
 
 pub mod tdfuture;
 
+pub mod storage;
+
 // #[cfg(test)]
 // TODO: isolate with a feature
 // pub mod testlib;
@@ -0,0 +1,274 @@
+use std::cell::{Cell, RefCell};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use futures::future::{self, FutureResult};
+
+use data::RcBytes;
+
+use traits::TreeError;
+
+use super::{checksum, BlockAddress, BlockStore};
+
+const SUPERBLOCK_MAGIC: &'static [u8; 4] = b"HTSB";
+const SUPERBLOCK_VERSION: u16 = 1;
+const SUPERBLOCK_SIZE: u64 = 128;
+const SUPERBLOCK_HEADER_SIZE: usize = 16;
+/// The largest root record a FileStore can hold.
+pub const MAX_ROOT_SIZE: usize = SUPERBLOCK_SIZE as usize - SUPERBLOCK_HEADER_SIZE - 8;
+
+/// A BlockStore in a file.
+///
+/// The file starts with two superblocks, which hold the root record. Each new root record goes in the older
+/// superblock, so a torn write leaves the newer one intact. A superblock holds magic `HTSB`, a version (u16),
+/// the length of the root record (u16), a sequence number (u64), the root record, and a checksum (u64) of
+/// everything before it. The valid superblock with the highest sequence number wins.
+///
+/// Blocks are appended after the superblocks, each prefixed by its length as a u32, and addressed by their offset.
+/// Every integer is big-endian.
+///
+/// IO is synchronous, so the returned futures are always complete.
+pub struct FileStore {
+    file: RefCell<File>,
+    len: Cell<u64>,
+    /// The sequence number of the newest superblock.
+    sequence: Cell<u64>,
+}
+
+impl FileStore {
+    /// Opens the store in the given file, creating the file if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FileStore, TreeError> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+        let mut len = file.metadata()?.len();
+
+        if len == 0 {
+            file.write_all(&[0; 2 * SUPERBLOCK_SIZE as usize])?;
+            file.sync_data()?;
+            len = 2 * SUPERBLOCK_SIZE;
+        } else if len < 2 * SUPERBLOCK_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a store file").into())
+        }
+
+        let r = FileStore {
+            file: RefCell::new(file),
+            len: Cell::new(len),
+            sequence: Cell::new(0),
+        };
+        let sequence = r.newest_superblock()?.map_or(0, |(sequence, _)| sequence);
+        r.sequence.set(sequence);
+
+        Ok(r)
+    }
+
+    /// The length of the underlying file, in bytes.
+    pub fn len(&self) -> u64 {
+        self.len.get()
+    }
+
+    fn read_block(&self, addr: BlockAddress) -> io::Result<RcBytes> {
+        if addr.0 < 2 * SUPERBLOCK_SIZE || addr.0.checked_add(4).map_or(true, |end| end > self.len.get()) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no block at address"));
+        }
+
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(addr.0))?;
+        let mut len = [0; 4];
+        file.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len);
+        // A corrupt length must not make us allocate more than the file holds.
+        if addr.0 + 4 + len as u64 > self.len.get() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "block runs past the end of the store"));
+        }
+        let mut r = vec![0; len as usize];
+        file.read_exact(&mut r)?;
+
+        Ok(RcBytes::from_box(r.into_boxed_slice()))
+    }
+
+    fn write_block(&self, block: &[u8]) -> io::Result<BlockAddress> {
+        if block.len() > u32::max_value() as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "block too large"));
+        }
+
+        let addr = self.len.get();
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(addr))?;
+        file.write_all(&(block.len() as u32).to_be_bytes())?;
+        file.write_all(block)?;
+        self.len.set(addr + 4 + block.len() as u64);
+
+        Ok(BlockAddress(addr))
+    }
+
+    /// Reads the superblock in the given slot, returning its sequence number and root record, or None if the
+    /// superblock is empty, torn or corrupt.
+    fn read_superblock(&self, slot: u64) -> io::Result<Option<(u64, RcBytes)>> {
+        let mut sb = [0; SUPERBLOCK_SIZE as usize];
+        {
+            let mut file = self.file.borrow_mut();
+            file.seek(SeekFrom::Start(slot * SUPERBLOCK_SIZE))?;
+            file.read_exact(&mut sb)?;
+        }
+
+        let mut version = [0; 2];
+        version.copy_from_slice(&sb[4..6]);
+        let mut root_len = [0; 2];
+        root_len.copy_from_slice(&sb[6..8]);
+        let root_len = u16::from_be_bytes(root_len) as usize;
+        if &sb[..4] != &SUPERBLOCK_MAGIC[..] || u16::from_be_bytes(version) != SUPERBLOCK_VERSION ||
+            root_len > MAX_ROOT_SIZE {
+            return Ok(None)
+        }
+
+        let end = SUPERBLOCK_HEADER_SIZE + root_len;
+        let mut sum = [0; 8];
+        sum.copy_from_slice(&sb[end..end + 8]);
+        if checksum(&sb[..end]) != u64::from_be_bytes(sum) {
+            return Ok(None)
+        }
+
+        let mut sequence = [0; 8];
+        sequence.copy_from_slice(&sb[8..16]);
+        Ok(Some((u64::from_be_bytes(sequence), RcBytes::new(&sb[SUPERBLOCK_HEADER_SIZE..end]))))
+    }
+
+    fn newest_superblock(&self) -> io::Result<Option<(u64, RcBytes)>> {
+        let (sb0, sb1) = (self.read_superblock(0)?, self.read_superblock(1)?);
+        Ok(match (sb0, sb1) {
+            (Some(sb0), Some(sb1)) => Some(if sb0.0 > sb1.0 { sb0 } else { sb1 }),
+            (sb0, sb1) => sb0.or(sb1),
+        })
+    }
+
+    fn write_superblock(&self, root: &[u8]) -> io::Result<()> {
+        if root.len() > MAX_ROOT_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "root record too large"));
+        }
+
+        let mut file = self.file.borrow_mut();
+        // Blocks must be durable before the root can point at them.
+        file.sync_data()?;
+
+        let sequence = self.sequence.get() + 1;
+        let mut sb = Vec::with_capacity(SUPERBLOCK_SIZE as usize);
+        sb.extend_from_slice(SUPERBLOCK_MAGIC);
+        sb.extend_from_slice(&SUPERBLOCK_VERSION.to_be_bytes());
+        sb.extend_from_slice(&(root.len() as u16).to_be_bytes());
+        sb.extend_from_slice(&sequence.to_be_bytes());
+        sb.extend_from_slice(root);
+        let sum = checksum(&sb);
+        sb.extend_from_slice(&sum.to_be_bytes());
+
+        file.seek(SeekFrom::Start(sequence % 2 * SUPERBLOCK_SIZE))?;
+        file.write_all(&sb)?;
+        file.sync_data()?;
+        self.sequence.set(sequence);
+
+        Ok(())
+    }
+}
+
+impl BlockStore for FileStore {
+    type GetF = FutureResult<RcBytes, TreeError>;
+    type PutF = FutureResult<BlockAddress, TreeError>;
+    type SyncF = FutureResult<(), TreeError>;
+    type RootF = FutureResult<Option<RcBytes>, TreeError>;
+
+    fn get(&self, addr: BlockAddress) -> Self::GetF {
+        future::result(self.read_block(addr).map_err(TreeError::from))
+    }
+
+    fn put(&self, block: &[u8]) -> Self::PutF {
+        future::result(self.write_block(block).map_err(TreeError::from))
+    }
+
+    fn sync(&self) -> Self::SyncF {
+        future::result(self.file.borrow().sync_data().map_err(TreeError::from))
+    }
+
+    fn root(&self) -> Self::RootF {
+        future::result(self.newest_superblock().map(|sb| sb.map(|(_, root)| root)).map_err(TreeError::from))
+    }
+
+    fn set_root(&self, root: &[u8]) -> Self::SyncF {
+        future::result(self.write_superblock(root).map_err(TreeError::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use futures::Future;
+
+    use storage::temp_path;
+
+    use super::*;
+
+    #[test]
+    fn test_file_store() {
+        let path = temp_path("test_file_store");
+        let (a, b) = {
+            let s = FileStore::open(&path).unwrap();
+            let a = s.put(b"foo").wait().unwrap();
+            let b = s.put(&[7; 1000][..]).wait().unwrap();
+            assert_eq!(&*s.get(a).wait().unwrap(), b"foo");
+            s.sync().wait().unwrap();
+            (a, b)
+        };
+
+        // Blocks survive reopening, and new blocks are appended.
+        let s = FileStore::open(&path).unwrap();
+        assert_eq!(&*s.get(b).wait().unwrap(), &[7; 1000][..]);
+        let c = s.put(b"bar").wait().unwrap();
+        assert_eq!(&*s.get(a).wait().unwrap(), b"foo");
+        assert_eq!(&*s.get(c).wait().unwrap(), b"bar");
+        assert!(s.get(BlockAddress(s.len())).wait().is_err());
+        assert!(s.get(BlockAddress(0)).wait().is_err());
+        assert!(s.get(BlockAddress(u64::max_value())).wait().is_err());
+
+        // Corrupt lengths are rejected rather than read past the end of the file.
+        {
+            let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.seek(SeekFrom::Start(c.0)).unwrap();
+            file.write_all(&u32::max_value().to_be_bytes()).unwrap();
+        }
+        assert!(FileStore::open(&path).unwrap().get(c).wait().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_roots() {
+        let path = temp_path("test_file_store_roots");
+        {
+            let s = FileStore::open(&path).unwrap();
+            assert!(s.root().wait().unwrap().is_none());
+            s.set_root(b"one").wait().unwrap();
+            s.set_root(b"two").wait().unwrap();
+            assert_eq!(&*s.root().wait().unwrap().unwrap(), b"two");
+            assert!(s.set_root(&[0; MAX_ROOT_SIZE + 1]).wait().is_err());
+        }
+
+        {
+            let s = FileStore::open(&path).unwrap();
+            assert_eq!(&*s.root().wait().unwrap().unwrap(), b"two");
+            s.set_root(b"three").wait().unwrap();
+        }
+
+        // A torn write of the newest superblock leaves the one before it.
+        {
+            let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.seek(SeekFrom::Start(SUPERBLOCK_SIZE + 20)).unwrap();
+            file.write_all(b"garbage").unwrap();
+        }
+        let s = FileStore::open(&path).unwrap();
+        assert_eq!(&*s.root().wait().unwrap().unwrap(), b"two");
+        s.set_root(b"four").wait().unwrap();
+        assert_eq!(&*FileStore::open(&path).unwrap().root().wait().unwrap().unwrap(), b"four");
+
+        fs::write(&path, b"short").unwrap();
+        assert!(FileStore::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
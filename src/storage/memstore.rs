@@ -0,0 +1,87 @@
+use std::cell::RefCell;
+use std::io;
+
+use futures::future::{self, FutureResult};
+
+use data::RcBytes;
+
+use traits::TreeError;
+
+use super::{BlockAddress, BlockStore};
+
+/// A BlockStore in memory. Blocks live as long as the store.
+pub struct MemStore {
+    blocks: RefCell<Vec<RcBytes>>,
+    root: RefCell<Option<RcBytes>>,
+}
+
+impl MemStore {
+    pub fn new() -> MemStore {
+        MemStore {
+            blocks: RefCell::new(Vec::new()),
+            root: RefCell::new(None),
+        }
+    }
+
+    /// The number of blocks in this store.
+    pub fn len(&self) -> usize {
+        self.blocks.borrow().len()
+    }
+}
+
+impl BlockStore for MemStore {
+    type GetF = FutureResult<RcBytes, TreeError>;
+    type PutF = FutureResult<BlockAddress, TreeError>;
+    type SyncF = FutureResult<(), TreeError>;
+    type RootF = FutureResult<Option<RcBytes>, TreeError>;
+
+    fn get(&self, addr: BlockAddress) -> Self::GetF {
+        match self.blocks.borrow().get(addr.0 as usize) {
+            Some(block) => future::ok(block.clone()),
+            None => future::err(io::Error::new(io::ErrorKind::NotFound, "no block at address").into()),
+        }
+    }
+
+    fn put(&self, block: &[u8]) -> Self::PutF {
+        let mut blocks = self.blocks.borrow_mut();
+        blocks.push(RcBytes::new(block));
+        future::ok(BlockAddress(blocks.len() as u64 - 1))
+    }
+
+    fn sync(&self) -> Self::SyncF {
+        future::ok(())
+    }
+
+    fn root(&self) -> Self::RootF {
+        future::ok(self.root.borrow().clone())
+    }
+
+    fn set_root(&self, root: &[u8]) -> Self::SyncF {
+        *self.root.borrow_mut() = Some(RcBytes::new(root));
+        future::ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+
+    use super::*;
+
+    #[test]
+    fn test_mem_store() {
+        let s = MemStore::new();
+        let a = s.put(b"foo").wait().unwrap();
+        let b = s.put(b"").wait().unwrap();
+
+        assert!(a != b);
+        assert_eq!(&*s.get(a).wait().unwrap(), b"foo");
+        assert_eq!(&*s.get(b).wait().unwrap(), b"");
+        assert!(s.get(BlockAddress(2)).wait().is_err());
+        assert_eq!(s.len(), 2);
+
+        assert!(s.root().wait().unwrap().is_none());
+        s.set_root(b"root").wait().unwrap();
+        assert_eq!(&*s.root().wait().unwrap().unwrap(), b"root");
+    }
+}
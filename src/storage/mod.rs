@@ -0,0 +1,70 @@
+//! Block storage for persistent nodes.
+//!
+//! Stores are external to the tree, and return futures for getting and saving, so they may do IO asynchronously.
+//! Blocks are written once and never overwritten, so trees can write new nodes copy-on-write. Each store also has
+//! a small root record, which is replaced atomically to commit the blocks written before it.
+//!
+//! Writes between commits go to a write-ahead log, which is replayed after a crash.
+
+#[cfg(test)]
+use std::{env, fs, process};
+#[cfg(test)]
+use std::path::PathBuf;
+
+use futures::Future;
+
+use data::RcBytes;
+
+use traits::TreeError;
+
+mod filestore;
+pub use self::filestore::*;
+
+mod memstore;
+pub use self::memstore::*;
+
+mod wal;
+pub use self::wal::*;
+
+/// The address of a block in a BlockStore.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BlockAddress(pub u64);
+
+/// A store of immutable blocks of bytes. Stores are shared by trees and their snapshots, so they take `&self`.
+pub trait BlockStore {
+    type GetF: Future<Item = RcBytes, Error = TreeError> + 'static;
+    type PutF: Future<Item = BlockAddress, Error = TreeError> + 'static;
+    type SyncF: Future<Item = (), Error = TreeError> + 'static;
+    type RootF: Future<Item = Option<RcBytes>, Error = TreeError> + 'static;
+
+    /// Reads the block at the given address.
+    fn get(&self, addr: BlockAddress) -> Self::GetF;
+
+    /// Writes a new block, returning its address.
+    fn put(&self, block: &[u8]) -> Self::PutF;
+
+    /// Makes every block written so far durable.
+    fn sync(&self) -> Self::SyncF;
+
+    /// Reads the root record last written by `set_root`, if any.
+    fn root(&self) -> Self::RootF;
+
+    /// Makes every block written so far durable, then atomically replaces the root record.
+    /// If this fails, the store keeps its old root record.
+    fn set_root(&self, root: &[u8]) -> Self::SyncF;
+}
+
+/// A 64-bit FNV-1a hash, for detecting torn or corrupt writes.
+pub fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Gets a path in the system temp directory, unique to this process and the given name, for tests. Any file
+/// already there, or its write-ahead log, is removed.
+#[cfg(test)]
+pub fn temp_path(name: &str) -> PathBuf {
+    let r = env::temp_dir().join(format!("htree-{}-{}", process::id(), name));
+    fs::remove_file(&r).ok();
+    fs::remove_file(wal_path(&r)).ok();
+    r
+}
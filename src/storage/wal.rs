@@ -0,0 +1,246 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use counter::Counter;
+
+use data::RcBytes;
+
+use traits::TreeError;
+
+use super::checksum;
+
+const PUT: u8 = 0;
+const DELETE: u8 = 1;
+const UPSERT: u8 = 2;
+
+const RECORD_HEADER_SIZE: usize = 17;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalOp {
+    Put,
+    Delete,
+    /// A delta, to be merged by the tree's merge operator.
+    Upsert,
+}
+
+/// Gets the path of the write-ahead log of the tree file at the given path.
+pub fn wal_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut r = path.as_ref().as_os_str().to_owned();
+    r.push(".wal");
+    PathBuf::from(r)
+}
+
+/// A write recorded in a Wal. Keys are full keys of the tree, and deletes have empty values.
+pub struct WalRecord {
+    pub op: WalOp,
+    pub txid: Counter,
+    pub key: RcBytes,
+    pub value: RcBytes,
+}
+
+/// An append-only write-ahead log of tree writes.
+///
+/// Each record holds an op (u8: 0 put, 1 delete, 2 upsert), a txid (u64), the lengths of its key and value
+/// (u32 each), the key, the value, and a checksum (u64) of everything before it. Every integer is big-endian.
+///
+/// Records are written in groups: the log buffers records, and writes and syncs them once it holds a full group,
+/// or when synced. Larger groups take fewer fsyncs, but a crash loses the records of the current group.
+pub struct Wal {
+    file: File,
+    pending: Vec<u8>,
+    pending_count: usize,
+    group_size: usize,
+}
+
+impl Wal {
+    /// Opens the log in the given file, creating the file if it doesn't exist, and returns the records in it.
+    /// A torn or corrupt record ends the log, and is removed along with everything after it.
+    /// Panics if group_size is 0.
+    pub fn open<P: AsRef<Path>>(path: P, group_size: usize) -> Result<(Wal, Vec<WalRecord>), TreeError> {
+        assert!(group_size > 0, "log group size must be positive");
+        let mut file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while let Some((record, len)) = decode(&bytes[pos..]) {
+            records.push(record);
+            pos += len;
+        }
+
+        if pos < bytes.len() {
+            file.set_len(pos as u64)?;
+            file.sync_data()?;
+        }
+        file.seek(SeekFrom::Start(pos as u64))?;
+
+        Ok((Wal {
+            file: file,
+            pending: Vec::new(),
+            pending_count: 0,
+            group_size: group_size,
+        }, records))
+    }
+
+    /// Appends a record, syncing the log if it completes a group.
+    pub fn append(&mut self, op: WalOp, txid: Counter, key: &[u8], value: &[u8]) -> Result<(), TreeError> {
+        let start = self.pending.len();
+        self.pending.push(match op {
+            WalOp::Put => PUT,
+            WalOp::Delete => DELETE,
+            WalOp::Upsert => UPSERT,
+        });
+        self.pending.extend_from_slice(&txid.to_bytes());
+        self.pending.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        self.pending.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.pending.extend_from_slice(key);
+        self.pending.extend_from_slice(value);
+        let sum = checksum(&self.pending[start..]);
+        self.pending.extend_from_slice(&sum.to_be_bytes());
+
+        self.pending_count += 1;
+        if self.pending_count >= self.group_size {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes and syncs every buffered record.
+    pub fn sync(&mut self) -> Result<(), TreeError> {
+        if self.pending.is_empty() {
+            return Ok(())
+        }
+
+        let pos = self.file.seek(SeekFrom::Current(0))?;
+        if let Err(e) = self.file.write_all(&self.pending).and_then(|_| self.file.sync_data()) {
+            // Replay stops at a torn record, which would hide the records written after it. Cut the group off,
+            // so the next sync writes it again in its place.
+            let _ = self.file.set_len(pos).and_then(|_| self.file.seek(SeekFrom::Start(pos)));
+            return Err(e.into())
+        }
+        self.pending.clear();
+        self.pending_count = 0;
+
+        Ok(())
+    }
+
+    /// Drops every record, including buffered ones.
+    pub fn truncate(&mut self) -> Result<(), TreeError> {
+        self.pending.clear();
+        self.pending_count = 0;
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        // A failed sync loses only records that were never promised to be durable.
+        let _ = self.sync();
+    }
+}
+
+/// Decodes the record at the start of the given bytes, returning it and its length, or None if it is torn or corrupt.
+fn decode(bytes: &[u8]) -> Option<(WalRecord, usize)> {
+    if bytes.len() < RECORD_HEADER_SIZE {
+        return None
+    }
+
+    let u32_at = |pos: usize| {
+        let mut r = [0; 4];
+        r.copy_from_slice(&bytes[pos..pos + 4]);
+        u32::from_be_bytes(r) as usize
+    };
+    let (key_len, value_len) = (u32_at(9), u32_at(13));
+    let end = RECORD_HEADER_SIZE + key_len + value_len;
+    if bytes.len() < end + 8 {
+        return None
+    }
+
+    let mut sum = [0; 8];
+    sum.copy_from_slice(&bytes[end..end + 8]);
+    if checksum(&bytes[..end]) != u64::from_be_bytes(sum) {
+        return None
+    }
+
+    let op = match bytes[0] {
+        PUT => WalOp::Put,
+        DELETE => WalOp::Delete,
+        UPSERT => WalOp::Upsert,
+        _ => return None,
+    };
+    let mut txid = [0; 8];
+    txid.copy_from_slice(&bytes[1..9]);
+
+    Some((WalRecord {
+        op: op,
+        txid: Counter::from_bytes(txid),
+        key: RcBytes::new(&bytes[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + key_len]),
+        value: RcBytes::new(&bytes[RECORD_HEADER_SIZE + key_len..end]),
+    }, end + 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use storage::temp_path;
+
+    use super::*;
+
+    fn ops(records: &[WalRecord]) -> Vec<(WalOp, u64, Vec<u8>, Vec<u8>)> {
+        records.iter()
+            .map(|r| (r.op, u64::from_be_bytes(r.txid.to_bytes()), r.key.to_vec(), r.value.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_wal() {
+        let path = temp_path("test_wal");
+        {
+            let (mut wal, records) = Wal::open(&path, 2).unwrap();
+            assert!(records.is_empty());
+            wal.append(WalOp::Put, Counter::new(1), b"a", b"1").unwrap();
+            // Only full groups are written.
+            assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+            wal.append(WalOp::Delete, Counter::new(1), b"b", b"").unwrap();
+            assert!(fs::metadata(&path).unwrap().len() > 0);
+            wal.append(WalOp::Upsert, Counter::new(2), b"c", b"+1").unwrap();
+        }
+
+        let len = {
+            let (mut wal, records) = Wal::open(&path, 1).unwrap();
+            assert_eq!(ops(&records), vec![
+                (WalOp::Put, 1, b"a".to_vec(), b"1".to_vec()),
+                (WalOp::Delete, 1, b"b".to_vec(), vec![]),
+                (WalOp::Upsert, 2, b"c".to_vec(), b"+1".to_vec()),
+            ]);
+            wal.append(WalOp::Put, Counter::new(3), b"d", b"4").unwrap();
+            fs::metadata(&path).unwrap().len()
+        };
+
+        // A torn record is dropped, and later records go after the last good one.
+        {
+            let file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.set_len(len - 1).unwrap();
+        }
+        {
+            let (mut wal, records) = Wal::open(&path, 1).unwrap();
+            assert_eq!(records.len(), 3);
+            wal.append(WalOp::Put, Counter::new(3), b"e", b"5").unwrap();
+        }
+        {
+            let (mut wal, records) = Wal::open(&path, 1).unwrap();
+            assert_eq!(ops(&records)[3], (WalOp::Put, 3, b"e".to_vec(), b"5".to_vec()));
+            wal.truncate().unwrap();
+        }
+        assert!(Wal::open(&path, 1).unwrap().1.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
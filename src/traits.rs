@@ -1,3 +1,4 @@
+use std::fmt;
 use std::io;
 use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
@@ -23,6 +24,37 @@ pub enum TreeError {
     RuntimeError(String),
 }
 
+impl From<io::Error> for TreeError {
+    fn from(e: io::Error) -> Self {
+        TreeError::IoError(e)
+    }
+}
+
+/// A user-registered way to fold deltas into values, such as adding to a counter or appending to a list.
+/// Trees with a merge operator can record a delta with `TreeMut::upsert` instead of reading and rewriting the value.
+#[derive(Clone, Copy)]
+pub struct MergeOperator {
+    /// Applies a delta to the value of the given key, or to nothing if the key is absent, returning the new value.
+    pub apply: fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8>,
+    /// Combines two deltas to the given key, older first, into one delta with the same effect.
+    pub combine: fn(&[u8], &[u8], &[u8]) -> Vec<u8>,
+}
+
+// Operators are compared by identity, so trees can check that all their nodes agree on one.
+impl PartialEq for MergeOperator {
+    fn eq(&self, other: &MergeOperator) -> bool {
+        self.apply as usize == other.apply as usize && self.combine as usize == other.combine as usize
+    }
+}
+
+impl Eq for MergeOperator {}
+
+impl fmt::Debug for MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MergeOperator {{ apply: {:p}, combine: {:p} }}", self.apply, self.combine)
+    }
+}
+
 pub trait DerefSpec<'a> {
     type Target: ?Sized;
     type Deref: Deref<Target = Self::Target>;
@@ -61,7 +93,9 @@ pub trait Entry<'a, Spec: MapSpec<'a> + ?Sized> {
 pub trait Cursor<'a, Spec: MapSpec<'a> + ?Sized>: Entry<'a, Spec> + Sized {
     fn exists(&self) -> bool;
 
-	fn next(&mut self) -> bool;
+	/// Advances this cursor, returning true if it still points to a key. Returns an error if the tree
+	/// couldn't read the keys it would have advanced over.
+	fn next(&mut self) -> Result<bool, TreeError>;
 }
 
 /// A map where the keys are byte strings.
@@ -115,7 +149,7 @@ pub trait Tree<Spec: for<'x> TreeSpec<'x> + ?Sized>: Map<Spec> {
     /// but this makes Rust's constraint checker behave oddly in some cases, particularly with subtraits.
     fn suffix<'b, K: AsRef<[u8]>>(&'b self, prefix: K) -> <Spec as TreeSpec<'b>>::SuffixImpl;
 
-    fn subrange<'b, K1: AsRef<[u8]>, K2: AsRef<[u8]>>(&self, start: K1, end: K2) -> <Spec as TreeSpec<'b>>::SubrangeImpl;
+    fn subrange<'b, K1: AsRef<[u8]>, K2: AsRef<[u8]>>(&'b self, start: K1, end: K2) -> <Spec as TreeSpec<'b>>::SubrangeImpl;
 }
 
 pub trait Subtree<'a, Spec: for<'x> TreeSpec<'x> + ?Sized>: Tree<Spec> {}
@@ -131,6 +165,7 @@ pub trait TreeMutSpec<'a>: TreeSpec<'a> {
     type EntryMut: EntryMut<'a, Self>;
     type CursorMut: Cursor<'a, Self> + EntryMut<'a, Self>;
     type GetMut: DerefMut<Target = Self::Value>;
+    type GetMutSpec: for<'x> DerefMutSpec<'x, Target = Self::Value>;
     type SuffixMutSpec: for<'x> TreeMutSpec<'x>;
     type SuffixMutImpl: TreeMut<Self::SuffixMutSpec> + Subtree<'a, Self::SuffixMutSpec>;
     type SubrangeMutSpec: for<'x> TreeMutSpec<'x>;
@@ -144,7 +179,11 @@ pub trait EntryMut<'a, Spec: TreeMutSpec<'a> + ?Sized>: Entry<'a, Spec> {
     /// Implementations may copy or clone the read value.
 
     // TODO: maybe return Result?
-    fn get_mut(&mut self) -> Spec::GetMut;
+    fn get_mut<'b>(&'b mut self) -> <Spec::GetMutSpec as DerefMutSpec<'b>>::DerefMut;
+
+    /// Like get_mut, but destroys this EntryMut. Useful if you want to return the reference
+    /// while keeping the 'a lifetime.
+    fn unwrap_mut(self) -> Spec::GetMut;
 
     /// Sets this Entry's value. This is at least as fast as calling get_value_mut
     /// and overwriting the read value, and can be faster depending on implementation.
@@ -161,7 +200,7 @@ pub trait TreeMut<Spec: for<'x> TreeMutSpec<'x> + ?Sized>: Tree<Spec> {
     fn cursor_mut<'b, K: AsRef<[u8]>>(&'b mut self, k: K) -> Result<<Spec as TreeMutSpec<'b>>::CursorMut, TreeError>;
 
     fn get_mut<'b, K: AsRef<[u8]>>(&'b mut self, k: K) -> Result<Option<<Spec as TreeMutSpec<'b>>::GetMut>, TreeError> {
-        self.entry_mut(k).map(|x| x.map(|mut y| y.get_mut()))
+        self.entry_mut(k).map(|x| x.map(EntryMut::unwrap_mut))
     }
 
     fn put<K: AsRef<[u8]>, V: AsRef<<Spec as MapSpec<'static>>::Value>>(&mut self, k: K, v: V) -> Result<(), TreeError>;
@@ -170,9 +209,13 @@ pub trait TreeMut<Spec: for<'x> TreeMutSpec<'x> + ?Sized>: Tree<Spec> {
         self.entry_mut(k).and_then(|x| x.map_or(Ok(()), EntryMut::delete))
     }
 
-    fn suffix_mut<'b, K: AsRef<[u8]>>(&'b self, prefix: K) -> <Spec as TreeMutSpec<'b>>::SuffixMutImpl;
+    /// Merges the given delta into the value of the given key with this tree's merge operator, without
+    /// reading the value. Returns an error if this tree has no merge operator.
+    fn upsert<K: AsRef<[u8]>, D: AsRef<[u8]>>(&mut self, k: K, delta: D) -> Result<(), TreeError>;
+
+    fn suffix_mut<'b, K: AsRef<[u8]>>(&'b mut self, prefix: K) -> <Spec as TreeMutSpec<'b>>::SuffixMutImpl;
 
-    fn subrange_mut<'b, K1: AsRef<[u8]>, K2: AsRef<[u8]>>(&'b self, start: K1, end: K2) -> <Spec as TreeMutSpec<'b>>::SubrangeMutImpl;
+    fn subrange_mut<'b, K1: AsRef<[u8]>, K2: AsRef<[u8]>>(&'b mut self, start: K1, end: K2) -> <Spec as TreeMutSpec<'b>>::SubrangeMutImpl;
 }
 
 // // TODO: make these better. What's the dominant design pattern? What's the expected use case?
@@ -192,7 +235,7 @@ pub trait TransientTreeSpec<'a, 'p>: TreeMutSpec<'a> {
 }
 
 pub trait TransientTree<'a, 'p, Spec: for<'x> TransientTreeSpec<'x, 'p> + ?Sized>: TreeMut<Spec> {
-    fn persistent(&self) -> <Spec as TransientTreeSpec<'a, 'p>>::PersistentImpl;
+    fn persistent(&mut self) -> <Spec as TransientTreeSpec<'a, 'p>>::PersistentImpl;
 }
 
 pub trait HistoryTreeSpec<'a>: TreeSpec<'a> {
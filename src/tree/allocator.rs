@@ -14,50 +14,95 @@ are not shareable until turned into 'on-disk' nodes.
 For speed, we actually need several typed allocators, perhaps using macros.
 */
 
-// TODO: name?
-
-//! Thread-unsafe arena allocators.
-
-// trait Arena {
-// 	type Handle<Arena = Self>: ArenaHandle;
-// 	type Validator: ArenaValidator;
-
-// 	fn handle(&self) -> Self::Handle;
-
-// 	fn alloc<T>(&mut self, t: T) -> ArenaPtr<T>;
-// }
-
-// struct ArenaImpl {
-// 	// Any frees of the Arena must happen-after this state is cleared.
-// 	state: AtomicBool,
-// }
-
-// trait ArenaHandle {
-// 	type Arena: Arena;
-
-// 	fn get(&self) -> Self::Arena;
-// }
-
-// struct ArenaHandleImpl<A: Arena> {
-// 	arena: Rc<A>,
-// }
-
-// trait ArenaValidator {
-// 	// empty trait
-// }
-
-// /// ArenaPtrs represent shared data.
-// trait ArenaPtr<T> {
-// 	type Arena: Arena,
-
-// 	fn deref(&self, a: <Self::Arena as Arena>::Validator) -> &T;
-// }
-
-// /// An ArenaPtrMut can be downgraded into an ArenaPtr, but not vice versa.
-// trait ArenaPtrMut {
-
-// }
-
-// struct ArenaPtr<T> {
-
-// }
+//! Per-transaction arenas for transient nodes.
+//!
+//! Only nodes live in the arena. Buckets and their key and value bytes stay reference counted: entries
+//! and cursors hand them out, and snapshots share them, so they can outlive the transaction that wrote them.
+//! The arena still hands out buffers for keys and values, so a transaction only takes buffers it allocated.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use alloc::{AllocBytes, ArenaPtr, CheckedArena, SafeCheckModel};
+
+use tree::memnode::MemNode;
+
+/// Checks that node pointers are not used after their transaction is freed.
+/// UncheckedModel skips the checks.
+pub type NodeCheckModel = SafeCheckModel;
+
+/// A transient node's slot in its arena. Emptied when the node is made persistent, which moves it out.
+pub type NodeSlot = RefCell<Option<MemNode>>;
+
+pub type NodePtr = ArenaPtr<NodeSlot, NodeCheckModel>;
+
+/// Allocates the transient nodes of one transaction. Nodes are freed together when the arena is dropped:
+/// after the transaction is committed and every live node was moved out, or when it is abandoned.
+/// Slots of nodes discarded before then are reused, so a long transaction only grows with its live nodes.
+pub struct NodeArena {
+	nodes: CheckedArena<NodeSlot, NodeCheckModel>,
+	/// Slots emptied by `free`, to be reused by `alloc`.
+	free: RefCell<Vec<NodePtr>>,
+	/// Identifies this arena's transaction to the buffers it allocated.
+	txn: Rc<()>,
+}
+
+impl NodeArena {
+	pub fn new() -> Rc<NodeArena> {
+		Rc::new(NodeArena {
+			nodes: CheckedArena::new(),
+			free: RefCell::new(Vec::new()),
+			txn: Rc::new(()),
+		})
+	}
+
+	/// Allocates the given node. The node remembers this arena, so it can fork its children into it.
+	pub fn alloc(arena: &Rc<NodeArena>, mut n: MemNode) -> NodePtr {
+		n.set_arena(Rc::downgrade(arena));
+		let slot = arena.free.borrow_mut().pop();
+		match slot {
+			Some(p) => {
+				*p.get().borrow_mut() = Some(n);
+				p
+			}
+			None => arena.nodes.alloc(RefCell::new(Some(n))),
+		}
+	}
+
+	/// Frees the discarded node at the given pointer, so its slot can be reused. The node must be in this arena,
+	/// and nothing may point to it afterwards.
+	pub fn free(&self, p: NodePtr) {
+		take_node(&p);
+		self.free.borrow_mut().push(p);
+	}
+
+	/// Allocates a buffer of the given bytes followed by `len` zeroes, for a key or value written in this transaction.
+	pub fn alloc_bytes(&self, given: &[u8], len: usize) -> AllocBytes {
+		AllocBytes::new(&self.txn, given, len)
+	}
+
+	/// True if the given buffer was allocated by this arena.
+	pub fn owns(&self, b: &AllocBytes) -> bool {
+		b.is_from(&self.txn)
+	}
+
+	/// The number of slots in this arena, including ones since freed or made persistent.
+	pub fn len(&self) -> usize {
+		self.nodes.len()
+	}
+}
+
+/// Borrows the node at the given pointer. Panics if it was made persistent.
+pub fn apply_node<F, R>(p: &NodePtr, f: F) -> R where F: FnOnce(&MemNode) -> R {
+	f(p.get().borrow().as_ref().expect("node was made persistent"))
+}
+
+/// Mutably borrows the node at the given pointer. Panics if it was made persistent.
+pub fn apply_node_mut<F, R>(p: &NodePtr, f: F) -> R where F: FnOnce(&mut MemNode) -> R {
+	f(p.get().borrow_mut().as_mut().expect("node was made persistent"))
+}
+
+/// Moves the node at the given pointer out of its arena.
+pub fn take_node(p: &NodePtr) -> MemNode {
+	p.get().borrow_mut().take().expect("node was made persistent")
+}
@@ -1,20 +1,44 @@
-use std::borrow::Borrow;
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::marker::PhantomData;
+use std::mem;
+use std::ops::Deref;
+use std::path::Path;
+use std::rc::{Rc, Weak};
+
+use futures::Future;
+use futures::future;
+
+use alloc::AllocBytes;
 
 use counter::Counter;
 
 use data::*;
 
+use storage::{wal_path, BlockAddress, BlockStore, FileStore, Wal, WalOp};
+
+use tdfuture::{BoxFuture, FutureExt};
+
 use traits::*;
 
+use tree::allocator::NodeArena;
 use tree::bucketref::*;
+use tree::chunks::*;
 use tree::memnode::*;
+use tree::nodecache::{NodeCache, DEFAULT_CACHE_CAPACITY};
 use tree::noderef::*;
+use tree::page::{NodeAddress, RootRecord, TreeSettings};
+use tree::util::prefix_end;
 
 // TODO: this does not need to be a mod
 mod nodestack {
+	use std::rc::Rc;
+
+	use counter::Counter;
+
+	use tree::allocator::NodeArena;
 	use tree::bucketref::*;
-	use tree::noderef::NodeRef;
+	use tree::noderef::{FatNodeRef, NodeRef};
 	use tree::memnode::*;
 
 	const MAX_DEPTH: u8 = 32;
@@ -23,6 +47,9 @@ mod nodestack {
 
 	pub struct NodeStack {
 		entries: Vec<NodeCursor>,
+		/// Pins on the stored nodes in entries, so the node cache can't free them while the stack is on them.
+		/// Buckets handed out by the stack are weak, and would otherwise dangle.
+		pins: Vec<Option<FatNodeRef>>,
 	}
 
 	impl NodeStack {
@@ -30,15 +57,21 @@ mod nodestack {
 			NodeStack {
 				// master_node: topnode,
 				entries: Vec::with_capacity(MAX_DEPTH as usize),
+				pins: Vec::with_capacity(MAX_DEPTH as usize),
 			}
 		}
 
 		pub fn push(&mut self, node: NodeRef, child_index: u16) {
 			debug_assert!(self.entries.len() < MAX_DEPTH as usize);
+			self.pins.push(match node {
+				NodeRef::Stored(_) => Some(node.upgrade()),
+				NodeRef::Transient(_) | NodeRef::Persistent(_) => None,
+			});
 			self.entries.push((node, child_index));
 		}
 
 		pub fn pop(&mut self) -> Option<NodeCursor> {
+			self.pins.pop();
 			self.entries.pop()
 		}
 
@@ -64,6 +97,10 @@ mod nodestack {
 			self.entries.is_empty()
 		}
 
+		pub fn len(&self) -> usize {
+			self.entries.len()
+		}
+
 		/// Returns the NodeRef at position 0, or the given NodeRef if this is empty.
 		pub fn head_or(&self, head_maybe: &NodeRef) -> NodeRef {
 			if self.entries.len() == 0 {
@@ -74,6 +111,27 @@ mod nodestack {
 			}
 		}
 
+		/// Makes every node in this NodeStack transient, forking persistent nodes in place into the given arena
+		/// as needed. The given head must be the node at the bottom of this NodeStack.
+		pub fn heat(&mut self, head: &mut FatNodeRef, arena: &Rc<NodeArena>) {
+			for i in 0..self.entries.len() {
+				if self.entries[i].0.is_transient() {
+					continue;
+				}
+
+				let heated = if i == 0 {
+					head.heat(arena);
+					head.noderef()
+				} else {
+					// The node at i - 1 is already transient, so this will not copy it.
+					let (ref parent, parent_idx) = self.entries[i - 1];
+					parent.apply_mut(|hn| hn.heat_child(parent_idx))
+				};
+
+				self.entries[i].0 = heated;
+			}
+		}
+
 		/// Finds the NodeStack pointing to the given key. True if the key was found exactly.
 		/// If the key was not found, the NodeStack points to the first key greater than the given node.
 		/// In this case the NodeStack may not point to a valid node and may need revalidation.
@@ -112,6 +170,17 @@ mod nodestack {
 		/// Advances this NodeStack.
 		/// Precondition: the stack is not empty.
 		pub fn advance(&mut self) -> Option<WeakBucketRef> {
+			self.advance_skipping(|_| false)
+		}
+
+		/// Like advance, but skips every subtree not written after the given txid. Branch buckets
+		/// are still visited, even if older than the given txid.
+		/// Precondition: the stack is not empty.
+		pub fn advance_newer_than(&mut self, txid: Counter) -> Option<WeakBucketRef> {
+			self.advance_skipping(|n| !n.is_newer_than(txid))
+		}
+
+		fn advance_skipping<F: Fn(&NodeRef) -> bool>(&mut self, skip: F) -> Option<WeakBucketRef> {
 			let is_leaf;
 
 			{ // Borrow checker block
@@ -129,13 +198,15 @@ mod nodestack {
 				// If a leaf, increment by one, then revalidate.
 				return self.ascend_maybe();
 			} else {
-				return self.descend();
+				return self.descend(skip);
 			}
 		}
 
 		/// Left-descends down the nodestack until we reach the first left bucket on the next leaf node.
+		/// Children for which skip returns true are not descended into; instead we stop at the
+		/// branch bucket following that child.
 		/// Precondition: we are not at a leaf node, and we are not empty.
-		fn descend(&mut self) -> Option<WeakBucketRef> {
+		fn descend<F: Fn(&NodeRef) -> bool>(&mut self, skip: F) -> Option<WeakBucketRef> {
 			let mut nref;
 
 			{ // borrow checker block
@@ -149,16 +220,27 @@ mod nodestack {
 				cursorref.1 += 1;
 			};
 
-			loop {
-				debug_assert!(nref.apply(|node| node.child_count() > 0));
+			if skip(&nref) {
+				return self.ascend_from_branch();
+			}
 
+			loop {
 				if nref.apply(MemNode::is_leaf) {
+					// Leaves only lack buckets if they couldn't be read, in which case we move past them.
+					self.push(nref, 0);
+					return self.ascend_maybe()
+				}
+
+				debug_assert!(nref.apply(|node| node.bucket_count() > 0));
+
+				let nref2 = nref.apply(|node| node.child_ref(0));
+				if skip(&nref2) {
+					// Branch nodes always have a bucket 0.
 					let r = Some(nref.apply(|node| node.bucket_ref(0)));
 					self.push(nref, 0);
 					return r
 				}
 
-				let nref2 = nref.apply(|node| node.child_ref(0));
 				self.push(nref, 0);
 				nref = nref2
 			}
@@ -169,10 +251,16 @@ mod nodestack {
 		/// (So, if we are at a leaf node with 4 buckets, we need to ascend if the top index == 4.)
 		/// Precondition: we are at a leaf node, and we are not empty.
 		pub fn ascend_maybe(&mut self) -> Option<WeakBucketRef> {
+			debug_assert!(self.peek().unwrap().0.apply(MemNode::is_leaf));
+			self.ascend_from_branch()
+		}
+
+		/// Like ascend_maybe, but the top of the stack may also be a branch node.
+		/// Precondition: we are not empty.
+		fn ascend_from_branch(&mut self) -> Option<WeakBucketRef> {
 			{ // borrow checker block
 				// Asserts we are not empty
 				let topcursor = self.peek_mut().unwrap();
-				debug_assert!(topcursor.0.apply(MemNode::is_leaf));
 
 				// We have no need to ascend
 				if topcursor.1 < topcursor.0.apply(|node| node.bucket_count()) {
@@ -206,8 +294,11 @@ mod nodestack {
 pub use self::nodestack::NodeStack;
 
 mod btree_insert {
+	use std::rc::Rc;
+
 	use data::*;
 
+	use tree::allocator::NodeArena;
 	use tree::btree::NodeStack;
 	use tree::bucketref::BucketRef;
 	use tree::memnode::*;
@@ -216,13 +307,14 @@ mod btree_insert {
 	/// Precondition: self is the head node.
 	// TODO: can we make this iterative? the issue is hot handles' lifetime syntactically depends on the parent handle,
 	// when it really depends on the lifetime of top.
-	fn insert_helper_nosplit(top: &mut NodeRef, nhot: HotHandle, stack: &mut NodeStack) -> FatNodeRef {
+	fn insert_helper_nosplit(top: &mut NodeRef, nhot: HotHandle, stack: &mut NodeStack, arena: &Rc<NodeArena>)
+		-> FatNodeRef {
 		if let Some((parent, parent_idx)) = stack.pop() {
-			let (mut parent_hot, was_copied) = parent.heat();
+			let (mut parent_hot, was_copied) = parent.heat(arena);
 			parent_hot.apply_mut(|hn| hn.reassign_child(parent_idx, nhot));
 
 			if was_copied {
-				insert_helper_nosplit(top, parent_hot, stack)
+				insert_helper_nosplit(top, parent_hot, stack, arena)
 			} else {
 				// If not, we reached the termination condition, and the head node is not modified
 				stack.head_or(&parent).upgrade()
@@ -237,11 +329,11 @@ mod btree_insert {
 
 	// TODO: figure out how to have a simple return thingy
 	fn insert_helper(top: &mut NodeRef, nhot: HotHandle, insert_result: InsertResult,
-		stack: &mut NodeStack) -> FatNodeRef {
+		stack: &mut NodeStack, arena: &Rc<NodeArena>) -> FatNodeRef {
 		if let Some((parent, parent_idx)) = stack.pop() {
 			// Get the next node up the stack, loop while we have to modify nodes
 			// TODO: weak references?
-			let (mut parent_hot, was_copied) = parent.heat();
+			let (mut parent_hot, was_copied) = parent.heat(arena);
 			parent_hot.apply_mut(|hn| hn.reassign_child(parent_idx, nhot));
 
 			match insert_result {
@@ -249,7 +341,7 @@ mod btree_insert {
 					if was_copied {
 						// This hot parent was modified. Flushing will not be necessary,
 						// but we have to continue looping until we no longer need to modify hot parents.
-						insert_helper_nosplit(top, parent_hot, stack)
+						insert_helper_nosplit(top, parent_hot, stack, arena)
 					} else {
 						// Termination condition, and we have not modified the head node
 						stack.head_or(&parent).upgrade()
@@ -258,8 +350,8 @@ mod btree_insert {
 				InsertResult::Flushed(split_bucket, newnode) => {
 					// This might trigger another flush.
 					let insert_result = parent_hot.apply_mut(
-						|hn| hn.insert_at(parent_idx, split_bucket, Some(FatNodeRef::new_transient(newnode))));
-					insert_helper(top, parent_hot, insert_result, stack)
+						|hn| hn.insert_at(parent_idx, split_bucket, Some(FatNodeRef::new_transient(arena, newnode))));
+					insert_helper(top, parent_hot, insert_result, stack, arena)
 				}
 			}
 		} else {
@@ -274,28 +366,253 @@ mod btree_insert {
 				}
 				InsertResult::Flushed(split_bucket, newnode) => {
 					// Need to create a new head node, and return it
-					FatNodeRef::new_transient(
-						MemNode::new_from_two(r, split_bucket, FatNodeRef::new_transient(newnode)))
+					FatNodeRef::new_transient(arena,
+						MemNode::new_from_two(r, split_bucket, FatNodeRef::new_transient(arena, newnode)))
 				}
 			}
 		}
 	}
 
 	// TODO: flushed should probably return a FatNodeRef as its 2nd node return value.
-	pub fn insert(top: &mut NodeRef, k: &[u8], v: &[u8]) -> FatNodeRef {
+	/// Inserts the given bucket, overwriting any bucket with the same key.
+	pub fn insert_bucket(top: &mut NodeRef, b: BucketRef, arena: &Rc<NodeArena>) -> FatNodeRef {
 		// TODO use an array stack. Minimize allocs
 		// Depth is 0-indexed
-		let (mut stack, exists) = NodeStack::construct(top.clone(), k);
+		let (mut stack, exists) = NodeStack::construct(top.clone(), b.key());
 		if exists {
-			panic!("not implemented")
+			// Overwrite the existing bucket, wherever it lives. This splits only if the node goes over its byte limit,
+			// but copied parents still need to be reassigned.
+			let (node, idx) = stack.pop().unwrap();
+			let (mut nhot, _) = node.heat(arena);
+			let insert_result = nhot.apply_mut(|hn| {
+				hn.replace_bucket(idx, b);
+				hn.split_if_needed()
+			});
+
+			return insert_helper(top, nhot, insert_result, &mut stack, arena)
 		}
 
 		// Prepare to insert
 		let (node, idx) = stack.pop().unwrap();
-		let (mut nhot, _) = node.heat();
-		let insert_result = nhot.apply_mut(|hn| hn.insert_at(idx, BucketRef::transient_from_bytes(k, v), None));
+		let (mut nhot, _) = node.heat(arena);
+		let insert_result = nhot.apply_mut(|hn| hn.insert_at(idx, b, None));
+
+		insert_helper(top, nhot, insert_result, &mut stack, arena)
+	}
+}
+
+mod btree_delete {
+	use std::rc::Rc;
+
+	use tree::allocator::NodeArena;
+	use tree::btree::NodeStack;
+	use tree::bucketref::BucketRef;
+	use tree::memnode::*;
+	use tree::noderef::{HotHandle, FatNodeRef, NodeRef};
+
+	/// The result of a delete. If the key was found, contains the new head, or None if the tree is now empty.
+	pub enum DeleteResult {
+		NotFound,
+		Deleted(Option<FatNodeRef>),
+	}
+
+	/// Walks back up the stack, reassigning heated children and rebalancing deficient ones.
+	/// If the deleted key lived in a branch node, replacement contains the stack depth of that node
+	/// together with the predecessor bucket that should take its place. A larger predecessor, or a bucket
+	/// borrowed by rebalancing, may put a branch over the byte limit, in which case split holds its split.
+	fn delete_helper(top: &mut NodeRef, nhot: HotHandle, mut replacement: Option<(usize, BucketRef)>,
+		split: InsertResult, stack: &mut NodeStack, arena: &Rc<NodeArena>) -> Option<FatNodeRef> {
+		if let Some((parent, parent_idx)) = stack.pop() {
+			let (mut parent_hot, was_copied) = parent.heat(arena);
+
+			let replacement_bucket = match replacement {
+				Some((depth, _)) if depth == stack.len() => replacement.take().map(|(_, b)| b),
+				_ => None,
+			};
+
+			let (split, is_deficient) = parent_hot.apply_mut(|hn| {
+				hn.reassign_child(parent_idx, nhot);
+				if let Some(b) = replacement_bucket {
+					hn.replace_bucket(parent_idx, b);
+				}
+				if let InsertResult::Flushed(split_bucket, newnode) = split {
+					hn.insert_split_child(parent_idx, split_bucket, FatNodeRef::new_transient(arena, newnode));
+				}
+				hn.rebalance_child(parent_idx);
+				(hn.split_if_needed(), hn.is_deficient())
+			});
+			let is_split = match split {
+				InsertResult::Flushed(_, _) => true,
+				InsertResult::Ok => false,
+			};
+
+			if was_copied || is_split || replacement.is_some() || is_deficient {
+				// We have to continue up the stack, either to reassign a copied node, to insert a split,
+				// to replace a deleted branch bucket, or to rebalance a deficient node.
+				delete_helper(top, parent_hot, replacement, split, stack, arena)
+			} else {
+				// Termination condition, and we have not modified the head node
+				Some(stack.head_or(&parent).upgrade())
+			}
+		} else {
+			// We have recursed all the way back to the head node, which is allowed to be deficient
+			// unless it is empty.
+			let mut r = top.upgrade();
+			r.reassign(nhot);
+
+			if let InsertResult::Flushed(split_bucket, newnode) = split {
+				Some(FatNodeRef::new_transient(arena,
+					MemNode::new_from_two(r, split_bucket, FatNodeRef::new_transient(arena, newnode))))
+			} else if r.apply(MemNode::bucket_count) > 0 {
+				Some(r)
+			} else if r.apply(MemNode::is_leaf) {
+				r.discard(arena);
+				None
+			} else {
+				let child = r.apply_mut(MemNode::take_only_child);
+				r.discard(arena);
+				Some(child)
+			}
+		}
+	}
+
+	pub fn delete(top: &mut NodeRef, k: &[u8], arena: &Rc<NodeArena>) -> DeleteResult {
+		let (mut stack, exists) = NodeStack::construct(top.clone(), k);
+		if !exists {
+			return DeleteResult::NotFound
+		}
+
+		let mut replacement_depth = None;
+
+		if !stack.peek().unwrap().0.apply(MemNode::is_leaf) {
+			// The key lives in a branch node. We replace it with its predecessor, the rightmost bucket
+			// in its left subtree, which we will remove from a leaf.
+			replacement_depth = Some(stack.len() - 1);
+
+			let mut n = {
+				let &(ref branch, idx) = stack.peek().unwrap();
+				branch.apply(|node| node.child_ref(idx))
+			};
+
+			loop {
+				let bucket_count = n.apply(MemNode::bucket_count);
+
+				if n.apply(MemNode::is_leaf) {
+					if bucket_count == 0 {
+						// Only nodes that couldn't be read are empty leaves. The tree is poisoned, so leave it be.
+						return DeleteResult::NotFound
+					}
+					stack.push(n, bucket_count - 1);
+					break;
+				}
+
+				let n2 = n.apply(|node| node.child_ref(bucket_count));
+				stack.push(n, bucket_count);
+				n = n2;
+			}
+		}
+
+		let (node, idx) = stack.pop().unwrap();
+		let (mut nhot, _) = node.heat(arena);
+		let removed = nhot.apply_mut(|hn| hn.remove_at(idx));
+		let replacement = replacement_depth.map(|depth| (depth, removed));
+
+		DeleteResult::Deleted(delete_helper(top, nhot, replacement, InsertResult::Ok, &mut stack, arena))
+	}
+}
+
+mod btree_buffer {
+	use std::rc::Rc;
+
+	use tree::allocator::NodeArena;
+	use tree::bucketref::BucketRef;
+	use tree::memnode::*;
+	use tree::noderef::{FatNodeRef, NodeRef};
+
+	/// Buffers the given write in the head, which must be a branch, flushing buffers that overflow.
+	/// Returns the writes flushed out of buffers over leaves, and writes to branch buckets, which the caller
+	/// must insert. Inserting them splits nodes that grow over their limits, as buffering never does.
+	pub fn write(head: &mut FatNodeRef, b: BucketRef, arena: &Rc<NodeArena>) -> Vec<BucketRef> {
+		let mut flushed = Vec::new();
+
+		head.heat(arena);
+		if buffer_write(head.noderef(), b, &mut flushed) {
+			flush(head.noderef(), &mut flushed);
+		}
+
+		flushed
+	}
+
+	/// Buffers the given write in the given transient branch, or, if it can't be buffered there, in the child
+	/// it belongs to, or out with the writes the caller must insert. Returns true if the branch's buffer overflows.
+	fn buffer_write(n: NodeRef, b: BucketRef, out: &mut Vec<BucketRef>) -> bool {
+		match n.apply_mut(|hn| hn.buffer_write(b)) {
+			BufferResult::Buffered(overflows) => overflows,
+			BufferResult::Bucket(b) => {
+				out.push(b);
+				false
+			}
+			BufferResult::Child(idx, b) => {
+				let child = n.apply_mut(|hn| hn.heat_child(idx));
+				if child.apply(MemNode::is_leaf) {
+					out.push(b);
+				} else if buffer_write(child.clone(), b, out) {
+					flush(child, out);
+				}
+				false
+			}
+		}
+	}
+
+	/// Flushes the writes buffered for the fullest child of the given transient node until its buffer
+	/// no longer overflows, recursively flushing children whose buffers overflow in turn.
+	fn flush(n: NodeRef, out: &mut Vec<BucketRef>) {
+		while n.apply(MemNode::buffer_overflows) {
+			let (child, writes) = n.apply_mut(|hn| {
+				let idx = hn.fullest_child();
+				let writes = hn.take_buffered(idx);
+				(hn.heat_child(idx), writes)
+			});
+
+			if child.apply(MemNode::is_leaf) {
+				out.extend(writes);
+			} else {
+				for b in writes {
+					buffer_write(child.clone(), b, out);
+				}
+				if child.apply(MemNode::buffer_overflows) {
+					flush(child, out);
+				}
+			}
+		}
+	}
+
+	/// Moves every buffered write under the given transient node down to the leaves, returning the writes
+	/// the caller must insert. Only dirty nodes are visited, and only nodes with buffered writes are heated.
+	pub fn drain(n: NodeRef, out: &mut Vec<BucketRef>) {
+		if !n.apply(MemNode::is_dirty) {
+			return
+		}
+
+		for idx in 0..n.apply(MemNode::child_count) {
+			let writes = n.apply_mut(|hn| hn.take_buffered(idx));
+			if writes.is_empty() && !n.apply(|node| node.child_ref(idx).apply(MemNode::is_dirty)) {
+				continue;
+			}
+
+			let child = n.apply_mut(|hn| hn.heat_child(idx));
+
+			if child.apply(MemNode::is_leaf) {
+				out.extend(writes);
+			} else {
+				for b in writes {
+					buffer_write(child.clone(), b, out);
+				}
+				drain(child, out);
+			}
+		}
 
-		insert_helper(top, nhot, insert_result, &mut stack)
+		n.apply_mut(MemNode::set_clean);
 	}
 }
 
@@ -304,14 +621,15 @@ mod btree_get {
 
 	use data::*;
 
+	use tree::bucketref::WeakBucketRef;
 	use tree::memnode::*;
 	use tree::noderef::NodeRef;
 
-	pub fn get(mut n: NodeRef, k: &[u8]) -> Option<RcBytes> {
+	pub fn get(mut n: NodeRef, k: &[u8]) -> Option<WeakBucketRef> {
 		loop {
 			match n.apply(|node| node.find(k)) {
 				Ok(idx) => {
-					return Some(n.apply(|node| node.bucket_ref(idx).value()))
+					return Some(n.apply(|node| node.bucket_ref(idx)))
 				}
 				Err(idx) => {
 					if n.apply(MemNode::is_leaf) {
@@ -325,16 +643,51 @@ mod btree_get {
 		}
 	}
 
+	/// Finds the write to the given key still buffered on its search path, if any. Buffered writes shadow buckets.
+	pub fn get_buffered(n: NodeRef, k: &[u8]) -> Option<WeakBucketRef> {
+		get_buffered_writes(n, k).into_iter().next()
+	}
+
+	/// Finds the writes to the given key still buffered on its search path, newest first. Stops after the first
+	/// write that is not a delta, since it shadows everything below it.
+	pub fn get_buffered_writes(mut n: NodeRef, k: &[u8]) -> Vec<WeakBucketRef> {
+		let mut r = Vec::new();
+
+		// Leaves are never dirty.
+		while n.apply(MemNode::is_dirty) {
+			if let Some(b) = n.apply(|node| node.find_buffered(k)) {
+				let is_delta = b.is_delta();
+				r.push(b);
+				if !is_delta {
+					break;
+				}
+			}
+
+			match n.apply(|node| node.find(k)) {
+				Ok(_) => break,
+				Err(idx) => n = n.apply(|node| node.child_ref(idx)),
+			}
+		}
+
+		r
+	}
+
+	/// Like get, but sees buffered writes.
+	pub fn get_latest(n: NodeRef, k: &[u8]) -> Option<WeakBucketRef> {
+		get_buffered(n.clone(), k).or_else(|| get(n, k))
+	}
+
 	/// Searches the tree, ignoring any transactions equal or older in time than the given txid.
+	/// Transient nodes and buckets are newer than any txid.
 	pub fn get_recent(mut n: NodeRef, k: &[u8], trailing_txid: Counter) -> Option<RcBytes> {
 		loop {
-			if !n.apply_persistent(|pnode| trailing_txid.circle_lt(pnode.txid())) {
+			if !n.is_newer_than(trailing_txid) {
 				return None
 			}
 
 			match n.apply(|node| node.find(k)) {
 				Ok(idx) => {
-					let tx_test = n.apply(|node| trailing_txid.circle_lt(node.bucket_ref(idx).txid()));
+					let tx_test = n.apply(|node| node.bucket_ref(idx).is_newer_than(trailing_txid));
 
 					return if tx_test {
 						Some(n.apply(|node| node.bucket_ref(idx).value()))
@@ -355,31 +708,318 @@ mod btree_get {
 	}
 }
 
+mod btree_store {
+	use std::collections::HashMap;
+	use std::rc::Rc;
+
+	use futures::future::{self, Future};
+
+	use storage::BlockStore;
+
+	use tdfuture::{BoxFuture, FutureExt};
+
+	use traits::TreeError;
+
+	use tree::bucketref::BucketRef;
+	use tree::chunks::ChunkStore;
+	use tree::memnode::*;
+	use tree::noderef::*;
+	use tree::page::{NodeAddress, Page};
+
+	/// Saves the given persistent node and every descendant not yet saved, returning the node's address.
+	/// Chunked values are saved as blocks of their own.
+	pub fn save<S: BlockStore + 'static>(store: &Rc<S>, n: FatNodeRef) -> BoxFuture<NodeAddress, TreeError> {
+		if let Some(addr) = n.address() {
+			return future::ok(addr).td_boxed()
+		}
+
+		let (children, chunks) = n.apply(|node| {
+			let children: Vec<_> = (0..node.child_count())
+				.map(|i| save(store, node.child(i).shallow_clone()))
+				.collect();
+			let chunks: Vec<_> = (0..node.bucket_count()).map(|i| node.stored_bucket(i)).chain(node.buffered())
+				.filter_map(BucketRef::chunk)
+				.filter(|c| c.address().is_none())
+				.map(|c| {
+					let c = c.clone();
+					store.put(&c.load()).map(move |addr| c.set_address(addr))
+				})
+				.collect();
+			(children, chunks)
+		});
+
+		let store = store.clone();
+		future::join_all(children).join(future::join_all(chunks)).and_then(move |_| {
+			let block = n.apply_persistent(Page::from_node).encode();
+			store.put(&block).map(move |addr| {
+				let addr = NodeAddress(addr);
+				n.apply_persistent(|pnode| pnode.set_address(addr));
+				addr
+			})
+		}).td_boxed()
+	}
+
+	/// Loads the node saved at the given address and all its descendants. Chunked values are put into
+	/// the given chunk store.
+	pub fn load<S: BlockStore + 'static>(store: &Rc<S>, addr: NodeAddress, limits: NodeLimits,
+		chunks: &Rc<ChunkStore>) -> BoxFuture<FatNodeRef, TreeError> {
+		let (store, chunks) = (store.clone(), chunks.clone());
+
+		store.get(addr.0).and_then(move |block| {
+			let page = Page::decode(&block)?;
+			if page.buckets.len() >= limits.capacity as usize {
+				return Err(TreeError::RuntimeError(format!("node at {:?} does not fit this tree's node limits", addr)))
+			}
+
+			Ok(page)
+		}).and_then(move |page| {
+			let children: Vec<_> = page.children.iter().map(|&child| load(&store, child, limits, &chunks)).collect();
+			let loaded_chunks: Vec<_> = page.chunk_addresses().into_iter().map(|chunk_addr| {
+				let chunks = chunks.clone();
+				store.get(chunk_addr).map(move |v| {
+					let c = ChunkStore::put(&chunks, v);
+					c.set_address(chunk_addr);
+					(chunk_addr, c)
+				})
+			}).collect();
+
+			future::join_all(children).join(future::join_all(loaded_chunks)).map(move |(children, loaded_chunks)| {
+				let txid = page.txid;
+				let node = page.into_node(limits, children, &loaded_chunks.into_iter().collect::<HashMap<_, _>>());
+				FatNodeRef::new_persistent(node, txid, addr)
+			})
+		}).td_boxed()
+	}
+}
+
+/// The bounds of a view, as seen by a cursor into that view.
+#[derive(Clone)]
+struct CursorBounds {
+	/// The length of the view's prefix, which is stripped from keys.
+	prefix_len: usize,
+	/// The cursor only visits keys contained by every range.
+	ranges: Rc<Vec<Range>>,
+	/// If present, the cursor only visits keys written after this txid, including tombstones.
+	trailing_txid: Option<Counter>,
+}
+
+impl CursorBounds {
+	fn unbounded() -> CursorBounds {
+		CursorBounds {
+			prefix_len: 0,
+			ranges: Rc::new(Vec::new()),
+			trailing_txid: None,
+		}
+	}
+}
+
 pub struct BTreeCursor<'a> {
 	stack: NodeStack,
 	current_bucket: Option<WeakBucketRef>,
+	bounds: CursorBounds,
+	/// If this cursor points to a buffered write, it has no stack yet. Holds the root to drain and the full key
+	/// to seek to before advancing, and the search path to the write, which pins the node holding it.
+	buffered: Option<(Rc<RefCell<BTreeRoot>>, RcBytes, NodeStack)>,
+	/// If this cursor points to buffered deltas merged on lookup, the merged bucket, which current_bucket
+	/// refers to, and the empty prefix it was downgraded with.
+	merged: Option<(RcBytes, BucketRef)>,
+	/// If the tree was opened from a file, where to pin values handed out by `unwrap`.
+	loans: Option<Loans>,
+	/// If the tree was opened from a file, its node cache, which records nodes that couldn't be read.
+	cache: Option<Rc<NodeCache>>,
 	_p: PhantomData<&'a u8>,
 }
 
 impl<'a> BTreeCursor<'a> {
-	fn construct(head: NodeRef, k: &[u8]) -> BTreeCursor<'a> {
-		let (mut stack, _) = NodeStack::construct(head, k);
-		let bucket = stack.ascend_maybe();
+	fn construct(head: NodeRef, k: &[u8], bounds: CursorBounds) -> BTreeCursor<'a> {
+		if bounds.trailing_txid.map_or(false, |txid| !head.is_newer_than(txid)) {
+			// Nothing has changed.
+			return Self::empty();
+		}
 
-		BTreeCursor {
+		let (stack, found) = {
+			// Seek to the first key that could be in every range.
+			let start = bounds.ranges.iter().fold(k, |start, range| {
+				if range.is_before(start) && range.left() > start { range.left() } else { start }
+			});
+			NodeStack::construct(head, start)
+		};
+
+		Self::from_stack(stack, found, bounds)
+	}
+
+	/// Like construct, but returns None unless the given key exists and is visible.
+	fn construct_exact(head: NodeRef, k: &[u8], bounds: CursorBounds) -> Option<BTreeCursor<'a>> {
+		if !bounds.ranges.iter().all(|range| range.contains(&k)) {
+			return None;
+		}
+
+		let (stack, found) = NodeStack::construct(head, k);
+
+		if found {
+			let r = Self::from_stack(stack, found, bounds);
+			// If the key is not visible, the cursor may have moved on to another key.
+			let visible = r.current_bucket.as_ref().map_or(false, |b| &*b.key() == k);
+			if visible { Some(r) } else { None }
+		} else {
+			None
+		}
+	}
+
+	/// Makes a cursor pointing to the given buffered write, or returns None if it is not visible.
+	fn construct_buffered(b: WeakBucketRef, root: Rc<RefCell<BTreeRoot>>, bounds: CursorBounds)
+		-> Option<BTreeCursor<'a>> {
+		let k = b.key();
+		let in_range = bounds.ranges.iter().all(|range| range.contains(&&*k));
+		let visible = match bounds.trailing_txid {
+			Some(txid) => b.is_newer_than(txid),
+			None => !b.is_tombstone(),
+		};
+
+		if in_range && visible {
+			let (path, _) = NodeStack::construct(root.borrow().head.as_ref().unwrap().noderef(), &k);
+			Some(BTreeCursor {
+				stack: NodeStack::empty(),
+				current_bucket: Some(b),
+				bounds: bounds,
+				buffered: Some((root, k, path)),
+				merged: None,
+				loans: None,
+				cache: None,
+				_p: PhantomData,
+			})
+		} else {
+			None
+		}
+	}
+
+	/// Like construct_buffered, but points to a bucket merged from buffered deltas, which this cursor owns.
+	/// Values handed out by `unwrap` are pinned by the tree's loans.
+	fn construct_merged(b: BucketRef, root: Rc<RefCell<BTreeRoot>>, bounds: CursorBounds)
+		-> Option<BTreeCursor<'a>> {
+		let prefix = RcBytes::new(&[][..]);
+		let loans = root.borrow().loans.clone();
+		Self::construct_buffered(b.downgrade(&prefix), root, bounds).map(|mut r| {
+			r.merged = Some((prefix, b));
+			r.loans = Some(loans);
+			r
+		})
+	}
+
+	fn from_stack(mut stack: NodeStack, found: bool, bounds: CursorBounds) -> BTreeCursor<'a> {
+		let bucket;
+
+		if found {
+			// The key may have been found in a branch node, so we can't ascend.
+			let &(ref n, idx) = stack.peek().unwrap();
+			bucket = Some(n.apply(|node| node.bucket_ref(idx)));
+		} else {
+			bucket = stack.ascend_maybe();
+		}
+
+		let mut r = BTreeCursor {
 			stack: stack,
 			current_bucket: bucket,
+			bounds: bounds,
+			buffered: None,
+			merged: None,
+			loans: None,
+			cache: None,
 			_p :PhantomData,
-		}
+		};
+		r.settle();
+
+		r
 	}
 
 	fn empty() -> BTreeCursor<'a> {
 		BTreeCursor {
 			stack: NodeStack::empty(),
 			current_bucket: None,
+			bounds: CursorBounds::unbounded(),
+			buffered: None,
+			merged: None,
+			loans: None,
+			cache: None,
 			_p :PhantomData,
 		}
 	}
+
+	/// Returns the key this cursor points to, without the prefix of the view it was made from.
+	pub fn key(&self) -> Option<Vec<u8>> {
+		// Flushing may rewrite a buffered write's key, so we keep our own copy.
+		if let Some((_, ref k, _)) = self.buffered {
+			return Some(k[self.bounds.prefix_len..].to_vec())
+		}
+
+		self.current_bucket.as_ref().map(|b| b.key()[self.bounds.prefix_len..].to_vec())
+	}
+
+	/// Returns an error if a node of the file the tree was opened from couldn't be read. The cursor stops
+	/// at such nodes, so it may have skipped keys.
+	fn check(&self) -> Result<(), TreeError> {
+		self.cache.as_ref().map_or(Ok(()), |cache| cache.check())
+	}
+
+	/// Returns true if this cursor points to a deleted key. Only cursors over diffs see deleted keys,
+	/// whose values are empty.
+	pub fn is_removed(&self) -> bool {
+		self.current_bucket.as_ref().map_or(false, WeakBucketRef::is_tombstone)
+	}
+
+	/// Advances this cursor until it points to a visible key, or exhausts it if it has moved
+	/// past the end of its ranges.
+	fn settle(&mut self) {
+		loop {
+			let (past_end, visible) = match self.current_bucket.as_ref() {
+				Some(b) => {
+					let k = b.key();
+					let ranges = &self.bounds.ranges;
+					let past_end = ranges.iter().any(|range| range.is_after(&k));
+					let in_range = !ranges.iter().any(|range| range.is_before(&k));
+					let visible = match self.bounds.trailing_txid {
+						Some(txid) => b.is_newer_than(txid),
+						None => !b.is_tombstone(),
+					};
+					(past_end, in_range && visible)
+				},
+				None => return,
+			};
+
+			if past_end {
+				self.current_bucket = None;
+				return;
+			} else if visible {
+				return;
+			} else {
+				self.current_bucket = self.advance_stack();
+			}
+		}
+	}
+
+	/// If this cursor points to a buffered write, drains the tree's buffers and seeks to where that write landed.
+	fn seek_buffered(&mut self) {
+		if let Some((root, k, _)) = self.buffered.take() {
+			let head = root.borrow_mut().drained_head();
+
+			let (stack, found) = NodeStack::construct(head.unwrap(), &k);
+			debug_assert!(found, "buffered write was lost");
+			self.current_bucket = {
+				let &(ref n, idx) = stack.peek().unwrap();
+				Some(n.apply(|node| node.bucket_ref(idx)))
+			};
+			self.stack = stack;
+			self.merged = None;
+		}
+	}
+
+	/// Advances the underlying NodeStack. Diff cursors skip unchanged subtrees.
+	fn advance_stack(&mut self) -> Option<WeakBucketRef> {
+		match self.bounds.trailing_txid {
+			Some(txid) => self.stack.advance_newer_than(txid),
+			None => self.stack.advance(),
+		}
+	}
 }
 
 pub struct ByteDerefSpec {}
@@ -389,6 +1029,13 @@ impl<'a> DerefSpec<'a> for ByteDerefSpec {
     type Deref = &'a [u8];
 }
 
+pub struct ByteDerefMutSpec {}
+
+impl<'a> DerefMutSpec<'a> for ByteDerefMutSpec {
+    type Target = [u8];
+    type DerefMut = &'a mut [u8];
+}
+
 pub struct PersistentBTreeSpec {}
 
 impl<'a> MapSpec<'a> for PersistentBTreeSpec {
@@ -410,7 +1057,12 @@ impl<'a> Entry<'a, PersistentBTreeSpec> for BTreeCursor<'a> {
     }
 
     fn unwrap(self) -> &'a [u8] {
-		let p: *const [u8] = &*self.current_bucket.as_ref().unwrap().value();
+		let v = self.current_bucket.as_ref().unwrap().value();
+		let p: *const [u8] = &*v;
+		// Once this cursor is gone, nothing else stops the node cache from freeing the value.
+		if let Some(ref loans) = self.loans {
+			loans.borrow_mut().push(v);
+		}
         unsafe { &*p }
     }
 
@@ -424,266 +1076,1583 @@ impl<'a> Cursor<'a, PersistentBTreeSpec> for BTreeCursor<'a> {
 		self.current_bucket.is_some()
 	}
 
-	fn next(&mut self) -> bool {
-		self.current_bucket = self.stack.advance();
-		self.exists()
+	fn next(&mut self) -> Result<bool, TreeError> {
+		if self.exists() {
+			self.seek_buffered();
+			self.current_bucket = self.advance_stack();
+			self.settle();
+			if let Err(e) = self.check() {
+				self.current_bucket = None;
+				return Err(e)
+			}
+		}
+
+		Ok(self.exists())
 	}
 }
 
-// impl EntryMut<'static> for BTreeCursor {
-//     type GetMut = RcBytes;
-//
-//     fn get_mut<'a>(&'a mut self) -> HRefMut<'a, Self, Self::GetMut> {
-//         panic!() // TODO
-//     }
-//
-//     fn set<V: AsRef<Box<[u8]>>>(&mut self, v: V) {
-//         self.get_mut().set_value(v.clone())
-//     }
-// }
-
 // A simple in-memory persistent b-tree.
 // TODO: naming. Transient, persistent. Two separate structs maybe?
 pub struct PersistentBTree {
+	root: Rc<RefCell<BTreeRoot>>,
+	/// If this is a suffix view, the prefix shared by all keys in this view. Keys in this view
+	/// have the prefix stripped.
+	prefix: RcBytes,
+	/// If this is a view, the ranges of the underlying tree this view is bounded by.
+	/// Keys are only visible if they are contained by every range.
+	ranges: Rc<Vec<Range>>,
+	/// If this is a diff, only keys written after this txid are visible.
+	trailing_txid: Option<Counter>,
+	/// False if this is a read-only view, which shares its root with a tree it may not write to.
+	writable: bool,
+}
+
+/// Pins on the txids that live snapshots and diffs can see changes after. Shared by a tree, its
+/// snapshots, and their transients, so compaction knows which tombstones are still visible.
+type SnapshotPins = Rc<RefCell<Vec<Weak<Counter>>>>;
+
+/// Values handed out by reference from a tree opened from a file. They stay pinned until the next write,
+/// since the node cache may evict the nodes holding them.
+type Loans = Rc<RefCell<Vec<RcBytes>>>;
+
+/// The state of a PersistentBTree that is shared with any views of that tree.
+struct BTreeRoot {
 	// TODO: this shouldn't be an option.
 	head: Option<FatNodeRef>,
 	/// Gets the max txid of this PersistentBTree (exclusive). The next transient material to be persisted
 	/// will have this txid.
 	// TODO: test this invariant.
 	leading_txid: Counter,
+	/// If true, this tree is an immutable snapshot, and writes return an error.
+	frozen: bool,
+	/// If true, deletes leave tombstones, since some snapshot may have seen the deleted key.
+	keep_tombstones: bool,
+	pins: SnapshotPins,
+	/// If this is a snapshot or diff, the pin keeping tombstones newer than its txid alive.
+	pin: Option<Rc<Counter>>,
+	/// The size limits of each node in this tree.
+	node_limits: NodeLimits,
+	chunking: Chunking,
+	/// If this tree is frozen and has buffered writes, a copy of its head with the buffers drained, for cursors.
+	drained: Option<FatNodeRef>,
+	/// The arena of the current transaction, which transient nodes are allocated from.
+	/// Replaced once every transient node has been made persistent.
+	arena: Rc<NodeArena>,
+	/// The file this tree was opened from, which commits go to.
+	file: Option<TreeFile>,
+	/// The number of bytes of nodes a tree opened from a file keeps in memory.
+	cache_capacity: usize,
+	loans: Loans,
+	/// If this is the tree returned by `open`, the log of its writes since the last commit.
+	log: Option<Wal>,
+	/// The number of writes the log syncs at once.
+	log_group_size: usize,
+	/// Keys written through cursors but not yet logged. Cursors hand out mutable values, so they are logged
+	/// at the next write, once the values are final.
+	unlogged: Vec<RcBytes>,
 }
 
-impl PersistentBTree {
-	pub fn new() -> PersistentBTree {
-		PersistentBTree {
-			head: None,
-			leading_txid: Counter::new(0),
+/// The file a tree was opened from, and the cache of nodes loaded from it. Shared by all snapshots of the tree.
+#[derive(Clone)]
+struct TreeFile {
+	store: Rc<FileStore>,
+	cache: Rc<NodeCache>,
+}
+
+/// Where a tree keeps its large values. Shared by all snapshots and views of the tree.
+#[derive(Clone)]
+struct Chunking {
+	/// Values at least this long are stored in chunks, if set.
+	threshold: Option<usize>,
+	store: Rc<ChunkStore>,
+}
+
+impl BTreeRoot {
+	/// Evicts cold nodes from the cache of the file this tree was opened from, if any. Only called between
+	/// operations, which hold weak references into the nodes they visit.
+	fn trim_cache(&self) {
+		if let Some(ref file) = self.file {
+			file.cache.trim();
 		}
 	}
 
-	/// Gets the max txid of this PersistentBTree (exclusive).
-	fn txid(&self) -> Counter {
-		self.leading_txid
+	/// Unpins the values handed out since the last write. Only called by writes, which borrow the tree mutably.
+	fn release_loans(&self) {
+		self.loans.borrow_mut().clear();
 	}
 
-	/// Internal method for snapshot diffs.
-	fn get_recent<K: Key + ?Sized>(&mut self, k: &K, trailing_txid: Counter) -> Option<RcBytes> {
-		self.head.as_ref().and_then(|strongref| btree_get::get_recent(strongref.noderef(), k.bytes(), trailing_txid))
-	}
+	/// Appends a write to this tree's log, if it has one, after any cursor writes made before it.
+	fn log(&mut self, op: WalOp, full_key: &[u8], v: &[u8]) -> Result<(), TreeError> {
+		if self.log.is_none() {
+			return Ok(())
+		}
 
-	/// Makes a persistent clone of this PersistentBTree. Does *not* update the current txid, of course.
-	fn shallow_clone(&mut self) -> Self {
-		let cloned_head;
+		self.log_cursor_writes()?;
+		let txid = self.leading_txid;
+		self.log.as_mut().unwrap().append(op, txid, full_key, v)
+	}
 
-		match self.head.as_mut() {
-			Some(strongref) => {
-				strongref.immute(self.leading_txid);
-				cloned_head = Some(strongref.shallow_clone());
-			}
-			None => {
-				cloned_head = None;
-			}
+	/// Notes a write through a cursor, to be logged at the next write.
+	fn log_later(&mut self, full_key: RcBytes) {
+		if self.log.is_some() {
+			self.unlogged.push(full_key);
 		}
+	}
 
-		PersistentBTree {
-			head: cloned_head,
-			leading_txid: self.leading_txid,
+	/// Logs the current value of every key written through a cursor since the last write.
+	fn log_cursor_writes(&mut self) -> Result<(), TreeError> {
+		let txid = self.leading_txid;
+		for k in mem::replace(&mut self.unlogged, Vec::new()) {
+			let b = self.head.as_ref().and_then(|strongref| btree_get::get_latest(strongref.noderef(), &k));
+			let (op, v) = match b {
+				Some(ref b) if !b.is_tombstone() => (WalOp::Put, b.value()),
+				_ => (WalOp::Delete, RcBytes::new(&[][..])),
+			};
+			self.log.as_mut().unwrap().append(op, txid, &k, &v)?;
 		}
+
+		Ok(())
 	}
 
-	/// Like shallow clone, except not mutable. Panics if this tree is not persistent.
-	fn persistent_clone(&self) -> Self {
-		PersistentBTree {
-			head: self.head.as_ref().map(|strongref| strongref.shallow_clone()),
-			leading_txid: self.leading_txid,
+	/// Makes a bucket for the given key and value, moving the value off-tree if it's large.
+	/// Neither is copied.
+	fn make_bucket(&self, full_key: RcBytes, v: RcBytes) -> BucketRef {
+		match self.chunking.threshold {
+			Some(threshold) if v.len() >= threshold =>
+				BucketRef::transient_chunked(full_key, ChunkStore::put(&self.chunking.store, v)),
+			_ => BucketRef::transient_from_rcbytes(full_key, v),
 		}
 	}
 
-	fn cursor(&self, k: &[u8]) -> BTreeCursor {
-		match self.head.as_ref() {
-			Some(strongref) => BTreeCursor::construct(strongref.noderef(), k),
-			None => BTreeCursor::empty(),
+	/// Stops leaving tombstones once every snapshot and diff pinning them is gone. Dead pins are pruned
+	/// from the front, so this takes amortized constant time.
+	fn release_tombstones(&mut self) {
+		if self.keep_tombstones {
+			let mut pins = self.pins.borrow_mut();
+			let dead = pins.iter().take_while(|pin| pin.upgrade().is_none()).count();
+			pins.drain(..dead);
+			self.keep_tombstones = !pins.is_empty();
 		}
 	}
-}
 
-impl Map<PersistentBTreeSpec> for PersistentBTree {
-    // TODO: existence check
-    fn entry<'a, K: AsRef<[u8]>>(&'a self, k: K) -> Result<Option<BTreeCursor<'a>>, TreeError> {
-        Ok(Some(self.cursor(k.as_ref())))
-    }
+	/// True if writes are buffered in the head rather than inserted into the leaves.
+	fn is_buffered(&self) -> bool {
+		self.node_limits.buffer_capacity > 0 && self.head.as_ref().map_or(false, |h| !h.apply(MemNode::is_leaf))
+	}
 
-	// TODO: feature-gate.
-	fn check_invariants(&self) {
-      self.head.as_ref().map(|strongref| strongref.check_invariants());
-    }
-}
+	/// Inserts the given bucket into its leaf or branch, overwriting any bucket with the same key.
+	fn insert(&mut self, b: BucketRef) {
+		let newhead = match self.head.as_ref() {
+			Some(strongref) => btree_insert::insert_bucket(&mut strongref.noderef(), b, &self.arena),
+			None => FatNodeRef::new_transient(&self.arena, MemNode::new_from_one(self.node_limits, b)),
+		};
 
-impl<'a> TreeSpec<'a> for PersistentBTreeSpec {
-    type Cursor = BTreeCursor<'a>;
-    type SuffixSpec = PersistentBTreeSpec;
-    type SuffixImpl = PersistentBTree;
-    type SubrangeSpec = PersistentBTreeSpec;
-    type SubrangeImpl = PersistentBTree;
-}
+		self.head = Some(newhead);
+	}
+
+	/// Inserts the given write, merging it into the bucket with the same key if it's a delta.
+	fn insert_merged(&mut self, b: BucketRef) {
+		let b = if b.is_delta() {
+			let older = self.head.as_ref().and_then(|strongref| btree_get::get(strongref.noderef(), b.key()));
+			let merged = merge_write(self.node_limits.merge_operator, older.as_ref(), b);
+			let mut b = self.make_bucket(RcBytes::new(merged.key()), merged.value());
+			if let BucketRef::Persistent(_, txid) = merged {
+				b.immute(txid);
+			}
+			b
+		} else {
+			b
+		};
+
+		self.insert(b);
+	}
+
+	/// Writes the given bucket, buffering it in the head if possible.
+	fn write(&mut self, b: BucketRef) {
+		if !self.is_buffered() {
+			return self.insert_merged(b)
+		}
+
+		// Deletes of keys in branch buckets are applied to the bucket at once, rather than flushed.
+		let deleted = if b.is_tombstone() && !self.keep_tombstones { Some(b.key().to_vec()) } else { None };
+		let flushed = btree_buffer::write(self.head.as_mut().unwrap(), b, &self.arena);
+		self.insert_flushed(flushed);
+		self.remove_tombstones(deleted.into_iter().collect());
+	}
+
+	/// Applies every buffered write, so the tree can be walked without looking at buffers.
+	fn drain(&mut self) {
+		let mut flushed = Vec::new();
+
+		match self.head.as_mut() {
+			Some(strongref) if strongref.apply(MemNode::is_dirty) => {
+				strongref.heat(&self.arena);
+				btree_buffer::drain(strongref.noderef(), &mut flushed);
+			}
+			_ => return,
+		}
+
+		self.insert_flushed(flushed);
+	}
+
+	/// Returns an error if a node of the file this tree was opened from couldn't be read. What the tree
+	/// read since may be wrong, so reads and writes fail from then on.
+	fn check_file(&self) -> Result<(), TreeError> {
+		self.file.as_ref().map_or(Ok(()), |file| file.cache.check())
+	}
+
+	/// Gets the settings this tree writes its pages with.
+	fn settings(&self) -> TreeSettings {
+		TreeSettings {
+			node_capacity: self.node_limits.capacity,
+			max_bytes: self.node_limits.max_bytes,
+			buffer_capacity: self.node_limits.buffer_capacity,
+			chunk_threshold: self.chunking.threshold,
+			has_merge_operator: self.node_limits.merge_operator.is_some(),
+		}
+	}
+
+	/// Checks that pages written with the given settings can be read by this tree.
+	fn check_settings(&self, saved: &TreeSettings) -> Result<(), TreeError> {
+		let ours = self.settings();
+		if *saved != ours {
+			return Err(TreeError::RuntimeError(format!("tree was written with {:?}, not {:?}", saved, ours)));
+		}
+
+		Ok(())
+	}
+
+	/// Gets the head for cursors to walk, with every buffered write in its bucket. Transient trees are drained
+	/// in place. Frozen trees must not change, so they drain a private copy once, which later cursors share.
+	fn drained_head(&mut self) -> Option<NodeRef> {
+		if !self.frozen {
+			self.drain();
+		} else if self.drained.is_none() && self.head.as_ref().map_or(false, |h| h.apply(MemNode::is_dirty)) {
+			let txid = self.leading_txid;
+			let copy = PersistentBTree::from_root(self.head.as_ref().map(|strongref| strongref.immuted_copy(txid)),
+				txid, self.node_limits, self.chunking.clone());
+			let mut root = copy.root.borrow_mut();
+			root.keep_tombstones = true;
+			root.drain();
+			self.drained = root.head.take().map(|mut strongref| {
+				strongref.immute(txid);
+				strongref
+			});
+		}
+
+		self.drained.as_ref().or(self.head.as_ref()).map(FatNodeRef::noderef)
+	}
+
+	/// Inserts writes flushed out of the buffers over leaves. Without snapshots, nobody needs the tombstones
+	/// among them, so their keys are removed.
+	fn insert_flushed(&mut self, flushed: Vec<BucketRef>) {
+		let mut removed = Vec::new();
+
+		for b in flushed {
+			if b.is_tombstone() && !self.keep_tombstones {
+				let live = self.head.as_ref()
+					.and_then(|strongref| btree_get::get(strongref.noderef(), b.key()))
+					.map_or(false, |b| !b.is_tombstone());
+				if !live {
+					continue;
+				}
+				removed.push(b.key().to_vec());
+			}
+
+			self.insert_merged(b);
+		}
+
+		self.remove_tombstones(removed);
+	}
+
+	/// Removes the given keys if their buckets are tombstones.
+	fn remove_tombstones(&mut self, keys: Vec<Vec<u8>>) {
+		if keys.is_empty() {
+			return
+		}
+
+		// Removing buckets rebalances nodes, which would strand buffered writes. Newer buffered writes
+		// may revive a removed key, so the tombstones stay until the buffers are drained.
+		self.drain();
+		for k in keys {
+			let dead = self.head.as_ref()
+				.and_then(|strongref| btree_get::get(strongref.noderef(), &k))
+				.map_or(false, |b| b.is_tombstone());
+			if dead {
+				PersistentBTree::remove_raw(self, &k);
+			}
+		}
+	}
+
+	/// Merges the deltas buffered for the given key into the value below them, leaving the tree unchanged.
+	/// The result keeps the newest delta's txid, so diffs see no new change.
+	fn merge_deltas(&self, full_key: &[u8]) -> Option<BucketRef> {
+		let writes = match self.head.as_ref() {
+			Some(strongref) => btree_get::get_buffered_writes(strongref.noderef(), full_key),
+			None => return None,
+		};
+
+		let below;
+		let (deltas, older) = match writes.split_last() {
+			Some((last, rest)) if !last.is_delta() => (rest, Some(last)),
+			_ => {
+				below = btree_get::get(self.head.as_ref().unwrap().noderef(), full_key);
+				(&writes[..], below.as_ref())
+			}
+		};
+
+		// Oldest first. The first merge makes a value, which later deltas apply to in turn.
+		let prefix = RcBytes::new(&[][..]);
+		let mut merged: Option<BucketRef> = None;
+		for d in deltas.iter().rev() {
+			let d = BucketRef::transient_delta(full_key, &d.value());
+			let b = match merged {
+				Some(ref b) => merge_write(self.node_limits.merge_operator, Some(&b.downgrade(&prefix)), d),
+				None => merge_write(self.node_limits.merge_operator, older, d),
+			};
+			merged = Some(b);
+		}
+
+		merged.map(|merged| {
+			let mut b = self.make_bucket(RcBytes::new(full_key), merged.value());
+			if let Some(&WeakBucketRef::Persistent(_, txid)) = writes.first() {
+				b.immute(txid);
+			}
+			b
+		})
+	}
+}
+
+impl PersistentBTree {
+	pub fn new() -> PersistentBTree {
+		Self::with_node_capacity(DEFAULT_NODE_CAPACITY)
+	}
+
+	/// Creates a tree whose nodes have at most the given number of children. Small nodes are cheaper to copy
+	/// on write; large nodes make shallower trees. Panics unless the capacity is even and at least 4.
+	pub fn with_node_capacity(node_capacity: u16) -> PersistentBTree {
+		Self::with_node_limits(node_capacity, None)
+	}
+
+	/// Like with_node_capacity, but nodes also split when their keys and values exceed max_bytes,
+	/// as long as they have at least 3 buckets. Panics if max_bytes is 0.
+	pub fn with_node_limits(node_capacity: u16, max_bytes: Option<usize>) -> PersistentBTree {
+		let node_limits = NodeLimits::new(node_capacity, max_bytes);
+		assert!(node_limits.is_valid(), "invalid node limits {:?}", node_limits);
+		let chunking = Chunking {
+			threshold: None,
+			store: ChunkStore::new(),
+		};
+		Self::from_root(None, Counter::new(0), node_limits, chunking)
+	}
+
+	/// Buffers up to the given number of writes in each branch node, flushing them to the child with the most
+	/// buffered writes on overflow, so most writes copy only the head. If 0, writes go straight to the leaves.
+	/// Panics unless the tree is empty.
+	pub fn with_buffer_capacity(self, buffer_capacity: u16) -> PersistentBTree {
+		assert!(self.root.borrow().head.is_none(), "buffer capacity set on a non-empty tree");
+		self.root.borrow_mut().node_limits.buffer_capacity = buffer_capacity;
+		self
+	}
+
+	/// Lets this tree take deltas through `TreeMut::upsert`, merging them with the given operator. Deltas are
+	/// buffered like other writes, combined as they are flushed, and applied when they reach their key's bucket
+	/// or the key is read. Panics unless the tree is empty.
+	pub fn with_merge_operator(self, op: MergeOperator) -> PersistentBTree {
+		assert!(self.root.borrow().head.is_none(), "merge operator set on a non-empty tree");
+		self.root.borrow_mut().node_limits.merge_operator = Some(op);
+		self
+	}
+
+	/// Stores values of at least the given length off-tree, so nodes hold only a small handle to them.
+	/// Chunks are loaded when read and shared by snapshots. Panics unless the tree is empty.
+	pub fn with_chunk_threshold(self, threshold: usize) -> PersistentBTree {
+		assert!(self.root.borrow().head.is_none(), "chunk threshold set on a non-empty tree");
+		self.root.borrow_mut().chunking.threshold = Some(threshold);
+		self
+	}
+
+	/// Keeps at most about the given number of bytes of nodes in memory once the tree is opened from a file.
+	/// Nodes saved to the file are evicted as needed and reloaded when used.
+	pub fn with_cache_capacity(self, bytes: usize) -> PersistentBTree {
+		self.root.borrow_mut().cache_capacity = bytes;
+		self
+	}
+
+	/// Syncs the write-ahead log of a tree opened from a file once every given number of writes, instead of after
+	/// each one. Larger groups take fewer fsyncs, but a crash may lose the writes of the current group.
+	/// Panics if group_size is 0.
+	pub fn with_log_group_size(self, group_size: usize) -> PersistentBTree {
+		assert!(group_size > 0, "log group size must be positive");
+		self.root.borrow_mut().log_group_size = group_size;
+		self
+	}
+
+	fn from_root(head: Option<FatNodeRef>, leading_txid: Counter, node_limits: NodeLimits, chunking: Chunking)
+		-> PersistentBTree {
+		PersistentBTree {
+			root: Rc::new(RefCell::new(BTreeRoot {
+				head: head,
+				leading_txid: leading_txid,
+				frozen: false,
+				keep_tombstones: false,
+				pins: Rc::new(RefCell::new(Vec::new())),
+				pin: None,
+				node_limits: node_limits,
+				chunking: chunking,
+				drained: None,
+				arena: NodeArena::new(),
+				file: None,
+				cache_capacity: DEFAULT_CACHE_CAPACITY,
+				loans: Rc::new(RefCell::new(Vec::new())),
+				log: None,
+				log_group_size: 1,
+				unlogged: Vec::new(),
+			})),
+			prefix: RcBytes::new(&[][..]),
+			ranges: Rc::new(Vec::new()),
+			trailing_txid: None,
+			writable: true,
+		}
+	}
+
+	/// Gets the max number of children of each node in this tree.
+	pub fn node_capacity(&self) -> u16 {
+		self.root.borrow().node_limits.capacity
+	}
+
+	/// Gets the max number of key and value bytes in each node in this tree, if limited.
+	pub fn node_max_bytes(&self) -> Option<usize> {
+		self.root.borrow().node_limits.max_bytes
+	}
+
+	/// Gets the number of values stored off-tree by this tree and its snapshots.
+	pub fn chunk_count(&self) -> usize {
+		self.root.borrow().chunking.store.len()
+	}
+
+	/// Gets the number of bytes stored off-tree by this tree and its snapshots.
+	pub fn chunk_bytes(&self) -> usize {
+		self.root.borrow().chunking.store.byte_size()
+	}
+
+	/// Gets the number of bytes of nodes loaded from this tree's file and held by its cache.
+	pub fn cache_bytes(&self) -> usize {
+		self.root.borrow().file.as_ref().map_or(0, |file| file.cache.byte_size())
+	}
+
+	/// Gets the number of nodes loaded from this tree's file, including reloads of evicted nodes.
+	pub fn cache_faults(&self) -> usize {
+		self.root.borrow().file.as_ref().map_or(0, |file| file.cache.faults())
+	}
+
+	/// Gets the number of node slots in this tree's arena, which holds the nodes written since its last snapshot.
+	pub fn transient_node_count(&self) -> usize {
+		self.root.borrow().arena.len()
+	}
+
+	/// Gets the max txid of this PersistentBTree (exclusive).
+	fn txid(&self) -> Counter {
+		self.root.borrow().leading_txid
+	}
+
+	fn head(&self) -> Option<NodeRef> {
+		self.root.borrow().head.as_ref().map(FatNodeRef::noderef)
+	}
+
+	/// Translates a key in this tree into a key in the underlying tree.
+	fn full_key<'k>(&self, k: &'k [u8]) -> Cow<'k, [u8]> {
+		if self.prefix.is_empty() {
+			Cow::Borrowed(k)
+		} else {
+			let mut r = self.prefix.to_vec();
+			r.extend_from_slice(k);
+			Cow::Owned(r)
+		}
+	}
+
+	/// Like full_key, but returns an error if the key is outside the bounds of this view.
+	fn full_key_checked<'k>(&self, k: &'k [u8]) -> Result<Cow<'k, [u8]>, TreeError> {
+		let full_key = self.full_key(k);
+		self.check_bounds(&full_key)?;
+
+		Ok(full_key)
+	}
+
+	/// Returns an error if the given full key is outside the bounds of this view.
+	fn check_bounds(&self, full_key: &[u8]) -> Result<(), TreeError> {
+		if self.ranges.iter().all(|range| range.contains(&full_key)) {
+			Ok(())
+		} else {
+			let k = &full_key[self.prefix.len()..];
+			Err(TreeError::RuntimeError(format!("key {:?} is outside the bounds of this view", k)))
+		}
+	}
+
+	/// Returns a view of this tree with the given prefix and ranges. Views share this tree's root,
+	/// and are only writable if asked for and if this tree is.
+	fn view(&self, prefix: RcBytes, ranges: Vec<Range>, writable: bool) -> PersistentBTree {
+		PersistentBTree {
+			root: self.root.clone(),
+			prefix: prefix,
+			ranges: Rc::new(ranges),
+			trailing_txid: self.trailing_txid,
+			writable: writable && self.writable,
+		}
+	}
+
+	/// Returns a view of the keys starting with the given prefix, which is stripped from them.
+	fn suffix_view(&self, prefix: &[u8], writable: bool) -> PersistentBTree {
+		// A suffix of a suffix is a suffix with a longer prefix.
+		let full_prefix = self.full_key(prefix).into_owned();
+
+		// If there is no prefix end, every key after the prefix starts with the prefix,
+		// and cursors never start before the prefix, so we need no new range.
+		let mut ranges = (*self.ranges).clone();
+		if let Some(end) = prefix_end(&full_prefix) {
+			ranges.push(Range::right_open(full_prefix.clone().into_boxed_slice(), end.into_boxed_slice()));
+		}
+
+		self.view(RcBytes::new(full_prefix), ranges, writable)
+	}
+
+	/// Returns a view of this tree bounded by [start, end), or an empty view unless start < end.
+	fn subrange_view(&self, start: &[u8], end: &[u8], writable: bool) -> PersistentBTree {
+		let range = if start < end {
+			Range::right_open(Box::from(start), Box::from(end))
+		} else {
+			Range::empty(Box::from(start))
+		};
+		let mut ranges = (*self.ranges).clone();
+		ranges.push(range.with_prefix(&self.prefix));
+
+		self.view(self.prefix.clone(), ranges, writable)
+	}
+
+	fn cursor_bounds(&self) -> CursorBounds {
+		CursorBounds {
+			prefix_len: self.prefix.len(),
+			ranges: self.ranges.clone(),
+			trailing_txid: self.trailing_txid,
+		}
+	}
+
+	/// Makes a persistent clone of this PersistentBTree, immuting its nodes in place if needed. The clone has
+	/// the current txid. If this tree is transient, the current txid is bumped.
+	///
+	/// Immuting moves transient nodes out of this tree's arena, so this takes &mut self: no cursor or
+	/// borrowed value may be pointing into them.
+	fn shallow_clone(&mut self) -> Self {
+		if !self.is_frozen() {
+			let mut root = self.root.borrow_mut();
+			let txid = root.leading_txid;
+			if let Some(strongref) = root.head.as_mut() {
+				strongref.immute(txid);
+			}
+			// Every transient node was moved out of the arena, so we can free it.
+			root.arena = NodeArena::new();
+		}
+
+		let r = self.persistent_clone();
+
+		let mut root = self.root.borrow_mut();
+		if !root.frozen {
+			// We might bump the leading txid even if the transaction does nothing. This is by design.
+			root.leading_txid = root.leading_txid.inc();
+			root.keep_tombstones = true;
+		}
+
+		r
+	}
+
+	/// Makes the given clone immutable with the given trailing txid, pinning that txid
+	/// (or the clone's txid, if none) so compaction keeps the tombstones it can see.
+	fn frozen(mut r: PersistentBTree, trailing_txid: Option<Counter>) -> PersistentBTree {
+		r.trailing_txid = trailing_txid;
+
+		{
+			let mut root = r.root.borrow_mut();
+			let pin = Rc::new(trailing_txid.unwrap_or(root.leading_txid));
+			{
+				let mut pins = root.pins.borrow_mut();
+				// Prune dead pins before growing, so frequent snapshots take amortized constant space.
+				if pins.len() == pins.capacity() {
+					pins.retain(|pin| pin.upgrade().is_some());
+				}
+				pins.push(Rc::downgrade(&pin));
+			}
+			root.pin = Some(pin);
+			root.frozen = true;
+		}
+
+		r
+	}
+
+	/// Like shallow clone, except not mutable: transient nodes are copied rather than immuted in place,
+	/// and the txid isn't bumped. The clone has the current txid.
+	fn persistent_clone(&self) -> Self {
+		let root = self.root.borrow();
+		let txid = root.leading_txid;
+
+		let r = PersistentBTree {
+			prefix: self.prefix.clone(),
+			ranges: self.ranges.clone(),
+			trailing_txid: self.trailing_txid,
+			..Self::from_root(root.head.as_ref().map(|strongref| strongref.immuted_copy(txid)), txid, root.node_limits,
+				root.chunking.clone())
+		};
+		{
+			let mut newroot = r.root.borrow_mut();
+			newroot.keep_tombstones = true;
+			newroot.pins = root.pins.clone();
+			newroot.file = root.file.clone();
+		}
+
+		r
+	}
+
+	/// Returns an immutable snapshot of this tree. Later writes to this tree do not affect the snapshot.
+	/// Freezing a transient tree immutes its nodes in place and bumps its txid.
+	pub fn freeze(&mut self) -> PersistentBTree {
+		Self::frozen(self.shallow_clone(), self.trailing_txid)
+	}
+
+	/// Returns a transient copy of this tree. Writes to the copy do not affect this tree, and vice versa.
+	/// Thawing a transient tree copies its unfrozen writes, so it leaves this tree's nodes alone.
+	pub fn thaw(&self) -> PersistentBTree {
+		let r = self.persistent_clone();
+		{
+			let mut root = r.root.borrow_mut();
+			root.leading_txid = root.leading_txid.inc();
+		}
+		if !self.is_frozen() {
+			self.root.borrow_mut().keep_tombstones = true;
+		}
+		r
+	}
+
+	/// Merges two trees forked from the given base snapshot into a new transient tree. Keys changed in only
+	/// one fork take that fork's value. Keys changed differently in both forks are passed to resolve with
+	/// their base, left, and right values, where None means the key is absent, and take the value it returns.
+	///
+	/// Subtrees shared with the base are never visited, so merges cost time proportional to the changes.
+	pub fn merge<F>(base: &PersistentBTree, left: &PersistentBTree, right: &PersistentBTree, mut resolve: F)
+	-> Result<PersistentBTree, TreeError>
+	where F: FnMut(&[u8], Option<&[u8]>, Option<&[u8]>, Option<&[u8]>) -> Result<Option<Vec<u8>>, TreeError> {
+		if !base.is_frozen() {
+			return Err(TreeError::RuntimeError(String::from("merge base must be a snapshot")));
+		}
+
+		// Work on persistent copies, so transient inputs aren't snapshotted.
+		let (left, right) = (left.persistent_clone(), right.persistent_clone());
+		if !left.descends_from(base) || !right.descends_from(base) {
+			return Err(TreeError::RuntimeError(String::from("merged trees must be forked from the merge base")));
+		}
+
+		// Diffs skip any persistent node not newer than the base, viz. any node shared with the base.
+		let c = base.counter();
+		let left_changes = left.diff(c);
+		let right_changes = right.diff(c);
+		let mut result = left.thaw();
+
+		let mut rc = Tree::cursor(&right_changes, [])?;
+		while rc.exists() {
+			let k = rc.key().unwrap();
+			let rv = if rc.is_removed() { None } else { Some(rc.get()) };
+
+			// The value to write to the merged tree, if any. Some(None) deletes the key.
+			let write = match left_changes.entry(&k)? {
+				None => Some(rv.map(<[u8]>::to_vec)),
+				Some(le) => {
+					let lv = if le.is_removed() { None } else { Some(le.get()) };
+					let bv = base.get(&k)?;
+
+					if lv == rv || rv == bv {
+						None
+					} else if lv == bv {
+						Some(rv.map(<[u8]>::to_vec))
+					} else {
+						Some(resolve(&k, bv, lv, rv)?)
+					}
+				},
+			};
+
+			match write {
+				Some(Some(v)) => result.put(&k, v)?,
+				Some(None) => result.delete(&k)?,
+				None => (),
+			}
+
+			rc.next()?;
+		}
+
+		Ok(result)
+	}
+
+	/// Returns true if this tree was forked from the given snapshot: it shares the snapshot's chunk store,
+	/// is no older, and every node it has from before the snapshot is one of the snapshot's nodes.
+	fn descends_from(&self, base: &PersistentBTree) -> bool {
+		let (root, base_root) = (self.root.borrow(), base.root.borrow());
+		if !Rc::ptr_eq(&root.chunking.store, &base_root.chunking.store)
+			|| root.leading_txid.circle_lt(base_root.leading_txid) {
+			return false
+		}
+
+		match root.head {
+			Some(ref strongref) => Self::shares_old_nodes(&strongref.noderef(),
+				base_root.head.as_ref().map(FatNodeRef::noderef), base.counter()),
+			None => true,
+		}
+	}
+
+	/// Returns true if every node under n that isn't newer than the given txid is also under base_head.
+	/// Only visits nodes newer than the txid, and the children of those nodes.
+	fn shares_old_nodes(n: &NodeRef, base_head: Option<NodeRef>, txid: Counter) -> bool {
+		if !n.is_newer_than(txid) {
+			return base_head.map_or(false, |h| Self::has_node(h, n))
+		}
+		if n.apply(MemNode::is_leaf) {
+			return true
+		}
+
+		let children: Vec<_> = n.apply(|node| (0..node.child_count()).map(|i| node.child_ref(i)).collect());
+		children.iter().all(|child| Self::shares_old_nodes(child, base_head.clone(), txid))
+	}
+
+	/// Returns true if the given node is under head. Looks only along the search path of its first key.
+	fn has_node(mut head: NodeRef, n: &NodeRef) -> bool {
+		let k = n.apply(|node| if node.bucket_count() > 0 { Some(node.full_key(0)) } else { None });
+
+		loop {
+			if head.is_same_node(n) {
+				return true
+			}
+			let k = match k {
+				Some(ref k) => k,
+				None => return false,
+			};
+
+			match head.apply(|node| node.find(k)) {
+				Err(idx) if !head.apply(MemNode::is_leaf) => head = head.apply(|node| node.child_ref(idx)),
+				// A node holding the key itself isn't above n.
+				_ => return false,
+			}
+		}
+	}
+
+	pub fn is_frozen(&self) -> bool {
+		self.root.borrow().frozen
+	}
+
+	fn check_writable(&self) -> Result<(), TreeError> {
+		if self.is_frozen() {
+			Err(TreeError::RuntimeError(String::from("cannot write to an immutable snapshot")))
+		} else if !self.writable {
+			Err(TreeError::RuntimeError(String::from("cannot write through a read-only view")))
+		} else {
+			self.root.borrow().check_file()
+		}
+	}
+
+	/// Returns a cursor pointing to the given key of the underlying tree, bounded by the ranges of this view.
+	/// Since cursors are not strongly tied to nodes, any lifetime may be given.
+	fn raw_cursor<'a>(&self, full_key: &[u8]) -> BTreeCursor<'a> {
+		self.root.borrow().trim_cache();
+		// Cursors walk nodes directly, so buffered writes have to reach their buckets first.
+		let head = self.root.borrow_mut().drained_head();
+
+		let mut r = match head {
+			Some(noderef) => BTreeCursor::construct(noderef, full_key, self.cursor_bounds()),
+			None => BTreeCursor::empty(),
+		};
+		r.loans = self.loans();
+		r.cache = self.cache();
+		r
+	}
+
+	/// Like raw_cursor, but returns None unless the given key exists.
+	fn raw_entry<'a>(&self, full_key: &[u8]) -> Option<BTreeCursor<'a>> {
+		self.root.borrow().trim_cache();
+		let (loans, cache) = (self.loans(), self.cache());
+		self.find_entry(full_key).map(|mut r| {
+			r.loans = r.loans.take().or(loans);
+			r.cache = cache;
+			r
+		})
+	}
+
+	/// The node cache of the file this tree was opened from, if any.
+	fn cache(&self) -> Option<Rc<NodeCache>> {
+		self.root.borrow().file.as_ref().map(|file| file.cache.clone())
+	}
+
+	/// Where cursors should pin values they hand out, if this tree was opened from a file.
+	fn loans(&self) -> Option<Loans> {
+		let root = self.root.borrow();
+		root.file.as_ref().map(|_| root.loans.clone())
+	}
+
+	fn find_entry<'a>(&self, full_key: &[u8]) -> Option<BTreeCursor<'a>> {
+		let buffered = self.head().and_then(|noderef| btree_get::get_buffered(noderef, full_key));
+		if buffered.as_ref().map_or(false, WeakBucketRef::is_delta) {
+			// Entries point to a value, so deltas have to be merged into one, which the entry owns.
+			let merged = self.root.borrow().merge_deltas(full_key);
+			return merged.and_then(|b| BTreeCursor::construct_merged(b, self.root.clone(), self.cursor_bounds()));
+		}
+
+		if let Some(b) = buffered {
+			return BTreeCursor::construct_buffered(b, self.root.clone(), self.cursor_bounds());
+		}
+
+		// Diffs can stop searching as soon as they reach an unchanged subtree.
+		if let Some(txid) = self.trailing_txid {
+			if self.head().and_then(|noderef| btree_get::get_recent(noderef, full_key, txid)).is_none() {
+				return None;
+			}
+		}
+
+		self.head().and_then(|noderef| {
+			BTreeCursor::construct_exact(noderef, full_key, self.cursor_bounds())
+		})
+	}
+
+	fn cursor(&self, k: &[u8]) -> BTreeCursor {
+		self.raw_cursor(&self.full_key(k))
+	}
+
+	fn entry_exact(&self, k: &[u8]) -> Option<BTreeCursor> {
+		self.raw_entry(&self.full_key(k))
+	}
+
+	/// Returns a read-only view of this tree bounded by the given range. Keys in the view are the same as
+	/// keys in this tree. Views of views are bounded by both ranges.
+	pub fn range(&self, range: &Range) -> BTreeView<&PersistentBTree> {
+		let mut ranges = (*self.ranges).clone();
+		ranges.push(range.with_prefix(&self.prefix));
+
+		BTreeView::new(self.view(self.prefix.clone(), ranges, false))
+	}
+
+	fn put_raw(&mut self, full_key: RcBytes, v: RcBytes) -> Result<(), TreeError> {
+		let mut root = self.root.borrow_mut();
+		root.release_loans();
+		root.trim_cache();
+		root.log(WalOp::Put, &full_key, &v)?;
+		let b = root.make_bucket(full_key, v);
+		root.write(b);
+		root.check_file()
+	}
+
+	fn upsert_raw(&mut self, full_key: &[u8], delta: &[u8]) -> Result<(), TreeError> {
+		let mut root = self.root.borrow_mut();
+		root.release_loans();
+		root.trim_cache();
+		if root.node_limits.merge_operator.is_none() {
+			return Err(TreeError::RuntimeError(String::from("tree has no merge operator")));
+		}
+
+		root.log(WalOp::Upsert, full_key, delta)?;
+		root.write(BucketRef::transient_delta(full_key, delta));
+		root.check_file()
+	}
+
+	fn delete_raw(&mut self, full_key: &[u8]) -> Result<(), TreeError> {
+		let mut root = self.root.borrow_mut();
+		root.release_loans();
+		root.trim_cache();
+		root.log(WalOp::Delete, full_key, &[])?;
+		root.release_tombstones();
+
+		if root.keep_tombstones || root.is_buffered() {
+			// Some snapshot may have seen this key, so leave a tombstone for diffs. Buffered deletes are tombstones
+			// too, since we can't rebalance nodes under buffers, but without snapshots they remove the key once flushed.
+			let live = root.head.as_ref()
+				.and_then(|strongref| btree_get::get_latest(strongref.noderef(), full_key))
+				.map_or(false, |b| !b.is_tombstone());
+
+			if live {
+				root.write(BucketRef::transient_tombstone(full_key));
+			}
+		} else {
+			Self::remove_raw(&mut root, full_key);
+		}
+
+		root.check_file()
+	}
+
+	/// Removes the given key from the tree, without leaving a tombstone.
+	fn remove_raw(root: &mut BTreeRoot, full_key: &[u8]) {
+		let result = match root.head.as_ref() {
+			Some(strongref) => btree_delete::delete(&mut strongref.noderef(), full_key, &root.arena),
+			None => btree_delete::DeleteResult::NotFound,
+		};
+
+		if let btree_delete::DeleteResult::Deleted(newhead) = result {
+			root.head = newhead;
+		}
+	}
+
+	/// Saves this tree to the given store, returning the address of a root record holding the address of its head
+	/// and the settings its nodes were written with. Nodes already saved, by this tree or by snapshots sharing them,
+	/// are not saved again, so a tree and its snapshots should always be saved to the same store. Only persistent
+	/// nodes can be saved, so trees with writes since their last snapshot return an error.
+	pub fn save<S: BlockStore + 'static>(&self, store: &Rc<S>) -> BoxFuture<BlockAddress, TreeError> {
+		let (txid, settings) = {
+			let root = self.root.borrow();
+			(root.leading_txid, root.settings())
+		};
+		let store = store.clone();
+
+		self.save_head(&store).and_then(move |head| {
+			store.put(&RootRecord { head: head, txid: txid, settings: settings }.encode())
+		}).td_boxed()
+	}
+
+	/// Saves this tree's nodes to the given store, returning the address of its head, or None if it is empty.
+	fn save_head<S: BlockStore + 'static>(&self, store: &Rc<S>) -> BoxFuture<Option<NodeAddress>, TreeError> {
+		let head = match self.root.borrow().head {
+			Some(ref strongref) if strongref.is_transient() =>
+				return future::err(TreeError::RuntimeError(String::from("tree has writes since its last snapshot")))
+					.td_boxed(),
+			Some(ref strongref) => strongref.shallow_clone(),
+			None => return future::ok(None).td_boxed(),
+		};
+
+		btree_store::save(store, head).map(Some).td_boxed()
+	}
+
+	/// Loads the tree saved at the given address by `save`, as a snapshot. The loaded tree has this tree's node limits,
+	/// merge operator and chunk threshold, and returns an error unless they match the saved tree's.
+	/// Panics unless this tree is empty.
+	pub fn load<S: BlockStore + 'static>(self, store: &Rc<S>, addr: BlockAddress)
+		-> BoxFuture<PersistentBTree, TreeError> {
+		assert!(self.root.borrow().head.is_none(), "loaded into a non-empty tree");
+		let (limits, chunking) = {
+			let root = self.root.borrow();
+			(root.node_limits, root.chunking.clone())
+		};
+		let store = store.clone();
+
+		store.get(addr).and_then(move |block| {
+			let record = RootRecord::decode(&block)?;
+			self.root.borrow().check_settings(&record.settings)?;
+			Ok(record)
+		}).and_then(move |record| {
+			let head = match record.head {
+				Some(addr) => btree_store::load(&store, addr, limits, &chunking.store).map(Some).td_boxed(),
+				None => future::ok(None).td_boxed(),
+			};
+			head.map(move |head| Self::from_root(head, record.txid, limits, chunking).freeze())
+		}).td_boxed()
+	}
+
+	/// Opens the tree in the given file, creating the file if needed, and returns a transient tree holding the
+	/// last commit to the file. Nodes are loaded from the file as they are used, and cached up to the cache capacity.
+	/// Like `load`, returns an error unless this tree's settings match those of the committed tree.
+	/// Panics unless this tree is empty.
+	///
+	/// Writes to the returned tree are logged to the file's path with `.wal` appended, until they are committed.
+	/// Logged writes that were never committed are replayed here.
+	pub fn open<P: AsRef<Path>>(self, path: P) -> Result<PersistentBTree, TreeError> {
+		assert!(self.root.borrow().head.is_none(), "opened into a non-empty tree");
+		let (limits, chunking, cache_capacity, log_group_size) = {
+			let root = self.root.borrow();
+			(root.node_limits, root.chunking.clone(), root.cache_capacity, root.log_group_size)
+		};
+		let path = path.as_ref();
+		let store = Rc::new(FileStore::open(path)?);
+		let file = TreeFile {
+			cache: NodeCache::new(store.clone(), limits, chunking.store.clone(), cache_capacity),
+			store: store,
+		};
+
+		let (mut r, committed_txid) = match file.store.root().wait()? {
+			Some(record) => {
+				let record = RootRecord::decode(&record)?;
+				self.root.borrow().check_settings(&record.settings)?;
+
+				let head = record.head.map(|addr| FatNodeRef::new_stored(addr, &file.cache));
+				(Self::from_root(head, record.txid, limits, chunking).thaw(), Some(record.txid))
+			},
+			None => (self, None),
+		};
+		{
+			let mut root = r.root.borrow_mut();
+			root.file = Some(file);
+			root.cache_capacity = cache_capacity;
+			root.log_group_size = log_group_size;
+		}
+
+		let (log, records) = Wal::open(wal_path(path), log_group_size)?;
+		for record in records {
+			// The log may outlive a commit if we crashed before truncating it.
+			if committed_txid.map_or(false, |txid| record.txid.circle_lt_eq(txid)) {
+				continue;
+			}
+
+			// Replayed writes keep their txids, so later writes are logged after them.
+			if r.txid().circle_lt(record.txid) {
+				r.root.borrow_mut().leading_txid = record.txid;
+			}
+			match record.op {
+				WalOp::Put => r.put_raw(record.key, record.value)?,
+				WalOp::Delete => r.delete_raw(&record.key)?,
+				WalOp::Upsert => r.upsert_raw(&record.key, &record.value)?,
+			}
+		}
+		r.root.borrow_mut().log = Some(log);
+
+		Ok(r)
+	}
+
+	/// Commits this tree to the file it was opened from. Nodes written since the last commit are appended to the
+	/// file, then the file's root is switched to this tree's head, so a crash leaves either this commit or the last.
+	/// Afterwards, the committed nodes are only kept in memory by the cache and by snapshots.
+	pub fn commit(&mut self) -> Result<(), TreeError> {
+		self.check_writable()?;
+		let file = match self.root.borrow().file {
+			Some(ref file) => file.clone(),
+			None => return Err(TreeError::RuntimeError(String::from("tree was not opened from a file"))),
+		};
+
+		let snapshot = self.shallow_clone();
+		let record = RootRecord {
+			head: snapshot.save_head(&file.store).wait()?,
+			txid: snapshot.txid(),
+			settings: snapshot.root.borrow().settings(),
+		};
+		file.store.set_root(&record.encode()).wait()?;
+
+		let mut root = self.root.borrow_mut();
+		root.release_loans();
+		root.head = record.head.map(|addr| FatNodeRef::new_stored(addr, &file.cache));
+		file.cache.trim();
+		// Cursor writes not yet logged were committed with the rest.
+		root.unlogged.clear();
+		if let Some(ref mut log) = root.log {
+			log.truncate()?;
+		}
+
+		Ok(())
+	}
+
+	/// Makes every logged write durable, even if the log's current group is not full.
+	pub fn sync_log(&mut self) -> Result<(), TreeError> {
+		let mut root = self.root.borrow_mut();
+		if root.log.is_none() {
+			return Ok(())
+		}
+
+		root.log_cursor_writes()?;
+		root.log.as_mut().unwrap().sync()
+	}
+
+	/// Allocates a zeroed buffer for a key of the given length from this tree's transaction, to be filled in place
+	/// and passed to `put_alloc`. The buffer starts with this view's prefix, so the key needn't be copied to add it.
+	pub fn alloc_key(&self, len: usize) -> AllocBytes {
+		self.root.borrow().arena.alloc_bytes(&self.prefix, len)
+	}
+
+	/// Allocates a zeroed buffer for a value of the given length from this tree's transaction, to be filled in place
+	/// and passed to `put_alloc`.
+	pub fn alloc_value(&self, len: usize) -> AllocBytes {
+		self.root.borrow().arena.alloc_bytes(&[], len)
+	}
+
+	/// Puts a key and value allocated by `alloc_key` and `alloc_value`. Their bytes are moved into the tree,
+	/// not copied. Returns an error if either was allocated by another tree or before this tree's last snapshot,
+	/// or if the key was allocated by a view with a different prefix.
+	pub fn put_alloc(&mut self, k: AllocBytes, v: AllocBytes) -> Result<(), TreeError> {
+		self.check_writable()?;
+		{
+			let root = self.root.borrow();
+			if !root.arena.owns(&k) || !root.arena.owns(&v) {
+				return Err(TreeError::RuntimeError(String::from("buffer was not allocated by this transaction")));
+			}
+		}
+		if k.given() != &self.prefix[..] {
+			return Err(TreeError::RuntimeError(String::from("key was allocated by a view with another prefix")));
+		}
+
+		let full_key = k.into_rcbytes();
+		self.check_bounds(&full_key)?;
+		self.put_raw(full_key, v.into_rcbytes())
+	}
+
+	/// Drops every tombstone that no live snapshot or diff of this tree can see, returning the number
+	/// of tombstones dropped. Tombstones not yet frozen are kept while a snapshot of this tree is alive.
+	/// Snapshots are unaffected.
+	pub fn compact(&mut self) -> Result<usize, TreeError> {
+		self.check_writable()?;
+		let mut root = self.root.borrow_mut();
+		root.release_loans();
+		root.trim_cache();
+		// Removing buckets rebalances nodes, which would strand buffered writes.
+		root.drain();
+
+		// Tombstones at or before the oldest pinned txid are invisible to every diff.
+		let horizon = {
+			let mut pins = root.pins.borrow_mut();
+			pins.retain(|pin| pin.upgrade().is_some());
+			pins.iter().filter_map(Weak::upgrade).map(|pin| *pin)
+				.fold(None, |oldest: Option<Counter>, pin| match oldest {
+					Some(oldest) if oldest.circle_lt(pin) => Some(oldest),
+					_ => Some(pin),
+				})
+		};
+
+		// Without snapshots, even transient tombstones are invisible.
+		root.release_tombstones();
+		let keep_transient = root.keep_tombstones;
+		let mut keys = Vec::new();
+		if let Some(strongref) = root.head.as_ref() {
+			Self::collect_tombstones(&strongref.noderef(), horizon, keep_transient, &mut keys);
+		}
+
+		for k in &keys {
+			Self::remove_raw(&mut root, k);
+		}
+
+		Ok(keys.len())
+	}
+
+	fn collect_tombstones(n: &NodeRef, horizon: Option<Counter>, keep_transient: bool, out: &mut Vec<RcBytes>) {
+		let buckets: Vec<_> = n.apply(|node| (0..node.bucket_count()).map(|i| node.bucket_ref(i)).collect());
+
+		for b in buckets {
+			let droppable = match b {
+				WeakBucketRef::Persistent(_, txid) => horizon.map_or(true, |h| txid.circle_lt_eq(h)),
+				WeakBucketRef::Transient(_) => !keep_transient,
+			};
+
+			if b.is_tombstone() && droppable {
+				out.push(b.key());
+			}
+		}
+
+		if !n.apply(MemNode::is_leaf) {
+			let children: Vec<_> = n.apply(|node| (0..node.child_count()).map(|i| node.child_ref(i)).collect());
+
+			for child in children {
+				Self::collect_tombstones(&child, horizon, keep_transient, out);
+			}
+		}
+	}
+}
+
+impl Map<PersistentBTreeSpec> for PersistentBTree {
+    fn entry<'a, K: AsRef<[u8]>>(&'a self, k: K) -> Result<Option<BTreeCursor<'a>>, TreeError> {
+		let r = self.entry_exact(k.as_ref());
+		self.root.borrow().check_file()?;
+        Ok(r)
+    }
+
+	/// Like Map::get, but also returns an error if a chunked value couldn't be loaded.
+    fn get<'a, K: AsRef<[u8]>>(&'a self, k: K) -> Result<Option<&'a [u8]>, TreeError> {
+		let r = self.entry(k)?.map(Entry::unwrap);
+		self.root.borrow().check_file()?;
+        Ok(r)
+    }
+
+	// TODO: feature-gate.
+	fn check_invariants(&self) {
+		// Only check the parts of the tree visible from this view.
+		let should_check = |lower: Option<&[u8]>, upper: Option<&[u8]>| {
+			self.ranges.iter().all(|range| {
+				upper.map_or(true, |upper| upper > range.left())
+					&& lower.map_or(true, |lower| lower < range.right())
+			})
+		};
+
+		self.root.borrow().head.as_ref().map(|strongref| strongref.check_invariants_in(&should_check));
+    }
+}
+
+impl<'a> TreeSpec<'a> for PersistentBTreeSpec {
+    type Cursor = BTreeCursor<'a>;
+    type SuffixSpec = PersistentBTreeSpec;
+    type SuffixImpl = BTreeView<&'a PersistentBTree>;
+    type SubrangeSpec = PersistentBTreeSpec;
+    type SubrangeImpl = BTreeView<&'a PersistentBTree>;
+}
 
 impl Tree<PersistentBTreeSpec> for PersistentBTree {
     fn cursor<'b, K: AsRef<[u8]>>(&'b self, k: K) -> Result<BTreeCursor, TreeError> {
         // TODO: rename internal cursor method
-        Ok(self.cursor(k.as_ref()))
+		let r = self.cursor(k.as_ref());
+		self.root.borrow().check_file()?;
+        Ok(r)
 	}
 
-    fn suffix<'b, K: AsRef<[u8]>>(&'b self, prefix: K) -> PersistentBTree {
-		panic!()
+	/// Returns a read-only suffix view. Writes through the view return an error; see `suffix_mut`.
+    fn suffix<'b, K: AsRef<[u8]>>(&'b self, prefix: K) -> BTreeView<&'b PersistentBTree> {
+		BTreeView::new(self.suffix_view(prefix.as_ref(), false))
 	}
 
-    fn subrange<'b, K1: AsRef<[u8]>, K2: AsRef<[u8]>>(&self, start: K1, end: K2) -> PersistentBTree {
-		panic!()
+	/// Returns a read-only view of this tree bounded by [start, end). The view is empty unless start < end.
+	/// Writes through the view return an error; see `subrange_mut`.
+    fn subrange<'b, K1: AsRef<[u8]>, K2: AsRef<[u8]>>(&'b self, start: K1, end: K2) -> BTreeView<&'b PersistentBTree> {
+		BTreeView::new(self.subrange_view(start.as_ref(), end.as_ref(), false))
 	}
 }
 
 impl<'a> Subtree<'a, PersistentBTreeSpec> for PersistentBTree {} // TODO
 
+/// A suffix or subrange view of a PersistentBTree. The view borrows the tree it was made from, shared for
+/// read-only views and mutable for writable ones, so nothing can write to the tree while values read
+/// through the view are still borrowed, and vice versa.
+pub struct BTreeView<R> {
+	tree: PersistentBTree,
+	_borrow: PhantomData<R>,
+}
+
+impl<R> BTreeView<R> {
+	fn new(tree: PersistentBTree) -> BTreeView<R> {
+		BTreeView { tree: tree, _borrow: PhantomData }
+	}
+
+	/// Like PersistentBTree::put_alloc. Returns an error if this view is read-only.
+	pub fn put_alloc(&mut self, k: AllocBytes, v: AllocBytes) -> Result<(), TreeError> {
+		self.tree.put_alloc(k, v)
+	}
+}
+
+/// Views can be read like the trees they view. Writes go through TreeMut, which checks the view is writable.
+impl<R> Deref for BTreeView<R> {
+	type Target = PersistentBTree;
+
+	fn deref(&self) -> &PersistentBTree {
+		&self.tree
+	}
+}
+
+impl<R> Map<PersistentBTreeSpec> for BTreeView<R> {
+    fn entry<'a, K: AsRef<[u8]>>(&'a self, k: K) -> Result<Option<BTreeCursor<'a>>, TreeError> {
+		Map::entry(&self.tree, k)
+    }
+
+    fn get<'a, K: AsRef<[u8]>>(&'a self, k: K) -> Result<Option<&'a [u8]>, TreeError> {
+		Map::get(&self.tree, k)
+    }
+
+    fn check_invariants(&self) {
+		self.tree.check_invariants()
+    }
+}
+
+impl<R> Tree<PersistentBTreeSpec> for BTreeView<R> {
+    fn cursor<'b, K: AsRef<[u8]>>(&'b self, k: K) -> Result<BTreeCursor<'b>, TreeError> {
+		Tree::cursor(&self.tree, k)
+    }
+
+    fn suffix<'b, K: AsRef<[u8]>>(&'b self, prefix: K) -> BTreeView<&'b PersistentBTree> {
+		self.tree.suffix(prefix)
+    }
+
+    fn subrange<'b, K1: AsRef<[u8]>, K2: AsRef<[u8]>>(&'b self, start: K1, end: K2) -> BTreeView<&'b PersistentBTree> {
+		self.tree.subrange(start, end)
+    }
+}
+
+impl<'a, R> Subtree<'a, PersistentBTreeSpec> for BTreeView<R> {}
+
+impl<R> TreeMut<PersistentBTreeSpec> for BTreeView<R> {
+    fn entry_mut<'b, K: AsRef<[u8]>>(&'b mut self, k: K) -> Result<Option<BTreeCursorMut<'b>>, TreeError> {
+		self.tree.entry_mut(k)
+    }
+
+    fn cursor_mut<'b, K: AsRef<[u8]>>(&'b mut self, k: K) -> Result<BTreeCursorMut<'b>, TreeError> {
+		self.tree.cursor_mut(k)
+    }
+
+    fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, k: K, v: V) -> Result<(), TreeError> {
+		self.tree.put(k, v)
+    }
+
+    fn delete<K: AsRef<[u8]>>(&mut self, k: K) -> Result<(), TreeError> {
+		self.tree.delete(k)
+    }
+
+    fn upsert<K: AsRef<[u8]>, D: AsRef<[u8]>>(&mut self, k: K, delta: D) -> Result<(), TreeError> {
+		self.tree.upsert(k, delta)
+    }
+
+    fn suffix_mut<'b, K: AsRef<[u8]>>(&'b mut self, prefix: K) -> BTreeView<&'b mut PersistentBTree> {
+		self.tree.suffix_mut(prefix)
+    }
+
+    fn subrange_mut<'b, K1: AsRef<[u8]>, K2: AsRef<[u8]>>(&'b mut self, start: K1, end: K2) -> BTreeView<&'b mut PersistentBTree> {
+		self.tree.subrange_mut(start, end)
+    }
+}
+
 impl<'a> TreeMutSpec<'a> for PersistentBTreeSpec {
-    type EntryMut = BTreeCursor<'a>;
-    type CursorMut = BTreeCursor<'a>;
+    type EntryMut = BTreeCursorMut<'a>;
+    type CursorMut = BTreeCursorMut<'a>;
     type GetMut = &'a mut [u8];
+    type GetMutSpec = ByteDerefMutSpec;
     type SuffixMutSpec = PersistentBTreeSpec;
-    type SuffixMutImpl = PersistentBTree;
+    type SuffixMutImpl = BTreeView<&'a mut PersistentBTree>;
     type SubrangeMutSpec = PersistentBTreeSpec;
-    type SubrangeMutImpl = PersistentBTree;
+    type SubrangeMutImpl = BTreeView<&'a mut PersistentBTree>;
 }
 
-// TODO: need a BTreeCursorMut
+/// A cursor that can modify the tree it points into. Nodes on the cursor's path are heated on write,
+/// so older snapshots are never modified.
+pub struct BTreeCursorMut<'a> {
+	tree: &'a mut PersistentBTree,
+	cursor: BTreeCursor<'a>,
+}
+
+impl<'a> BTreeCursorMut<'a> {
+	fn new(tree: &'a mut PersistentBTree, cursor: BTreeCursor<'a>) -> BTreeCursorMut<'a> {
+		BTreeCursorMut {
+			tree: tree,
+			cursor: cursor,
+		}
+	}
+
+	fn current_bucket(&self) -> Result<&WeakBucketRef, TreeError> {
+		self.cursor.current_bucket.as_ref().ok_or_else(
+			|| TreeError::RuntimeError(String::from("cursor does not point to a key")))
+	}
+
+	/// Replaces the current bucket, heating the node stack if needed.
+	fn replace_current(&mut self, b: BucketRef) {
+		{
+			let mut root = self.tree.root.borrow_mut();
+			let root = &mut *root;
+			self.cursor.stack.heat(root.head.as_mut().unwrap(), &root.arena);
+		}
+
+		let &(ref n, idx) = self.cursor.stack.peek().unwrap();
+		n.apply_mut(|hn| hn.replace_bucket(idx, b));
+		self.cursor.current_bucket = Some(n.apply(|node| node.bucket_ref(idx)));
+	}
+
+	/// Makes the current value transient and unshared, copying it if needed, and returns a pointer to it.
+	fn value_ptr(&mut self) -> *mut [u8] {
+		let k = self.current_bucket().unwrap().key();
+		self.tree.root.borrow_mut().log_later(k);
+
+		let copy = match *self.current_bucket().unwrap() {
+			// Persistent values may be shared with older snapshots, so we copy before writing.
+			ref b @ WeakBucketRef::Persistent(_, _) =>
+				Some(self.tree.root.borrow().make_bucket(RcBytes::new(&b.key()[..]), RcBytes::new(&b.value()[..]))),
+			WeakBucketRef::Transient(_) => None,
+		};
+
+		if let Some(b) = copy {
+			self.replace_current(b);
+		}
+
+		// Our own weak reference would stop the value from being borrowed in place.
+		self.cursor.current_bucket = None;
+		let &(ref n, idx) = self.cursor.stack.peek().unwrap();
+		let p: *mut [u8] = n.apply_mut(|hn| hn.value_mut(idx) as *mut [u8]);
+		self.cursor.current_bucket = Some(n.apply(|node| node.bucket_ref(idx)));
+		p
+	}
+
+	/// Deletes the current key-value pair. Unlike `EntryMut::delete`, this does not consume the cursor;
+	/// afterwards, it points to the following key, if any.
+	pub fn remove(&mut self) -> Result<(), TreeError> {
+		let k = self.current_bucket()?.key();
+		self.tree.delete_raw(&k)?;
 
-impl<'a> EntryMut<'a, PersistentBTreeSpec> for BTreeCursor<'a> {
-    fn get_mut(&mut self) -> &'a mut [u8] {
-        panic!()
+		// Deletes may rebalance nodes on our stack, so we have to seek to our new position.
+		self.cursor = self.tree.raw_cursor(&k);
+
+		Ok(())
+	}
+}
+
+impl<'a> Entry<'a, PersistentBTreeSpec> for BTreeCursorMut<'a> {
+    fn get<'b>(&'b self) -> &'b [u8] {
+		self.cursor.get()
+    }
+
+    fn unwrap(self) -> &'a [u8] {
+		self.cursor.unwrap()
+    }
+}
+
+impl<'a> Cursor<'a, PersistentBTreeSpec> for BTreeCursorMut<'a> {
+    fn exists(&self) -> bool {
+		self.cursor.exists()
+	}
+
+	fn next(&mut self) -> Result<bool, TreeError> {
+		self.cursor.next()
+	}
+}
+
+impl<'a> EntryMut<'a, PersistentBTreeSpec> for BTreeCursorMut<'a> {
+    fn get_mut<'b>(&'b mut self) -> &'b mut [u8] {
+		let p = self.value_ptr();
+		// The value lives as long as its node, which can't change while we are mutably borrowed.
+		unsafe { &mut *p }
+    }
+
+    fn unwrap_mut(mut self) -> &'a mut [u8] {
+		let p = self.value_ptr();
+		// We hold the only borrow of the tree, and give it up for the value.
+		unsafe { &mut *p }
     }
 
     fn set<V: AsRef<[u8]>>(&mut self, v: V) {
-        panic!()
+		let (k, old_size) = {
+			let b = self.current_bucket().unwrap();
+			(b.key(), b.value_size())
+		};
+
+		self.tree.root.borrow_mut().log_later(k.clone());
+		let b = self.tree.root.borrow().make_bucket(RcBytes::new(&k[..]), RcBytes::new(v.as_ref()));
+		let new_size = b.value_size();
+		if new_size == old_size || self.tree.node_max_bytes().is_none() {
+			self.replace_current(b);
+		} else if new_size > old_size {
+			// Larger values may split nodes on our stack, so we have to seek to our new position.
+			self.tree.root.borrow_mut().insert(b);
+			self.cursor = self.tree.raw_cursor(&k);
+		} else {
+			self.replace_current(b);
+			// Smaller values may leave a non-head node deficient. Removing and reinserting the key rebalances it.
+			let deficient = self.cursor.stack.len() > 1 &&
+				self.cursor.stack.peek().unwrap().0.apply(MemNode::is_deficient);
+			if deficient {
+				{
+					let mut root = self.tree.root.borrow_mut();
+					// Removing buckets rebalances nodes, which would strand buffered writes.
+					root.drain();
+					let b = root.make_bucket(RcBytes::new(&k[..]), RcBytes::new(v.as_ref()));
+					PersistentBTree::remove_raw(&mut root, &k);
+					root.insert(b);
+				}
+				self.cursor = self.tree.raw_cursor(&k);
+			}
+		}
     }
 
-    fn delete(self) -> Result<(), TreeError> {
-        panic!()
+    fn delete(mut self) -> Result<(), TreeError> {
+		self.remove()
     }
 }
 
 impl TreeMut<PersistentBTreeSpec> for PersistentBTree {
-    fn entry_mut<'b, K: AsRef<[u8]>>(&'b mut self, k: K) -> Result<Option<BTreeCursor>, TreeError> {
-        self.entry(k.as_ref())
+    fn entry_mut<'b, K: AsRef<[u8]>>(&'b mut self, k: K) -> Result<Option<BTreeCursorMut<'b>>, TreeError> {
+		self.check_writable()?;
+		// Mutable entries walk nodes directly.
+		self.root.borrow_mut().drain();
+		let cursor = self.raw_entry(&self.full_key(k.as_ref()));
+        Ok(cursor.map(move |c| BTreeCursorMut::new(self, c)))
     }
 
-    fn cursor_mut<'b, K: AsRef<[u8]>>(&'b mut self, k: K) -> Result<BTreeCursor, TreeError> {
-        Tree::cursor(self, k.as_ref())
+    fn cursor_mut<'b, K: AsRef<[u8]>>(&'b mut self, k: K) -> Result<BTreeCursorMut<'b>, TreeError> {
+		self.check_writable()?;
+		let cursor = self.raw_cursor(&self.full_key(k.as_ref()));
+        Ok(BTreeCursorMut::new(self, cursor))
     }
 
     fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, k: K, v: V) -> Result<(), TreeError> {
-		let newhead;
+		self.check_writable()?;
+		let full_key = RcBytes::new(self.full_key_checked(k.as_ref())?);
+		self.put_raw(full_key, RcBytes::new(v.as_ref()))?;
 
-		match self.head.as_ref() {
-			Some(strongref) => {
-				newhead = btree_insert::insert(&mut strongref.noderef(), k.as_ref(), v.as_ref());
-			}
-			None => {
-				newhead = FatNodeRef::new_transient(MemNode::new_from_one(BucketRef::transient_from_bytes(k.as_ref(), v.as_ref())))
-			}
-		}
+        Ok(())
+    }
 
-		self.head = Some(newhead);
+    fn delete<K: AsRef<[u8]>>(&mut self, k: K) -> Result<(), TreeError> {
+		self.check_writable()?;
+		let full_key = self.full_key_checked(k.as_ref())?.into_owned();
+		self.delete_raw(&full_key)?;
 
         Ok(())
     }
 
-    fn suffix_mut<'b, K: AsRef<[u8]>>(&'b self, prefix: K) -> Self {
-        panic!()
-    }
-
-    fn subrange_mut<'b, K1: AsRef<[u8]>, K2: AsRef<[u8]>>(&'b self, start: K1, end: K2) -> Self {
-        panic!()
-    }
-}
-
-// pub struct PersistentDiff {
-// 	v: PersistentBTree,
-// 	rear_counter: Counter,
-// }
-//
-// impl ByteMap for PersistentDiff {
-// 	type GetDatum = <PersistentBTree as ByteMap>::GetDatum;
-// 	type Get = <PersistentBTree as ByteMap>::Get;
-//
-// 	fn get<K: Key + ?Sized>(&mut self, k: &K) -> Option<Self::Get> {
-// 		self.v.get_recent(k, self.rear_counter)
-// 	}
-//
-// 	fn check_invariants(&self) {
-// 		// TODO: likely overkill
-// 		self.v.check_invariants()
-// 	}
-// }
-//
-// impl NavigableByteMap for PersistentDiff {
-// 	type Cursor = BTreeCursor;
-//
-// 	fn cursor(&self, k: &[u8]) -> Self::Cursor {
-// 		panic!("not yet implemented")
-// 	}
-// }
-//
-// // PersistentBTrees can serve as snapshots, as long as we don't try to write to a persistent node.
-// pub struct PersistentSnap {
-// 	v: PersistentBTree,
-// }
-//
-// impl ByteMap for PersistentSnap {
-// 	type GetDatum = <PersistentBTree as ByteMap>::GetDatum;
-// 	type Get = <PersistentBTree as ByteMap>::Get;
-//
-// 	fn get<K: Key + ?Sized>(&mut self, k: &K) -> Option<Self::Get> {
-// 		self.v.get(k)
-// 	}
-//
-// 	fn check_invariants(&self) {
-// 		self.v.check_invariants()
-// 	}
-// }
-//
-// impl ByteMapSnapshot for PersistentSnap {
-// 	type Diff = PersistentDiff;
-//
-// 	fn txid(&self) -> Counter {
-// 		self.v.txid()
-// 	}
-//
-// 	fn diff(&self, past: Counter) -> Self::Diff {
-// 		// TODO assertions about the counter
-// 		PersistentDiff {
-// 			v: self.v.persistent_clone(),
-// 			rear_counter: past,
-// 		}
-// 	}
-// }
-//
-// impl NavigableByteMap for PersistentSnap {
-// 	type Cursor = BTreeCursor;
-//
-// 	fn cursor(&self, k: &[u8]) -> Self::Cursor {
-// 		self.v.cursor(k)
-// 	}
-// }
-//
-// impl FunctionalByteMap for PersistentBTree {
-// 	type Snap = PersistentSnap;
-//
-// 	fn snap(&mut self) -> Self::Snap {
-// 		let clone = self.shallow_clone();
-// 		// We might bump the leading txid even if the transaction does nothing. This is by design.
-// 		self.leading_txid = self.leading_txid.inc();
-//
-// 		PersistentSnap {
-// 			v: clone,
-// 		}
-// 	}
-// }
+    fn upsert<K: AsRef<[u8]>, D: AsRef<[u8]>>(&mut self, k: K, delta: D) -> Result<(), TreeError> {
+		self.check_writable()?;
+		let full_key = self.full_key_checked(k.as_ref())?.into_owned();
+		self.upsert_raw(&full_key, delta.as_ref())
+    }
+
+	/// Returns a writable suffix view. Writes through the view land in this tree, and writes to keys
+	/// outside the view return an error. Views of read-only views are read-only.
+    fn suffix_mut<'b, K: AsRef<[u8]>>(&'b mut self, prefix: K) -> BTreeView<&'b mut PersistentBTree> {
+		BTreeView::new(self.suffix_view(prefix.as_ref(), true))
+    }
+
+	/// Returns a writable subrange view. Writes through the view land in this tree, and writes to keys
+	/// outside the view return an error. Views of read-only views are read-only.
+    fn subrange_mut<'b, K1: AsRef<[u8]>, K2: AsRef<[u8]>>(&'b mut self, start: K1, end: K2) -> BTreeView<&'b mut PersistentBTree> {
+		BTreeView::new(self.subrange_view(start.as_ref(), end.as_ref(), true))
+    }
+}
+
+/// Clones of snapshots are snapshots. Clones of transient trees are transient copies.
+impl Clone for PersistentBTree {
+	fn clone(&self) -> PersistentBTree {
+		if self.is_frozen() {
+			// Snapshots have no transient nodes, so this is as cheap as freezing.
+			Self::frozen(self.persistent_clone(), self.trailing_txid)
+		} else {
+			self.thaw()
+		}
+	}
+}
+
+impl<'a, 'p> PersistentTreeSpec<'a, 'p> for PersistentBTreeSpec {
+    type TransientSpec = PersistentBTreeSpec;
+    type TransientImpl = PersistentBTree;
+}
+
+impl<'a> PersistentTree<'a, PersistentBTreeSpec> for PersistentBTree {
+    fn transient<'b>(&'b self) -> PersistentBTree {
+		self.thaw()
+    }
+}
+
+impl<'a, 'p> TransientTreeSpec<'a, 'p> for PersistentBTreeSpec {
+    type PersistentSpec = PersistentBTreeSpec;
+    type PersistentImpl = PersistentBTree;
+}
+
+impl<'a, 'p> TransientTree<'a, 'p, PersistentBTreeSpec> for PersistentBTree {
+    fn persistent(&mut self) -> PersistentBTree {
+		self.freeze()
+    }
+}
+
+impl<'a> HistoryTreeSpec<'a> for PersistentBTreeSpec {
+    type DiffSpec = PersistentBTreeSpec;
+    type DiffImpl = PersistentBTree;
+}
+
+impl<'a> HistoryTree<'a, PersistentBTreeSpec> for PersistentBTree {
+	/// Returns the txid of the newest persistent material in this tree. A diff against this counter
+	/// contains only keys written after this tree was last frozen.
+    fn counter(&self) -> Counter {
+		let root = self.root.borrow();
+
+		if root.frozen {
+			root.leading_txid
+		} else {
+			root.leading_txid.dec()
+		}
+    }
+
+	/// Returns an immutable view of this tree containing only keys written after the given counter.
+	/// Keys deleted after the given counter are visible as tombstones; see BTreeCursor::is_removed.
+	/// Diffing a transient tree copies its unfrozen writes, so it neither snapshots the tree nor bumps its txid.
+    fn diff(&self, c: Counter) -> PersistentBTree {
+		let trailing_txid = match self.trailing_txid {
+			Some(txid) if c.circle_lt(txid) => txid,
+			_ => c,
+		};
+
+		if !self.is_frozen() {
+			self.root.borrow_mut().keep_tombstones = true;
+		}
+		Self::frozen(self.persistent_clone(), Some(trailing_txid))
+    }
+}
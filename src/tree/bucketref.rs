@@ -1,23 +1,53 @@
 use std::borrow::Borrow;
+use std::cell::OnceCell;
 use std::mem;
+use std::rc::Rc;
 
 use data::{RcBytes, WeakBytes, Datum};
 
 use counter::Counter;
 
+use tree::chunks::*;
+
+/// A bucket's value. Large values live off-tree, in a chunk store.
+#[derive(Clone)]
+enum Value {
+    Inline(RcBytes),
+    Chunked(Rc<Chunk>),
+}
+
+/// A weak reference to a bucket's value. Chunk handles are cheap, so chunked values pin their chunk,
+/// which outlives the bucket if the bucket is overwritten or deleted.
+#[derive(Clone)]
+enum WeakValue {
+    Inline(WeakBytes),
+    Chunked(Rc<Chunk>),
+}
+
 #[derive(Clone)]
 // TODO RcBytes -> RcBytes
 /// Public so we do not have a private type in a public interface.
 pub struct Bucket {
     k: RcBytes,
-    v: RcBytes,
+    v: Value,
+    /// If true, this bucket marks a deleted key, and its value is empty.
+    tombstone: bool,
+    /// If true, this bucket's value is a delta for the tree's merge operator. Deltas are only ever buffered.
+    delta: bool,
 }
 
 impl Bucket {
-    fn downgrade(&self) -> WeakBucket {
+    fn downgrade(&self, prefix: &RcBytes) -> WeakBucket {
         WeakBucket {
+            prefix: prefix.downgrade(),
             k: self.k.downgrade(),
-            v: self.v.downgrade(),
+            full_key: OnceCell::new(),
+            v: match self.v {
+                Value::Inline(ref v) => WeakValue::Inline(v.downgrade()),
+                Value::Chunked(ref c) => WeakValue::Chunked(c.clone()),
+            },
+            tombstone: self.tombstone,
+            delta: self.delta,
         }
     }
 }
@@ -32,7 +62,9 @@ impl BucketRef {
     pub fn new_transient<V: Datum>(k: &[u8], v: &V) -> BucketRef {
         BucketRef::Transient(Bucket {
             k: RcBytes::new(k),
-            v: RcBytes::from_value(v),
+            v: Value::Inline(RcBytes::from_value(v)),
+            tombstone: false,
+            delta: false,
         })
     }
 
@@ -40,7 +72,49 @@ impl BucketRef {
     pub fn transient_from_bytes(k: &[u8], v: &[u8]) -> BucketRef {
         BucketRef::Transient(Bucket {
             k: RcBytes::new(k),
-            v: RcBytes::new(v),
+            v: Value::Inline(RcBytes::new(v)),
+            tombstone: false,
+            delta: false,
+        })
+    }
+
+    /// Creates a bucket holding the given key and value, without copying them.
+    pub fn transient_from_rcbytes(k: RcBytes, v: RcBytes) -> BucketRef {
+        BucketRef::Transient(Bucket {
+            k: k,
+            v: Value::Inline(v),
+            tombstone: false,
+            delta: false,
+        })
+    }
+
+    /// Creates a bucket whose value lives in the given chunk. The key is not copied.
+    pub fn transient_chunked(k: RcBytes, v: Rc<Chunk>) -> BucketRef {
+        BucketRef::Transient(Bucket {
+            k: k,
+            v: Value::Chunked(v),
+            tombstone: false,
+            delta: false,
+        })
+    }
+
+    /// Creates a tombstone for the given key. Tombstones let diffs see deleted keys.
+    pub fn transient_tombstone(k: &[u8]) -> BucketRef {
+        BucketRef::Transient(Bucket {
+            k: RcBytes::new(k),
+            v: Value::Inline(RcBytes::new(&[][..])),
+            tombstone: true,
+            delta: false,
+        })
+    }
+
+    /// Creates a delta to be merged into the value of the given key.
+    pub fn transient_delta(k: &[u8], delta: &[u8]) -> BucketRef {
+        BucketRef::Transient(Bucket {
+            k: RcBytes::new(k),
+            v: Value::Inline(RcBytes::new(delta)),
+            tombstone: false,
+            delta: true,
         })
     }
 
@@ -51,10 +125,72 @@ impl BucketRef {
         }
     }
 
-    pub fn value(&self) -> &RcBytes {
+    fn bucket(&self) -> &Bucket {
+        match *self {
+            BucketRef::Transient(ref b) => b,
+            BucketRef::Persistent(ref b, _) => b,
+        }
+    }
+
+    /// Loads the value of this bucket.
+    pub fn value(&self) -> RcBytes {
+        match self.bucket().v {
+            Value::Inline(ref v) => v.clone(),
+            Value::Chunked(ref c) => c.load(),
+        }
+    }
+
+    /// Gets the chunk this bucket's value lives in, if it was stored off-tree.
+    pub fn chunk(&self) -> Option<&Rc<Chunk>> {
+        match self.bucket().v {
+            Value::Inline(_) => None,
+            Value::Chunked(ref c) => Some(c),
+        }
+    }
+
+    /// The number of bytes this bucket's value takes in its node. Chunked values count only their handle.
+    pub fn value_size(&self) -> usize {
+        match self.bucket().v {
+            Value::Inline(ref v) => v.len(),
+            Value::Chunked(_) => CHUNK_HANDLE_SIZE,
+        }
+    }
+
+    /// The number of key and value bytes this bucket takes in its node.
+    pub fn byte_size(&self) -> usize {
+        self.key().len() + self.value_size()
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        match *self {
+            BucketRef::Transient(ref b) => b.tombstone,
+            BucketRef::Persistent(ref b, _) => b.tombstone,
+        }
+    }
+
+    pub fn is_delta(&self) -> bool {
+        self.bucket().delta
+    }
+
+    /// Mutably borrows the value of this BucketRef. Transient values still shared, say by a value handed out
+    /// earlier or a snapshot, are copied first; weak references to the old value no longer see it. Persistent values may be
+    /// shared with snapshots, so this panics if this BucketRef is persistent.
+    pub fn value_mut(&mut self) -> &mut [u8] {
         match *self {
-            BucketRef::Transient(ref b) => &b.v,
-            BucketRef::Persistent(ref b, _) => &b.v,
+            BucketRef::Transient(Bucket { v: Value::Inline(ref mut v), .. }) => {
+                if v.get_mut().is_none() {
+                    *v = RcBytes::new(&v[..]);
+                }
+                v.get_mut().unwrap()
+            }
+            BucketRef::Transient(Bucket { v: Value::Chunked(ref mut c), .. }) => {
+                // Snapshots and weak bucket refs may share the chunk, so it is copied on write.
+                if Rc::get_mut(c).is_none() {
+                    *c = c.copy();
+                }
+                Rc::get_mut(c).unwrap().value_mut()
+            }
+            BucketRef::Persistent(_, _) => panic!("Can't mutate a persistent Bucket"),
         }
     }
 
@@ -65,11 +201,11 @@ impl BucketRef {
         }
     }
 
-    /// Makes this BucketRef immutable, if it wasn't already.
+    /// Makes this BucketRef immutable, if it wasn't already. Persistent buckets keep their txid.
     pub fn immute(&mut self, txid: Counter) {
         let mut newself = match *self {
             BucketRef::Transient(ref b) => BucketRef::Persistent(b.clone(), txid),
-            BucketRef::Persistent(ref b, old_txid) => BucketRef::Persistent(b.clone(), old_txid),
+            BucketRef::Persistent(_, _) => return,
         };
 
         // Now newself is uninit
@@ -83,10 +219,39 @@ impl BucketRef {
         }
     }
 
-    pub fn downgrade(&self) -> WeakBucketRef {
+    /// Returns a persistent copy of this BucketRef, with the given txid if it is transient.
+    pub fn immuted_copy(&self, txid: Counter) -> BucketRef {
+        match *self {
+            BucketRef::Transient(ref b) => BucketRef::Persistent(b.clone(), txid),
+            BucketRef::Persistent(ref b, txid) => BucketRef::Persistent(b.clone(), txid),
+        }
+    }
+
+    /// Returns a copy of this BucketRef with the given key, which is moved rather than copied. Persistent buckets
+    /// keep their txid. Used when a node changes how it compresses its keys.
+    pub fn with_key(self, k: Vec<u8>) -> BucketRef {
+        let k = RcBytes::from_box(k.into_boxed_slice());
+        match self {
+            BucketRef::Transient(b) => BucketRef::Transient(Bucket { k: k, ..b }),
+            BucketRef::Persistent(b, txid) => BucketRef::Persistent(Bucket { k: k, ..b }, txid),
+        }
+    }
+
+    /// Returns this BucketRef with the first `len` bytes of its key stripped. The rest of the key shares its
+    /// bytes rather than copying them.
+    pub fn strip_key(self, len: usize) -> BucketRef {
+        match self {
+            BucketRef::Transient(b) => BucketRef::Transient(Bucket { k: b.k.slice_from(len), ..b }),
+            BucketRef::Persistent(b, txid) => BucketRef::Persistent(Bucket { k: b.k.slice_from(len), ..b }, txid),
+        }
+    }
+
+    /// Downgrades this BucketRef. Its key is stored without the given prefix, which the WeakBucketRef
+    /// puts back on demand.
+    pub fn downgrade(&self, prefix: &RcBytes) -> WeakBucketRef {
         match *self {
-            BucketRef::Transient(ref b) => WeakBucketRef::Transient(b.downgrade()),
-            BucketRef::Persistent(ref b, txid) => WeakBucketRef::Persistent(b.downgrade(), txid),
+            BucketRef::Transient(ref b) => WeakBucketRef::Transient(b.downgrade(prefix)),
+            BucketRef::Persistent(ref b, txid) => WeakBucketRef::Persistent(b.downgrade(prefix), txid),
         }
     }
 }
@@ -94,8 +259,13 @@ impl BucketRef {
 #[derive(Clone)]
 /// Public for API purposes.
 pub struct WeakBucket {
+    prefix: WeakBytes,
     k: WeakBytes,
-    v: WeakBytes,
+    /// The full key, once it has been rebuilt.
+    full_key: OnceCell<RcBytes>,
+    v: WeakValue,
+    tombstone: bool,
+    delta: bool,
 }
 
 pub enum WeakBucketRef {
@@ -103,25 +273,76 @@ pub enum WeakBucketRef {
     Persistent(WeakBucket, Counter),
 }
 
+impl WeakBucket {
+    /// Rebuilds the full key. Only copies if the key was stored with a prefix, and then only once.
+    fn key(&self) -> RcBytes {
+        self.full_key.get_or_init(|| {
+            let prefix = self.prefix.upgrade();
+            if prefix.is_empty() {
+                self.k.upgrade()
+            } else {
+                let mut r = prefix.to_vec();
+                r.extend_from_slice(&self.k.upgrade());
+                RcBytes::from_box(r.into_boxed_slice())
+            }
+        }).clone()
+    }
+}
+
 impl WeakBucketRef {
     pub fn key(&self) -> RcBytes {
         match *self {
-            WeakBucketRef::Transient(ref b) => b.k.upgrade(),
-            WeakBucketRef::Persistent(ref b, _) => b.k.upgrade(),
+            WeakBucketRef::Transient(ref b) => b.key(),
+            WeakBucketRef::Persistent(ref b, _) => b.key(),
+        }
+    }
+
+    fn bucket(&self) -> &WeakBucket {
+        match *self {
+            WeakBucketRef::Transient(ref b) => b,
+            WeakBucketRef::Persistent(ref b, _) => b,
         }
     }
 
+    /// Loads the value of this bucket.
     pub fn value(&self) -> RcBytes {
+        match self.bucket().v {
+            WeakValue::Inline(ref v) => v.upgrade(),
+            WeakValue::Chunked(ref c) => c.load(),
+        }
+    }
+
+    /// The number of bytes this bucket's value takes in its node. Chunked values count only their handle.
+    pub fn value_size(&self) -> usize {
+        match self.bucket().v {
+            WeakValue::Inline(ref v) => v.upgrade().len(),
+            WeakValue::Chunked(_) => CHUNK_HANDLE_SIZE,
+        }
+    }
+
+    pub fn is_tombstone(&self) -> bool {
         match *self {
-            WeakBucketRef::Transient(ref b) => b.v.upgrade(),
-            WeakBucketRef::Persistent(ref b, _) => b.v.upgrade(),
+            WeakBucketRef::Transient(ref b) => b.tombstone,
+            WeakBucketRef::Persistent(ref b, _) => b.tombstone,
         }
     }
 
+    pub fn is_delta(&self) -> bool {
+        self.bucket().delta
+    }
+
     pub fn txid(&self) -> Counter {
         match *self {
             WeakBucketRef::Transient(_) => panic!("Can't call txid on a transient Bucket"),
             WeakBucketRef::Persistent(_, txid) => txid,
         }
     }
+
+    /// Returns true if this bucket was written after the given txid. Transient buckets are always newer.
+    pub fn is_newer_than(&self, txid: Counter) -> bool {
+        match *self {
+            WeakBucketRef::Transient(_) => true,
+            WeakBucketRef::Persistent(_, bucket_txid) => txid.circle_lt(bucket_txid),
+        }
+    }
 }
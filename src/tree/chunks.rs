@@ -0,0 +1,134 @@
+//! Off-tree storage for large values, as in PostgreSQL TOAST.
+//!
+//! Buckets with large values hold a Chunk handle instead of the value. Copying a node copies only the handle,
+//! so snapshots and their transients share chunks, and values are loaded only when read.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use data::RcBytes;
+
+use storage::BlockAddress;
+
+/// The size we count for a chunk handle when measuring nodes.
+pub const CHUNK_HANDLE_SIZE: usize = 8;
+
+/// Loads the value of a saved chunk from the given address.
+pub type ChunkLoader = Box<dyn FnOnce(BlockAddress) -> RcBytes>;
+
+/// A store of large values, shared by a tree and all its snapshots.
+pub struct ChunkStore {
+    chunks: RefCell<HashMap<u64, RcBytes>>,
+    next_id: Cell<u64>,
+}
+
+impl ChunkStore {
+    pub fn new() -> Rc<ChunkStore> {
+        Rc::new(ChunkStore {
+            chunks: RefCell::new(HashMap::new()),
+            next_id: Cell::new(0),
+        })
+    }
+
+    /// Stores the given value, returning a handle to it. The value is freed when the last handle is dropped.
+    pub fn put(store: &Rc<ChunkStore>, v: RcBytes) -> Rc<Chunk> {
+        let id = store.next_id.get();
+        store.next_id.set(id + 1);
+        store.chunks.borrow_mut().insert(id, v);
+
+        Rc::new(Chunk {
+            id: id,
+            store: Rc::downgrade(store),
+            addr: Cell::new(None),
+            loader: RefCell::new(None),
+        })
+    }
+
+    /// Returns a handle to the value saved at the given address, which the given loader loads when it is first read.
+    pub fn put_saved(store: &Rc<ChunkStore>, addr: BlockAddress, loader: ChunkLoader) -> Rc<Chunk> {
+        let id = store.next_id.get();
+        store.next_id.set(id + 1);
+
+        Rc::new(Chunk {
+            id: id,
+            store: Rc::downgrade(store),
+            addr: Cell::new(Some(addr)),
+            loader: RefCell::new(Some(loader)),
+        })
+    }
+
+    /// The number of live chunks whose values are loaded.
+    pub fn len(&self) -> usize {
+        self.chunks.borrow().len()
+    }
+
+    /// The number of bytes in live chunks whose values are loaded.
+    pub fn byte_size(&self) -> usize {
+        self.chunks.borrow().values().map(|v| v.len()).sum()
+    }
+}
+
+/// A handle to a value in a ChunkStore.
+pub struct Chunk {
+    id: u64,
+    store: Weak<ChunkStore>,
+    /// Where this chunk's value was saved, if it was saved to a BlockStore.
+    addr: Cell<Option<BlockAddress>>,
+    /// Loads this chunk's value, if it was saved and hasn't been read yet.
+    loader: RefCell<Option<ChunkLoader>>,
+}
+
+impl Chunk {
+    fn store(&self) -> Rc<ChunkStore> {
+        self.store.upgrade().expect("chunk outlived its store")
+    }
+
+    /// Loads this chunk's value.
+    pub fn load(&self) -> RcBytes {
+        let store = self.store();
+        let loader = self.loader.borrow_mut().take();
+        if let Some(loader) = loader {
+            let v = loader(self.addr.get().unwrap());
+            store.chunks.borrow_mut().insert(self.id, v);
+        }
+
+        let chunks = store.chunks.borrow();
+        chunks[&self.id].clone()
+    }
+
+    pub fn address(&self) -> Option<BlockAddress> {
+        self.addr.get()
+    }
+
+    pub fn set_address(&self, addr: BlockAddress) {
+        self.addr.set(Some(addr));
+    }
+
+    /// Stores a copy of this chunk's value in a new chunk.
+    pub fn copy(&self) -> Rc<Chunk> {
+        ChunkStore::put(&self.store(), RcBytes::new(&self.load()[..]))
+    }
+
+    /// Mutably borrows this chunk's value, copying it first if it is still shared, say by a value handed out earlier.
+    pub fn value_mut(&mut self) -> &mut [u8] {
+        self.load();
+        let store = self.store();
+        let mut chunks = store.chunks.borrow_mut();
+        let v = chunks.get_mut(&self.id).unwrap();
+        if v.get_mut().is_none() {
+            *v = RcBytes::new(&v[..]);
+        }
+        let p: *mut [u8] = v.get_mut().unwrap();
+        // The value lives as long as this handle, not the borrow of the chunk map, and only this handle reaches it.
+        unsafe { &mut *p }
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        if let Some(store) = self.store.upgrade() {
+            store.chunks.borrow_mut().remove(&self.id);
+        }
+    }
+}
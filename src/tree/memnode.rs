@@ -2,20 +2,103 @@
 //!
 //! An in-memory, modifiable node.
 
+use std::cmp::Ordering;
 use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::ptr;
+use std::rc::{Rc, Weak};
 
 use counter::Counter;
 
+use data::RcBytes;
+
+use traits::MergeOperator;
+
+use tree::allocator::NodeArena;
 use tree::bucketref::*;
 use tree::noderef::*;
 use tree::util::*;
 
 /// TODO deftype for idx's
 
-/// The max capacity of a MemNode.
-const NODE_CAPACITY: u16 = 16;
+/// The default max capacity of a MemNode, in children.
+pub const DEFAULT_NODE_CAPACITY: u16 = 16;
+
+/// The default max number of writes buffered in each branch node. Buffering is opt-in.
+pub const DEFAULT_BUFFER_CAPACITY: u16 = 0;
+
+/// With a byte limit, non-head nodes keep at least this many buckets, or (capacity - 1) / 2 if that is fewer.
+const MIN_BUCKETS: u16 = 2;
+
+/// The size limits of a MemNode, and how it merges deltas. Every node in a tree has the same limits.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct NodeLimits {
+	/// The max number of children.
+	pub capacity: u16,
+	/// The max number of key and value bytes, or None if unlimited.
+	pub max_bytes: Option<usize>,
+	/// The max number of writes buffered in a branch node before they are flushed to a child.
+	/// If 0, writes go straight to the leaves.
+	pub buffer_capacity: u16,
+	/// Merges buffered deltas into older writes and buckets, if the tree takes deltas.
+	pub merge_operator: Option<MergeOperator>,
+}
+
+impl NodeLimits {
+	pub fn new(capacity: u16, max_bytes: Option<usize>) -> NodeLimits {
+		NodeLimits {
+			capacity: capacity,
+			max_bytes: max_bytes,
+			buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+			merge_operator: None,
+		}
+	}
+
+	/// Returns true if nodes may have these limits. Capacities must be even, so that splitting a full node
+	/// never leaves a deficient node, and at least 4, so that every non-head node has a bucket.
+	pub fn is_valid(&self) -> bool {
+		self.capacity >= 4 && self.capacity % 2 == 0 && self.max_bytes != Some(0)
+	}
+
+	/// The fewest buckets a non-head node may have.
+	fn min_buckets(&self) -> u16 {
+		match self.max_bytes {
+			Some(_) => MIN_BUCKETS.min((self.capacity - 1) / 2),
+			None => (self.capacity - 1) / 2,
+		}
+	}
+
+	/// Returns true if a node with the given number of buckets and bytes needs no split. Nodes over the byte limit
+	/// are allowed if they are too small to split, viz. if either half of a split could have too few buckets.
+	fn fits(&self, bucket_count: u16, byte_size: usize) -> bool {
+		bucket_count < self.capacity &&
+			(bucket_count < 2 * self.min_buckets() + 1 || self.max_bytes.map_or(true, |max| byte_size <= max))
+	}
+}
+
+/// Merges a write into the older write or bucket with the same key, if any. Deltas are combined with older deltas
+/// and applied to anything else, so the result is a delta only if both are. The result has the newer write's key
+/// and txid. Its key must be the full key if there is no older write.
+pub fn merge_write(op: Option<MergeOperator>, older: Option<&WeakBucketRef>, newer: BucketRef) -> BucketRef {
+	if !newer.is_delta() {
+		return newer
+	}
+
+	let op = op.expect("delta written to a tree without a merge operator");
+	let mut r = match older {
+		Some(older) if older.is_delta() =>
+			BucketRef::transient_delta(newer.key(), &(op.combine)(&older.key(), &older.value(), &newer.value())),
+		Some(older) if !older.is_tombstone() =>
+			BucketRef::transient_from_bytes(newer.key(), &(op.apply)(&older.key(), Some(&older.value()), &newer.value())),
+		Some(older) => BucketRef::transient_from_bytes(newer.key(), &(op.apply)(&older.key(), None, &newer.value())),
+		None => BucketRef::transient_from_bytes(newer.key(), &(op.apply)(newer.key(), None, &newer.value())),
+	};
+
+	if let BucketRef::Persistent(_, txid) = newer {
+		r.immute(txid);
+	}
+	r
+}
 
 /// A simple pointer used internally by MemNode.
 /// This class was historically introduced because of over-strong coupling between
@@ -81,40 +164,66 @@ pub enum InsertResult {
 	Flushed(BucketRef, MemNode),
 }
 
+/// The result of buffering a write in a MemNode. Buffering never changes a node's buckets, so it never splits one.
+pub enum BufferResult {
+	/// The write was buffered. True if the buffer is now over capacity.
+	Buffered(bool),
+	/// The write is to one of this node's buckets. It must be inserted, so any split it causes reaches the parent.
+	Bucket(BucketRef),
+	/// Buffering the write would shorten this node's prefix and put it over its byte limit. It must be buffered
+	/// in the child at the given index instead.
+	Child(u16, BucketRef),
+}
+
 pub struct MemNode {
-	/// Invariant: between (NODE_CAPACITY - 1) / 2 and NODE_CAPACITY - 1 unless we are the top node,
-	/// in which case this is between 0 and NODE_CAPACITY - 1.
-	/// When split, this is between 0 and NODE_CAPACITY - 2.
+	limits: NodeLimits,
+	/// A prefix shared by every key in this node. Buckets store their keys without it.
+	/// Usually the common prefix of this node's bounds, viz. the parent buckets on either side.
+	prefix: RcBytes,
+	/// Invariant: between (capacity - 1) / 2 and capacity - 1 unless we are the top node,
+	/// in which case this is between 0 and capacity - 1. With a byte limit, non-head nodes may have
+	/// as few as NodeLimits::min_buckets, since nodes split around large buckets.
+	/// Before a split, this may be capacity.
 	bucket_count: u16,
 	/// Buckets: key pairs in this node.
 	/// Invariant: the buckets in the interval [0, bucket_count) are populated,
-	/// all others are not.
+	/// all others are not. There is one spare bucket, so we can insert before splitting.
 	// TODO: We don't need to use nullable pointers; we can use uninitialized data instead.
-    buckets: [MemPtr<BucketRef>; NODE_CAPACITY as usize - 1],
+    buckets: Box<[MemPtr<BucketRef>]>,
 	/// Invariant: If this is a leaf, all children are empty. Otherwise, child_count == bucket_count = 1.
-    children: [MemPtr<FatNodeRef>; NODE_CAPACITY as usize],
+    children: Box<[MemPtr<FatNodeRef>]>,
+	/// Writes to keys under this node that have not been flushed to its children, as in a hitchhiker tree.
+	/// Deletes are tombstones. Sorted, with at most one write per key, and compressed like the buckets.
+	/// Always empty in leaves. Writes here shadow any bucket in this node or below it.
+	buffer: Vec<BucketRef>,
+	/// False if neither this node nor any of its descendants has buffered writes.
+	dirty: bool,
+	/// The arena of the transaction this node is transient in, which its children are forked into.
+	/// Dead for persistent nodes.
+	arena: Weak<NodeArena>,
 }
 
 // TODO: rename MemNode -> MemNode
 impl MemNode {
 	/* Constructors */
-	pub fn empty() -> MemNode {
-		unsafe {
-			MemNode {
-				// height: 0,
-				bucket_count: 0,
-				// using mem::uninitialized might be faster
-        	    buckets: make_array!(|_| MemPtr::empty(), (NODE_CAPACITY - 1) as usize),
-       	    	children: make_array!(|_| MemPtr::empty(), NODE_CAPACITY as usize),
-       	    	// children: make_array!(|_| None, NODE_CAPACITY as usize),
-        	    // TODO
-       	    	// children: make_array!(|_| TC::RefCellT<NodePtr::empty()>, NODE_CAPACITY as usize),
-			}
+	pub fn empty(limits: NodeLimits) -> MemNode {
+		debug_assert!(limits.is_valid(), "invalid node limits {:?}", limits);
+
+		MemNode {
+			limits: limits,
+			prefix: RcBytes::new(&[][..]),
+			bucket_count: 0,
+			// using mem::uninitialized might be faster
+			buckets: (0..limits.capacity).map(|_| MemPtr::empty()).collect::<Vec<_>>().into_boxed_slice(),
+			children: (0..(limits.capacity + 1)).map(|_| MemPtr::empty()).collect::<Vec<_>>().into_boxed_slice(),
+			buffer: Vec::new(),
+			dirty: false,
+			arena: Weak::new(),
 		}
 	}
 
-	pub fn new_from_one(b: BucketRef) -> MemNode {
-		let mut r = Self::empty();
+	pub fn new_from_one(limits: NodeLimits, b: BucketRef) -> MemNode {
+		let mut r = Self::empty(limits);
 
 		r.buckets[0] = MemPtr::wrap(b);
 		r.bucket_count = 1;
@@ -123,32 +232,64 @@ impl MemNode {
 	}
 
 	pub fn new_from_two(n1: FatNodeRef, b1: BucketRef, n2: FatNodeRef) -> MemNode {
-		let mut r = Self::empty();
+		let mut r = Self::empty(n1.apply(MemNode::limits));
 
 		r.buckets[0] = MemPtr::wrap(b1);
 		r.children[0] = MemPtr::wrap(n1);
 		r.children[1] = MemPtr::wrap(n2);
 		r.bucket_count = 1;
+		r.dirty = r.children[0].apply(MemNode::is_dirty) || r.children[1].apply(MemNode::is_dirty);
+		r.compress_child(0);
+		r.compress_child(1);
 
 		r
 	}
 
-	/// Immutes this MemNode, recursively immuting its children.
+	/// Rebuilds a node from its stored parts. Keys of the given buckets and buffered writes must be stored
+	/// without the given prefix.
+	pub fn from_parts(limits: NodeLimits, prefix: RcBytes, buckets: Vec<BucketRef>, children: Vec<FatNodeRef>,
+		buffer: Vec<BucketRef>, dirty: bool) -> MemNode {
+		let mut r = Self::empty(limits);
+
+		r.prefix = prefix;
+		r.bucket_count = buckets.len() as u16;
+		for (i, b) in buckets.into_iter().enumerate() {
+			r.buckets[i] = MemPtr::wrap(b);
+		}
+		for (i, n) in children.into_iter().enumerate() {
+			r.children[i] = MemPtr::wrap(n);
+		}
+		r.buffer = buffer;
+		r.dirty = dirty;
+
+		r
+	}
+
+	/// Immutes this MemNode, recursively immuting its transient children.
 	pub fn immute(&mut self, txid: Counter) {
 		for i in 0..self.child_count() as usize {
-			self.children[i].immute(txid);
+			if self.children[i].is_transient() {
+				self.children[i].immute(txid);
+			}
 		}
 
 		for i in 0..self.bucket_count() as usize {
 			self.buckets[i].immute(txid);
 		}
+
+		for b in self.buffer.iter_mut() {
+			b.immute(txid);
+		}
+
+		self.arena = Weak::new();
 	}
 
 	/// Creates a copy of this MemNode. For this to make sense, the current node must be immutable.
 	pub fn fork(&self) -> MemNode {
 		// Right now, this is a poor man's Clone.
-		let mut r = Self::empty();
+		let mut r = Self::empty(self.limits);
 
+		r.prefix = self.prefix.clone();
 		r.bucket_count = self.bucket_count;
 
 		for i in 0..self.bucket_count() as usize {
@@ -159,10 +300,57 @@ impl MemNode {
 			r.children[i] = MemPtr::wrap(self.children[i].shallow_clone());
 		}
 
+		r.buffer = self.buffer.iter().map(BucketRef::shallow_clone).collect();
+		r.dirty = self.dirty;
+
+		r
+	}
+
+	/// Creates a persistent copy of this MemNode with the given txid, without immuting it. Transient children
+	/// and buckets are copied; persistent ones are shared.
+	pub fn immuted_copy(&self, txid: Counter) -> MemNode {
+		let mut r = Self::empty(self.limits);
+
+		r.prefix = self.prefix.clone();
+		r.bucket_count = self.bucket_count;
+
+		for i in 0..self.bucket_count() as usize {
+			r.buckets[i] = MemPtr::wrap(self.buckets[i].immuted_copy(txid));
+		}
+
+		for i in 0..self.child_count() as usize {
+			r.children[i] = MemPtr::wrap(self.children[i].immuted_copy(txid));
+		}
+
+		r.buffer = self.buffer.iter().map(|b| b.immuted_copy(txid)).collect();
+		r.dirty = self.dirty;
+
 		r
 	}
 
+	pub fn set_arena(&mut self, arena: Weak<NodeArena>) {
+		self.arena = arena;
+	}
+
 	/* Fast accessors */
+	pub fn limits(&self) -> NodeLimits {
+		self.limits
+	}
+
+	/// The number of key and value bytes in this node's buckets, not counting children. Keys count
+	/// in full, so this does not depend on how keys are compressed.
+	pub fn byte_size(&self) -> usize {
+		(0..self.bucket_count).map(|i| self.bucket_size(i)).sum()
+	}
+
+	fn bucket_size(&self, idx: u16) -> usize {
+		self.prefix.len() + self.buckets[idx as usize].byte_size()
+	}
+
+	pub fn prefix(&self) -> &[u8] {
+		&self.prefix
+	}
+
 	pub fn bucket_count(&self) -> u16 {
 		self.bucket_count
 	}
@@ -179,14 +367,123 @@ impl MemNode {
 		&self.buckets[idx as usize]
 	}
 
+	/// Gets the key at the given index, without this node's prefix.
 	fn key(&self, idx: u16) -> &[u8] {
 		self.bucket_ptr(idx).key()
 	}
 
+	/// Gets the bucket at the given index. Its key is stored without this node's prefix.
+	pub fn stored_bucket(&self, idx: u16) -> &BucketRef {
+		self.bucket_ptr(idx)
+	}
+
+	/// Gets the full key at the given index.
+	pub fn full_key(&self, idx: u16) -> Vec<u8> {
+		let mut r = self.prefix.to_vec();
+		r.extend_from_slice(self.key(idx));
+		r
+	}
+
 	/// Gets the value associated at a particular index.
 	// TODO: should this be generic on node?
 	pub fn bucket_ref(&self, idx: u16) -> WeakBucketRef {
-		self.bucket_ptr(idx).downgrade()
+		self.bucket_ptr(idx).downgrade(&self.prefix)
+	}
+
+	/* Prefix compression helpers */
+
+	/// Strips this node's prefix from the given bucket's full key.
+	fn compress(&self, b: BucketRef) -> BucketRef {
+		if self.prefix.is_empty() {
+			return b
+		}
+
+		debug_assert!(b.key().starts_with(&self.prefix));
+		b.strip_key(self.prefix.len())
+	}
+
+	/// Puts this node's prefix back on the given bucket's key.
+	fn decompress(&self, b: BucketRef) -> BucketRef {
+		if self.prefix.is_empty() {
+			return b
+		}
+
+		let mut k = Vec::with_capacity(self.prefix.len() + b.key().len());
+		k.extend_from_slice(&self.prefix);
+		k.extend_from_slice(b.key());
+		b.with_key(k)
+	}
+
+	/// Takes the bucket at the given index, leaving that slot empty, and returns it with its full key.
+	fn take_bucket(&mut self, idx: u16) -> BucketRef {
+		let mut bp = MemPtr::empty();
+		mem::swap(&mut bp, &mut self.buckets[idx as usize]);
+		self.decompress(bp.unwrap())
+	}
+
+	/// Puts the given bucket, with its full key, into the given empty slot.
+	fn put_bucket(&mut self, idx: u16, b: BucketRef) {
+		self.fit_prefix(b.key());
+		self.buckets[idx as usize] = MemPtr::wrap(self.compress(b));
+	}
+
+	/// Recompresses every bucket with the given prefix, which must be shared by every key in this node.
+	fn set_prefix(&mut self, prefix: &[u8]) {
+		if prefix == &self.prefix[..] {
+			return
+		}
+
+		let old_prefix = mem::replace(&mut self.prefix, RcBytes::new(prefix));
+		// Either prefix extends the other, so each key is rebuilt with one copy.
+		let rekey = |b: BucketRef| {
+			let k = if prefix.len() <= old_prefix.len() {
+				let mut k = old_prefix[prefix.len()..].to_vec();
+				k.extend_from_slice(b.key());
+				k
+			} else {
+				b.key()[(prefix.len() - old_prefix.len())..].to_vec()
+			};
+			b.with_key(k)
+		};
+
+		for bp in self.buckets.iter_mut().filter(|bp| !bp.is_empty()) {
+			let b = mem::replace(bp, MemPtr::empty()).unwrap();
+			*bp = MemPtr::wrap(rekey(b));
+		}
+
+		let buffer = mem::replace(&mut self.buffer, Vec::new());
+		self.buffer = buffer.into_iter().map(rekey).collect();
+	}
+
+	/// Shortens this node's prefix, if needed, so that it is shared by the given full key.
+	fn fit_prefix(&mut self, k: &[u8]) {
+		if !k.starts_with(&self.prefix) {
+			let len = common_prefix_len(&self.prefix, k);
+			let prefix = self.prefix[..len].to_vec();
+			self.set_prefix(&prefix);
+		}
+	}
+
+	/// Lengthens the prefix of the child at the given index to the common prefix of its bounds, viz. the buckets
+	/// on either side of it. Children on the edges use their own first or last key, buffered or not,
+	/// in place of a missing bound. Modified children are heated.
+	fn compress_child(&mut self, idx: u16) {
+		let lower = if idx > 0 { Some(self.full_key(idx - 1)) } else { None };
+		let upper = if idx < self.bucket_count { Some(self.full_key(idx)) } else { None };
+
+		self.fork_child(idx as usize);
+		self.children[idx as usize].apply_mut(|child| {
+			if child.bucket_count == 0 {
+				return
+			}
+
+			let first = lower.unwrap_or_else(|| child.first_full_key());
+			let last = upper.unwrap_or_else(|| child.last_full_key());
+			let len = common_prefix_len(&first, &last);
+			if len > child.prefix.len() {
+				child.set_prefix(&first[..len]);
+			}
+		});
 	}
 
 	// fn bucket_ptr(&self, idx: u16) -> &BucketPtr {
@@ -201,6 +498,10 @@ impl MemNode {
 		&self.children[idx as usize]
 	}
 
+	pub fn child(&self, idx: u16) -> &FatNodeRef {
+		self.child_ptr(idx)
+	}
+
 	// TODO: return weak instead
 	pub fn child_ref(&self, idx: u16) -> NodeRef {
 		self.child_ptr(idx).deref().noderef().clone()
@@ -211,11 +512,12 @@ impl MemNode {
 		self.children[0].is_empty()
 	}
 
-	// /// A node is deficient iff it can be merged with another deficient node
-	// /// without needing a flush.
-	// fn is_deficient(&self) -> bool {
-	// 	self.bucket_count < (NODE_CAPACITY - 1) / 2
-	// }
+	/// A node is deficient iff it has fewer buckets than a non-head node is allowed. With a byte limit,
+	/// a node is also allowed if it holds at least half that many bytes and at least NodeLimits::min_buckets.
+	pub fn is_deficient(&self) -> bool {
+		self.bucket_count < self.limits.min_buckets() || (self.bucket_count < (self.limits.capacity - 1) / 2 &&
+			self.limits.max_bytes.map_or(true, |max| self.byte_size() < max / 2))
+	}
 
 	/* Insert helpers */
 
@@ -223,6 +525,12 @@ impl MemNode {
 	/// Returns Err(i) where bucket[i] has the first key greater than the given key.
 	/// If buckets are empty (which probably shouldn't happen), returns Err(0).
 	pub fn find(&self, k: &[u8]) -> Result<u16, u16> {
+		// Every key in this node starts with the prefix. If the given key doesn't, it sorts before or after all of them.
+		if !k.starts_with(&self.prefix) {
+			return Err(if k < &self.prefix[..] { 0 } else { self.bucket_count })
+		}
+
+		let k = &k[self.prefix.len()..];
 		// TODO: we can make this faster with a subslice.
 		self.buckets[0..(self.bucket_count() as usize)]
 		.binary_search_by(|bp| bp.key().cmp(k))
@@ -231,13 +539,40 @@ impl MemNode {
 	}
 
 	fn needs_split(&self) -> bool {
-		self.bucket_count == NODE_CAPACITY - 1
+		!self.limits.fits(self.bucket_count, self.byte_size())
+	}
+
+	/// Picks the bucket to split around. If this node is over its byte limit, splits at the byte midpoint,
+	/// so neither half holds more than half the bytes. Otherwise, splits down the middle.
+	fn split_idx(&self) -> usize {
+		let bucket_count = self.bucket_count as usize;
+		let byte_size = self.byte_size();
+
+		if self.limits.max_bytes.map_or(true, |max| byte_size <= max) {
+			// If we have (2n + 1) buckets, this picks bucket n (0-indexed), exactly in the middle.
+			// If we have 2n buckets the nodes will be uneven so we pick n, saving us one bucket copy.
+			return bucket_count / 2
+		}
+
+		// Pick the bucket that straddles the midpoint. A bucket larger than everything else combined
+		// always straddles it, and becomes the parent bucket. Both halves must keep enough buckets.
+		let mut prefix_size = 0;
+		let mut idx = 0;
+		while idx < bucket_count - 1 {
+			prefix_size += self.bucket_size(idx as u16);
+			if prefix_size * 2 > byte_size {
+				break;
+			}
+			idx += 1;
+		}
+		let min = self.limits.min_buckets() as usize;
+		idx.max(min).min(bucket_count - 1 - min)
 	}
 
-	/// Splits this node, making it ready for insertion. Does not modify children.
+	/// Splits this node. Does not modify children.
 	/// Flushing is the only operation allowed to create new nodes. In addition, this particular split
 	/// may not change the level of any node of the tree, so a fully balanced tree remains so.
-	/// Preconditions: None.
+	/// Preconditions: This node needs a split.
 	/// Result: This node is split.
 	/// Returns: If this node was split, the bucket and node pointer that should be inserted into a parent node.
 	/// Note that the bucket should be this node's new parent bucket, and the new node should inherit the old bucket.
@@ -247,10 +582,22 @@ impl MemNode {
 		debug_assert!(self.needs_split());
 
 		let bucket_count = self.bucket_count as usize;
-		// Split down the middle. If we have (2n + 1) buckets, this picks bucket n (0-indexed), exactly in the middle.
-		// If we have 2n buckets the nodes will be uneven so we pick n, saving us one bucket copy.
-		let split_idx = bucket_count / 2;
-		let mut n2 = Self::empty();
+		let split_idx = self.split_idx();
+		let mut n2 = Self::empty(self.limits);
+		// Both halves keep our prefix. Our parent may lengthen them once it knows their new bounds.
+		n2.prefix = self.prefix.clone();
+
+		// Buffered writes follow their keys. A write to the split bucket is merged into it, since it moves above us.
+		let mut split_write = None;
+		let split_key = self.key(split_idx as u16).to_vec();
+		for b in mem::replace(&mut self.buffer, Vec::new()) {
+			match b.key().cmp(&split_key) {
+				Ordering::Less => self.buffer.push(b),
+				Ordering::Equal => split_write = Some(b),
+				Ordering::Greater => n2.buffer.push(b),
+			}
+		}
+		n2.dirty = self.dirty;
 
 		// TODO: use rotate fns
 		for i in (split_idx + 1)..bucket_count {
@@ -267,8 +614,14 @@ impl MemNode {
 
 		// Now our children are divided among two nodes. This leaves an extra bucket, which we return
 		// so the parent node can do something with it.
-		let mut bp = MemPtr::empty();
-		mem::swap(&mut bp, &mut self.buckets[split_idx]);
+		let merged = split_write.map(|b| {
+			let b = self.decompress(b);
+			merge_write(self.limits.merge_operator, Some(&self.bucket_ref(split_idx as u16)), b)
+		});
+		let mut bp = self.take_bucket(split_idx as u16);
+		if let Some(b) = merged {
+			bp = b;
+		}
 
 		// We are done, time to return.
 		// println!("split {} {} -> {} {}", bucket_count, split_idx, self.bucket_count, n2.bucket_count);
@@ -276,11 +629,23 @@ impl MemNode {
 		// self.quickcheck_invariants();
 		// n2.quickcheck_invariants();
 
-		(bp.unwrap(), n2)
+		(bp, n2)
+	}
+
+	/// Splits this node if it has too many buckets or bytes.
+	pub fn split_if_needed(&mut self) -> InsertResult {
+		if self.needs_split() {
+			let (bp, node2) = self.split();
+			InsertResult::Flushed(bp, node2)
+		} else {
+			InsertResult::Ok
+		}
 	}
 
 	// TODO: can we avoid passing the new node on the stack?
 	fn insert_unchecked(&mut self, idx: u16, b: BucketRef, right_child: Option<FatNodeRef>) {
+		self.fit_prefix(b.key());
+		let b = self.compress(b);
 		rotate_in(&mut MemPtr::wrap(b), &mut self.buckets[(idx as usize)..(self.bucket_count as usize + 1)]);
 		match right_child {
 			Some(nptr) => {
@@ -300,21 +665,32 @@ impl MemNode {
 	pub fn insert_at(&mut self, idx: u16, b: BucketRef, right_child: Option<FatNodeRef>) -> InsertResult {
 		debug_assert!(self.is_leaf() == right_child.is_none());
 
-		if self.needs_split() {
-			let (bp, mut node2) = self.split();
-			// Suppose ref1 now has 7 buckets and ref2 now has 7 buckets. This means bucket number 7 is now
-			// a new parent bucket. So if idx is less than 7, we insert into ref1.
-			let idx2 = idx as i32 - self.bucket_count() as i32 - 1;
-			if idx2 >= 0 {
-				node2.insert_unchecked(idx2 as u16, b, right_child);
-			} else {
-				self.insert_unchecked(idx, b, right_child);
+		// We use the spare bucket, then split if we went over.
+		match right_child {
+			Some(n) => self.insert_split_child(idx, b, n),
+			None => {
+				debug_assert!(self.bucket_count < self.limits.capacity);
+				self.insert_unchecked(idx, b, None);
 			}
-			InsertResult::Flushed(bp, node2)
-		} else {
-			self.insert_unchecked(idx, b, right_child);
-			InsertResult::Ok
 		}
+		self.split_if_needed()
+	}
+
+	/// Inserts the bucket and right half of a split of the child at the given index, without splitting this node.
+	pub fn insert_split_child(&mut self, idx: u16, b: BucketRef, right_child: FatNodeRef) {
+		debug_assert!(self.bucket_count < self.limits.capacity);
+
+		self.insert_unchecked(idx, b, Some(right_child));
+		// A write to the new bucket may have been buffered here while the bucket was below us. It is newer, so it wins.
+		if let Ok(i) = self.buffer.binary_search_by(|m| m.key().cmp(self.key(idx))) {
+			let w = self.buffer.remove(i);
+			let w = self.decompress(w);
+			let b = merge_write(self.limits.merge_operator, Some(&self.bucket_ref(idx)), w);
+			self.replace_bucket(idx, b);
+		}
+		// The new bucket split the child at idx. Both halves have narrower bounds now.
+		self.compress_child(idx);
+		self.compress_child(idx + 1);
 	}
 
 	pub fn reassign_child(&mut self, idx: u16, n: HotHandle) {
@@ -323,269 +699,358 @@ impl MemNode {
 
 	/* Get helpers */
 
-	// /* Delete helpers */
-	// fn merge_deficient_nodes(&mut self, right_other: &mut Self, bucket_to_steal: &mut BucketPtr) {
-	// 	debug_assert!(self.is_deficient() && right_other.is_deficient());
-	// 	let bucket_count = self.bucket_count as usize;
-	// 	let other_bucket_count = right_other.bucket_count as usize;
-
-	// 	// We are 'stealing' this bucket from the parent
-	// 	mem::swap(&mut self.buckets[bucket_count], bucket_to_steal);
-	// 	swap(
-	// 		&mut self.buckets[(bucket_count + 1)..(bucket_count + other_bucket_count + 1)],
-	// 		&mut right_other.buckets[..other_bucket_count]);
-	// 	swap(
-	// 		&mut self.children[(bucket_count + 1)..(bucket_count + other_bucket_count + 2)],
-	// 		&mut right_other.children[..(other_bucket_count + 1)]);
-	// 	self.bucket_count += right_other.bucket_count + 1;
-
-	// 	// self.check_invariants();
-	// }
+	/* Delete helpers */
 
-	// fn borrow_left(&mut self, left: &mut NodePtr, left_parent_bucket: &mut BucketPtr) {
-	// 	let mut ln = left.unwrap_hot();
-	// 	let num_to_borrow = (ln.bucket_count - self.bucket_count + 1) / 2;
-	// 	debug_assert!(num_to_borrow >= 1, "invalid bucket sizes: {} {}", ln.bucket_count, self.bucket_count);
-	// 	let bucket_count = self.bucket_count as usize;
-
-	// 	// Make room for the things we're borrowing.
-	// 	rotate_right(&mut self.buckets[..(bucket_count + num_to_borrow as usize)], num_to_borrow as usize);
-	// 	rotate_right(&mut self.children[..(bucket_count + num_to_borrow as usize + 1)], num_to_borrow as usize);
-
-	// 	// The first bororwed bucket becomes the new parent bucket, and the old parent bucket is moved into this node.
-	// 	mem::swap(left_parent_bucket, &mut self.buckets[num_to_borrow as usize - 1]);
-	// 	mem::swap(&mut ln.buckets[(ln.bucket_count - num_to_borrow) as usize], left_parent_bucket);
-
-	// 	// Swap in the buckets and children. Empty buckets will be swapped into ln.
-	// 	// We borrow n children and n-1 buckets (additionally, we did the little shuffle above, borrowing n total).
-	// 	swap(
-	// 		&mut ln.buckets[(ln.bucket_count - num_to_borrow + 1) as usize..ln.bucket_count as usize],
-	// 		&mut self.buckets[..(num_to_borrow as usize - 1)]);
-	// 	swap(
-	// 		&mut ln.children[(ln.bucket_count - num_to_borrow + 1) as usize..(ln.bucket_count as usize + 1)],
-	// 		&mut self.children[..(num_to_borrow as usize)]);
-
-	// 	self.bucket_count += num_to_borrow;
-	// 	ln.bucket_count -= num_to_borrow;
-
-	// 	// self.quickcheck_invariants();
-	// 	// ln.quickcheck_invariants();
-	// 		// self.check_invariants();
-	// 		// ln.check_invariants();
-	// }
+	/// Removes the bucket at the given index from this leaf node, returning it.
+	/// May leave this node deficient.
+	pub fn remove_at(&mut self, idx: u16) -> BucketRef {
+		debug_assert!(self.is_leaf());
+		debug_assert!(idx < self.bucket_count);
 
-	// fn borrow_right(&mut self, right: &mut NodePtr, right_parent_bucket: &mut BucketPtr) {
-	// 	let mut rn = right.unwrap_hot();
-	// 	let num_to_borrow = (rn.bucket_count - self.bucket_count + 1) / 2;
-	// 	debug_assert!(num_to_borrow >= 1, "invalid bucket sizes: {} {}", self.bucket_count, rn.bucket_count);
-	// 	let bucket_count = self.bucket_count as usize;
-
-	// 	// The last bororwed bucket becomes the new parent bucket, and the old parent bucket is moved into this node.
-	// 	mem::swap(right_parent_bucket, &mut self.buckets[bucket_count]);
-	// 	mem::swap(&mut rn.buckets[num_to_borrow as usize - 1], right_parent_bucket);
-
-	// 	// Swap in the buckets and children. Empty buckets will be swapped into rn.
-	// 	// We borrow n children and n-1 buckets (additionally, we did the little shuffle above, borrowing n total).
-	// 	swap(
-	// 		&mut rn.buckets[0..(num_to_borrow - 1) as usize],
-	// 		&mut self.buckets[(bucket_count + 1)..(bucket_count + num_to_borrow as usize)]);
-	// 	swap(
-	// 		&mut rn.children[0..num_to_borrow as usize],
-	// 		&mut self.children[(bucket_count + 1)..(bucket_count + num_to_borrow as usize + 1)]);
-
-	// 	// Adjust the positions in the right node.
-	// 	rotate_left(&mut rn.buckets[0..(rn.bucket_count ) as usize], num_to_borrow as usize);
-	// 	rotate_left(&mut rn.children[0..(rn.bucket_count + 1) as usize], num_to_borrow as usize);
-
-	// 	self.bucket_count += num_to_borrow;
-	// 	rn.bucket_count -= num_to_borrow;
-
-	// 	// self.quickcheck_invariants();
-	// 	// rn.quickcheck_invariants();
-	// 		// self.check_invariants();
-	// 		// rn.check_invariants();
-	// }
+		let mut bp = MemPtr::empty();
+		rotate_out(&mut self.buckets[(idx as usize)..(self.bucket_count as usize)], &mut bp);
+		self.bucket_count -= 1;
 
-	// /// Preconditions: The parent node, if it exists, is not deficient.
-	// /// (It may become deficient as a result of this merge.) As such,
-	// /// at least one neighbor is guaranteed.
-	// /// After this is run, one bucket pointer may be empty. If so, the parent must delete
-	// /// that bucket.
-
-	// // TODO: ptrs and bucketptrs are already options...
-	// fn merge(&mut self, left: Option<&mut NodePtr>, left_parent_bucket: Option<&mut BucketPtr>,
-	// 	right: Option<&mut NodePtr>, right_parent_bucket: Option<&mut BucketPtr>)
-	// -> Option<bool> {
-	// 	debug_assert!(self.is_deficient());
-	// 	debug_assert!(left.is_some() || right.is_some());
-	// 	debug_assert!(left.is_some() == left_parent_bucket.is_some());
-
-	// 	match left {
-	// 		Some(lv) => {
-	// 			if lv.unwrap().is_deficient() {
-	// 				lv.unwrap_hot().merge_deficient_nodes(self, left_parent_bucket.unwrap());
-	// 				Some(false)
-	// 			} else {
-	// 				match right {
-	// 					Some(rv) => {
-	// 						if rv.unwrap().is_deficient() {
-	// 							self.merge_deficient_nodes(rv.unwrap_hot(), right_parent_bucket.unwrap());
-	// 							Some(true)
-	// 						} else if rv.unwrap().bucket_count > lv.unwrap().bucket_count {
-	// 							self.borrow_right(rv, right_parent_bucket.unwrap());
-	// 							None
-	// 						} else {
-	// 							self.borrow_left(lv, left_parent_bucket.unwrap());
-	// 							None
-	// 						}
-	// 					}
-	// 					None => {
-	// 						self.borrow_left(lv, left_parent_bucket.unwrap());
-	// 					    None
-	// 					}
-	// 				}
-	// 			}
-	// 		}
-	// 		None => {
-	// 			if right.as_ref().unwrap().unwrap().is_deficient() {
-	// 				self.merge_deficient_nodes(right.unwrap().unwrap_hot(), right_parent_bucket.unwrap());
-	// 				Some(true)
-	// 			} else {
-	// 				self.borrow_right(right.unwrap(), right_parent_bucket.unwrap());
-	// 				None
-	// 			}
-	// 		}
-	// 	}
-	// }
+		self.decompress(bp.unwrap())
+	}
 
-	// /// Like split_at_mut but with reasonable defaults for out of bounds indices.
-	// fn split_or_empty<T>(t: &mut [T], idx: isize) -> (&mut [T], &mut [T]) {
-	// 	if idx >= 0 {
-	// 		if (idx as usize) < t.len() {
-	// 			t.split_at_mut(idx as usize)
-	// 		} else {
-	// 			(t, [].as_mut())
-	// 		}
-	// 	} else {
-	// 		([].as_mut(), t)
-	// 	}
-	// }
+	/// Replaces the bucket at the given index, returning the old bucket.
+	/// Requirements: b.key sorts into the same position as the replaced bucket.
+	pub fn replace_bucket(&mut self, idx: u16, b: BucketRef) -> BucketRef {
+		debug_assert!(idx < self.bucket_count);
 
-	// /// Returns true if merging happened (and hence buckets[idx] has changed).
-	// // TODO: this code is pure shit.
-	// fn check_deficient_child(&mut self, idx: usize) {
-	// 	debug_assert!(idx <= self.bucket_count as usize);
-
-	// 	if self.get_child(idx).is_deficient() {
-	// 		let delete_result;
-	// 		let bucket_count = self.bucket_count as usize;
-
-	// 		{
-	// 			// Get the borrows we need, in a way that won't make Rust freak out.
-	// 			// (A borrow by index borrows the whole array.)
-
-	// 			// If the idx is n, left child and right child are at n - 1 and n + 1.
-	// 			// Left bucket and right bucket are at n - 1 and n.
-	// 			let (left_bucket_dummy, right_bucket_dummy) = Self::split_or_empty(&mut self.buckets, idx as isize);
-	// 			let (left_child_dummy, mut mid_and_right_child_dummy) = Self::split_or_empty(&mut self.children, idx as isize);
-	// 			let (mid_child_dummy, right_child_dummy) = Self::split_or_empty(&mut mid_and_right_child_dummy, 1);
-
-	// 			// Now we have the borrows we need, we can start bundling our arguments.
-	// 			// TODO: ptrs are already options...
-	// 			let left_sibling;
-	// 			let left_parent_bucket;
-	// 			let right_sibling;
-	// 			let right_parent_bucket;
-	// 			let child = mid_child_dummy[0].unwrap_mut().heat();
-
-	// 			if idx > 0 {
-	// 				left_sibling = Some(&mut left_child_dummy[idx - 1]);
-	// 				left_parent_bucket = Some(&mut left_bucket_dummy[idx - 1]);
-	// 			} else {
-	// 				left_sibling = None;
-	// 				left_parent_bucket = None;
-	// 			}
-
-	// 			if idx < bucket_count {
-	// 				right_sibling = Some(&mut right_child_dummy[0]);
-	// 				right_parent_bucket = Some(&mut right_bucket_dummy[0]);
-	// 			} else {
-	// 				right_sibling = None;
-	// 				right_parent_bucket = None;
-	// 			}
-
-	// 			// A lot of syntax nonsense for one call!
-	// 			delete_result = child.merge(left_sibling, left_parent_bucket, right_sibling, right_parent_bucket);
-	// 			mid_child_dummy[0] = NodePtr::cool(child);
-	// 		}
-
-	// 		match delete_result {
-	// 			// false == left bucket, middle child was deleted
-	// 			Some(false) => {
-	// 				rotate_out(&mut self.buckets[(idx - 1)..bucket_count], &mut BucketPtr::empty());
-	// 				rotate_out(&mut self.children[idx..(bucket_count + 1)], &mut NodePtr::empty());
-	// 				self.bucket_count -= 1;
-	// 			}
-	// 			// true = right bucket, right sibling was deleted
-	// 			Some(true) => {
-	// 				rotate_out(&mut self.buckets[idx..bucket_count], &mut BucketPtr::empty());
-	// 				rotate_out(&mut self.children[(idx + 1)..(bucket_count + 1)], &mut NodePtr::empty());
-	// 				self.bucket_count -= 1;
-	// 			}
-	// 			None => (),
-	// 		}
-
-	// 		// self.quickcheck_invariants();
-	// 		// self.check_invariants();
-	// 	}
-	// }
+		let old = self.take_bucket(idx);
+		self.put_bucket(idx, b);
+		old
+	}
 
-	// /// Postcondition: May leave this node deficient. Will not leave descendant nodes deficient.
-	// fn yank_rightmost_bucket(&mut self) -> BucketPtr {
-	// 	let bucket_count = self.bucket_count as usize;
-
-	// 	if self.is_leaf() {
-	// 		let mut r = BucketPtr::empty();
-	// 		mem::swap(&mut r, &mut self.buckets[bucket_count - 1]);
-	// 		self.bucket_count -= 1;
-	// 		r
-	// 	} else {
-	// 		let r = self.doto_child(bucket_count, Self::yank_rightmost_bucket);
-	// 		self.check_deficient_child(bucket_count);
-	// 		r
-	// 	}
-	// }
+	/// Mutably borrows the value of the bucket at the given index. The bucket must be transient.
+	pub fn value_mut(&mut self, idx: u16) -> &mut [u8] {
+		debug_assert!(idx < self.bucket_count);
 
-	// /// Postcondition: May leave this node deficient. Will not leave descendant nodes deficient.
-	// fn yank_leftmost_bucket(&mut self) -> BucketPtr {
-	// 	if self.is_leaf() {
-	// 		let mut r = BucketPtr::empty();
-	// 		rotate_out(&mut self.buckets[0..self.bucket_count as usize], &mut r);
-	// 		self.bucket_count -= 1;
-	// 		r
-	// 	} else {
-	// 		let r = self.doto_child(0, Self::yank_leftmost_bucket);
-	// 		self.check_deficient_child(0);
-	// 		r
-	// 	}
-	// }
+		self.buckets[idx as usize].deref_mut().value_mut()
+	}
 
-	// fn delete_bucket(&mut self, idx: usize) -> () {
-	// 	rotate_out(&mut self.buckets[idx..self.bucket_count as usize], &mut BucketPtr::empty());
-	// 	self.bucket_count -= 1;
-	// }
+	/// Makes the child at the given index transient, forking it if needed, and returns a reference to it.
+	pub fn heat_child(&mut self, idx: u16) -> NodeRef {
+		self.fork_child(idx as usize);
+		self.child_ref(idx)
+	}
+
+	/// Forks the child at the given index into our transaction's arena, if it is persistent.
+	fn fork_child(&mut self, idx: usize) {
+		if !self.children[idx].is_transient() {
+			let arena: Rc<NodeArena> = self.arena.upgrade().expect("forked a child of a node outside any transaction");
+			self.children[idx].heat(&arena);
+		}
+	}
+
+	/// For the edge case where the head has no buckets and one child. Removes and returns that child.
+	pub fn take_only_child(&mut self) -> FatNodeRef {
+		debug_assert!(self.bucket_count == 0 && !self.is_leaf(), "called take_only_child when buckets are present");
+
+		let mut r = MemPtr::empty();
+		mem::swap(&mut r, &mut self.children[0]);
+		r.unwrap()
+	}
+
+	/// Moves the given parent bucket, and all buckets and children of the given right sibling, into this node.
+	/// The right sibling is left empty.
+	fn merge_from_right(&mut self, parent_bucket: BucketRef, right: &mut MemNode) {
+		let bucket_count = self.bucket_count as usize;
+		let right_bucket_count = right.bucket_count as usize;
+		debug_assert!(bucket_count + right_bucket_count + 1 < self.limits.capacity as usize,
+			"invalid bucket sizes for merge: {} {}", bucket_count, right_bucket_count);
+		debug_assert!(!self.dirty && !right.dirty, "merged nodes with buffered writes");
+
+		// We are 'stealing' this bucket from the parent. Both nodes need the same prefix
+		// before we can move buckets between them.
+		let len = common_prefix_len(&self.prefix, &right.prefix);
+		let prefix = self.prefix[..len].to_vec();
+		self.set_prefix(&prefix);
+		self.put_bucket(bucket_count as u16, parent_bucket);
+		let prefix = self.prefix.to_vec();
+		right.set_prefix(&prefix);
+		swap(
+			&mut self.buckets[(bucket_count + 1)..(bucket_count + right_bucket_count + 1)],
+			&mut right.buckets[..right_bucket_count]);
+		// Note that this is safe even if we are a leaf, because if so, all children are empty.
+		swap(
+			&mut self.children[(bucket_count + 1)..(bucket_count + right_bucket_count + 2)],
+			&mut right.children[..(right_bucket_count + 1)]);
+
+		self.bucket_count += right.bucket_count + 1;
+		right.bucket_count = 0;
+	}
+
+	/// Rotates one bucket from the given left sibling through the given parent bucket into this node.
+	/// The last child of the left sibling becomes the first child of this node.
+	/// The parent bucket has its full key.
+	fn borrow_one_left(&mut self, left: &mut MemNode, parent_bucket: &mut BucketRef) {
+		debug_assert!(!self.dirty && !left.dirty, "rebalanced nodes with buffered writes");
+		let bucket_count = self.bucket_count as usize;
+		let left_bucket_count = left.bucket_count as usize;
+
+		let mut b = left.take_bucket(left_bucket_count as u16 - 1);
+		let mut np = MemPtr::empty();
+		mem::swap(&mut np, &mut left.children[left_bucket_count]);
+		left.bucket_count -= 1;
+
+		// The borrowed bucket becomes the new parent bucket, and the old parent bucket is moved into this node.
+		mem::swap(parent_bucket, &mut b);
+		self.fit_prefix(b.key());
+		let mut bp = MemPtr::wrap(self.compress(b));
+		rotate_in(&mut bp, &mut self.buckets[..(bucket_count + 1)]);
+		rotate_in(&mut np, &mut self.children[..(bucket_count + 2)]);
+		self.bucket_count += 1;
+	}
+
+	/// Rotates one bucket from the given right sibling through the given parent bucket into this node.
+	/// The first child of the right sibling becomes the last child of this node.
+	/// The parent bucket has its full key.
+	fn borrow_one_right(&mut self, right: &mut MemNode, parent_bucket: &mut BucketRef) {
+		debug_assert!(!self.dirty && !right.dirty, "rebalanced nodes with buffered writes");
+		let bucket_count = self.bucket_count as usize;
+		let right_bucket_count = right.bucket_count as usize;
+
+		let mut bp = MemPtr::empty();
+		rotate_out(&mut right.buckets[..right_bucket_count], &mut bp);
+		let mut b = right.decompress(bp.unwrap());
+		let mut np = MemPtr::empty();
+		rotate_out(&mut right.children[..(right_bucket_count + 1)], &mut np);
+		right.bucket_count -= 1;
+
+		// The borrowed bucket becomes the new parent bucket, and the old parent bucket is moved into this node.
+		mem::swap(parent_bucket, &mut b);
+		self.put_bucket(bucket_count as u16, b);
+		self.children[bucket_count + 1] = np;
+		self.bucket_count += 1;
+	}
+
+	/// Rebalances the child at the given index if it is deficient, by merging it with a sibling
+	/// if the two fit in one node, or else borrowing buckets from that sibling. Modified children are heated,
+	/// so this may fork persistent nodes.
+	/// Preconditions: This node is not a leaf. Descendants of the given child are not deficient.
+	/// Result: No child of this node is deficient, unless borrowing would put a node over the byte limit.
+	/// Children keep at least NodeLimits::min_buckets even so, which may put this node over the byte limit.
+	/// This node may become deficient.
+	pub fn rebalance_child(&mut self, idx: u16) {
+		debug_assert!(!self.is_leaf());
+		debug_assert!(idx <= self.bucket_count);
+
+		if !self.children[idx as usize].apply(MemNode::is_deficient) {
+			return
+		}
+
+		// We rebalance the children on either side of the bucket at left_idx.
+		// Prefer the left sibling, if there is one.
+		let left_idx = if idx > 0 { idx - 1 } else { idx } as usize;
+		let bucket_count = self.bucket_count as usize;
+		let (left_count, left_bytes) = self.children[left_idx].apply(|n| (n.bucket_count, n.byte_size()));
+		let (right_count, right_bytes) = self.children[left_idx + 1].apply(|n| (n.bucket_count, n.byte_size()));
+		let parent_bytes = self.bucket_size(left_idx as u16);
+
+		if self.limits.fits(left_count + right_count + 1, left_bytes + right_bytes + parent_bytes) {
+			// The two children fit in one node. Merge them, removing the parent bucket and the right child.
+			let bp = self.take_bucket(left_idx as u16);
+			self.fork_child(left_idx);
+			self.fork_child(left_idx + 1);
+
+			{ // Borrow checker block
+				let (left_children, right_children) = self.children.split_at_mut(left_idx + 1);
+				let right = right_children[0].deref_mut();
+				left_children[left_idx].apply_mut(|ln| right.apply_mut(|rn| ln.merge_from_right(bp, rn)));
+			}
+
+			rotate_out(&mut self.buckets[left_idx..bucket_count], &mut MemPtr::empty());
+			let mut right = MemPtr::empty();
+			rotate_out(&mut self.children[(left_idx + 1)..(bucket_count + 1)], &mut right);
+			self.bucket_count -= 1;
+			// The right child was forked into our arena, and is empty now.
+			right.unwrap().discard(&self.arena.upgrade().unwrap());
+		} else {
+			// Otherwise, even out the two children. Since they hold at least capacity - 2 buckets together,
+			// neither will be deficient afterwards, unless we stop early to stay under the byte limit.
+			// Nodes with too few buckets always borrow.
+			let limits = self.limits;
+			let (count, other_bytes) = (self.bucket_count, self.byte_size() - parent_bytes);
+			let mut parent_bucket = self.take_bucket(left_idx as u16);
+			self.fork_child(left_idx);
+			self.fork_child(left_idx + 1);
+			let can_borrow = |dst: &MemNode, parent_bucket: &BucketRef, borrowed_bytes: usize|
+				dst.bucket_count < limits.min_buckets() ||
+				limits.fits(dst.bucket_count + 1, dst.byte_size() + parent_bucket.byte_size()) &&
+				limits.fits(count, other_bytes + borrowed_bytes);
+
+			{ // Borrow checker block
+				let parent_bucket = &mut parent_bucket;
+				let (left_children, right_children) = self.children.split_at_mut(left_idx + 1);
+				let right = right_children[0].deref_mut();
+
+				left_children[left_idx].apply_mut(|ln| right.apply_mut(|rn| {
+					if left_count < right_count {
+						for _ in 0..((right_count - left_count) / 2) {
+							if !can_borrow(ln, parent_bucket, rn.bucket_size(0)) {
+								break;
+							}
+							ln.borrow_one_right(rn, parent_bucket);
+						}
+					} else {
+						for _ in 0..((left_count - right_count) / 2) {
+							if !can_borrow(rn, parent_bucket, ln.bucket_size(ln.bucket_count - 1)) {
+								break;
+							}
+							rn.borrow_one_left(ln, parent_bucket);
+						}
+					}
+				}));
+			}
+
+			self.put_bucket(left_idx as u16, parent_bucket);
+		}
+	}
+
+	/* Buffer helpers */
+
+	pub fn is_dirty(&self) -> bool {
+		self.dirty
+	}
+
+	/// Gets this node's buffered writes. Their keys are stored without this node's prefix.
+	pub fn buffered(&self) -> &[BucketRef] {
+		&self.buffer
+	}
+
+	/// True if this node has more buffered writes than it may hold.
+	pub fn buffer_overflows(&self) -> bool {
+		self.buffer.len() > self.limits.buffer_capacity as usize
+	}
+
+	fn full_buffered_key(&self, b: &BucketRef) -> Vec<u8> {
+		let mut r = self.prefix.to_vec();
+		r.extend_from_slice(b.key());
+		r
+	}
+
+	/// Gets the least full key in this node, buffered or not.
+	fn first_full_key(&self) -> Vec<u8> {
+		let k = self.full_key(0);
+		match self.buffer.first() {
+			Some(b) => k.min(self.full_buffered_key(b)),
+			None => k,
+		}
+	}
+
+	/// Gets the greatest full key in this node, buffered or not.
+	fn last_full_key(&self) -> Vec<u8> {
+		let k = self.full_key(self.bucket_count - 1);
+		match self.buffer.last() {
+			Some(b) => k.max(self.full_buffered_key(b)),
+			None => k,
+		}
+	}
+
+	/// Finds the buffered write to the given full key, if any.
+	pub fn find_buffered(&self, k: &[u8]) -> Option<WeakBucketRef> {
+		if !k.starts_with(&self.prefix) {
+			return None
+		}
+
+		let k = &k[self.prefix.len()..];
+		self.buffer.binary_search_by(|b| b.key().cmp(k)).ok().map(|i| self.buffer[i].downgrade(&self.prefix))
+	}
+
+	/// Buffers the given write, which has its full key, merging it into any older write to the same key.
+	/// Writes this node can't buffer without changing its buckets are handed back; see BufferResult.
+	pub fn buffer_write(&mut self, b: BucketRef) -> BufferResult {
+		debug_assert!(!self.is_leaf(), "leaves have no buffer");
+
+		let idx = match self.find(b.key()) {
+			Ok(_) => return BufferResult::Bucket(b),
+			Err(idx) => idx,
+		};
+
+		// The caller buffers the write in our child, so we have buffered writes under us either way.
+		self.dirty = true;
+		if !self.fits_prefix_of(b.key()) {
+			return BufferResult::Child(idx, b)
+		}
+
+		self.fit_prefix(b.key());
+		let b = self.compress(b);
+		match self.buffer.binary_search_by(|m| m.key().cmp(b.key())) {
+			Ok(i) => {
+				let older = self.buffer[i].downgrade(&self.prefix);
+				self.buffer[i] = merge_write(self.limits.merge_operator, Some(&older), b);
+			}
+			Err(i) => self.buffer.insert(i, b),
+		}
+
+		BufferResult::Buffered(self.buffer_overflows())
+	}
+
+	/// True if this node still fits its limits after shortening its prefix, if needed, to share the given full key.
+	fn fits_prefix_of(&self, k: &[u8]) -> bool {
+		if k.starts_with(&self.prefix) {
+			return true
+		}
+
+		let shortened_by = self.prefix.len() - common_prefix_len(&self.prefix, k);
+		self.limits.fits(self.bucket_count, self.byte_size() + shortened_by * self.bucket_count as usize)
+	}
+
+	/// Takes the buffered writes to keys under the child at the given index, with their full keys.
+	pub fn take_buffered(&mut self, idx: u16) -> Vec<BucketRef> {
+		let bucket_count = self.bucket_count as usize;
+		let child_idx = |b: &BucketRef, buckets: &[MemPtr<BucketRef>]| {
+			buckets[..bucket_count].binary_search_by(|bp| bp.key().cmp(b.key())).unwrap_err() as u16
+		};
+
+		let buffer = mem::replace(&mut self.buffer, Vec::new());
+		let (taken, kept): (Vec<_>, Vec<_>) = buffer.into_iter().partition(|b| child_idx(b, &self.buckets) == idx);
+		self.buffer = kept;
+		taken.into_iter().map(|b| self.decompress(b)).collect()
+	}
+
+	/// Returns the index of the child with the most buffered writes.
+	pub fn fullest_child(&self) -> u16 {
+		let mut counts = vec![0; self.child_count() as usize];
+		for b in &self.buffer {
+			let idx = self.buckets[..self.bucket_count as usize].binary_search_by(|bp| bp.key().cmp(b.key()));
+			if let Err(idx) = idx {
+				counts[idx] += 1;
+			}
+		}
+
+		(0..counts.len()).max_by_key(|&i| counts[i]).unwrap_or(0) as u16
+	}
+
+	/// Marks this node as having no buffered writes in its subtree.
+	/// Requirements: the buffers of this node and its descendants are empty.
+	pub fn set_clean(&mut self) {
+		debug_assert!(self.buffer.is_empty());
+		self.dirty = false;
+	}
 
 	/* Invariants */
-	pub fn check_invariants_helper(&self, parent_lower_bound: Option<&[u8]>, parent_upper_bound: Option<&[u8]>,
-		is_hot: bool, recurse: bool) {
+	/// Checks this node's invariants. Children are checked recursively if should_check, given
+	/// the bounds of that child, returns true.
+	pub fn check_invariants_helper<F: Fn(Option<&[u8]>, Option<&[u8]>) -> bool>(&self, parent_lower_bound: Option<&[u8]>, parent_upper_bound: Option<&[u8]>,
+		is_hot: bool, should_check: &F) {
 		// TODO: validate all leaves are at the same level
 
+		let capacity = self.limits.capacity;
+		assert_eq!(self.buckets.len(), capacity as usize);
+		assert_eq!(self.children.len(), capacity as usize + 1);
+		assert!(self.bucket_count() < capacity, "node with {} buckets was not split", self.bucket_count());
+
 		// Validate the bucket count
-		for i in 0..(NODE_CAPACITY - 1) {
+		for i in 0..capacity {
 			if i >= self.bucket_count() {
 				assert!(self.bucket_ptr(i).is_empty(), "expected empty bucket in position {}", i);
 			} else {
 				assert!(!self.bucket_ptr(i).is_empty(), "expected populated bucket in position {}", i);
+				assert!(!self.bucket_ptr(i).is_delta(), "unmerged delta in position {}", i);
 				// Validate sorted order
 				if i > 1 {
 					assert!(self.key(i) > self.key(i - 1));
@@ -593,39 +1058,127 @@ impl MemNode {
 			}
 		}
 
-		// Validate bounds
-		assert!(parent_lower_bound.is_none() || self.key(0) > parent_lower_bound.unwrap());
-		assert!(parent_upper_bound.is_none() || self.key(self.bucket_count() - 1) < parent_upper_bound.unwrap());
+		// Validate bounds. Bounds are full keys.
+		assert!(parent_lower_bound.is_none() || &self.full_key(0)[..] > parent_lower_bound.unwrap());
+		assert!(parent_upper_bound.is_none() || &self.full_key(self.bucket_count() - 1)[..] < parent_upper_bound.unwrap());
 
-		// TODO: assert non-head nodes are never deficient.
+		// Non-head nodes are never deficient. The head node is the only node with neither bound.
+		// With a byte limit, splitting around large buckets may leave small nodes, but never smaller than min_buckets.
+		if parent_lower_bound.is_some() || parent_upper_bound.is_some() {
+			if self.limits.max_bytes.is_none() {
+				assert!(!self.is_deficient(), "deficient non-head node with {} buckets", self.bucket_count());
+			} else {
+				assert!(self.bucket_count() >= self.limits.min_buckets(), "non-head node with only {} buckets",
+					self.bucket_count());
+			}
+		}
 		assert!(self.is_leaf() || self.bucket_count() >= 1);
 
+		// Buffered writes are sorted, within our bounds, not to our own buckets, and only in branches.
+		// Clean nodes have clean children.
+		assert!(self.buffer.len() <= self.limits.buffer_capacity as usize, "buffer with {} writes was not flushed",
+			self.buffer.len());
+		assert!(!self.is_leaf() || self.buffer.is_empty(), "leaf has buffered writes");
+		assert!(self.dirty || self.buffer.is_empty(), "clean node has buffered writes");
+		for (i, b) in self.buffer.iter().enumerate() {
+			assert!(i == 0 || b.key() > self.buffer[i - 1].key());
+			let k = self.full_buffered_key(b);
+			assert!(self.find(&k).is_err(), "buffered write to a bucket of the same node");
+			assert!(parent_lower_bound.map_or(true, |lower| &k[..] > lower));
+			assert!(parent_upper_bound.map_or(true, |upper| &k[..] < upper));
+		}
+
+		// Every node stays under the byte limit, unless it is too small to split.
+		assert!(self.limits.fits(self.bucket_count(), self.byte_size()),
+			"node with {} buckets and {} bytes is over its limits", self.bucket_count(), self.byte_size());
+
 		// Validate the children
-		for i in 0..NODE_CAPACITY {
+		for i in 0..(capacity + 1) {
 			if self.is_leaf() || i >= self.bucket_count() + 1 {
 				assert!(self.child_ptr(i).is_empty());
 			} else {
 				assert!(!self.child_ptr(i).is_empty());
 
-				if recurse {
-					let lower_bound;
-					if i == 0 {
-						lower_bound = None;
-					} else {
-						lower_bound = Some(self.key(i - 1));
-					}
+				let lower_key;
+				let lower_bound;
+				if i == 0 {
+					lower_bound = parent_lower_bound;
+				} else {
+					lower_key = self.full_key(i - 1);
+					lower_bound = Some(&lower_key[..]);
+				}
 
-					let upper_bound;
-					if i == self.bucket_count() {
-						upper_bound = None;
-					} else {
-						upper_bound = Some(self.key(i));
-					}
+				let upper_key;
+				let upper_bound;
+				if i == self.bucket_count() {
+					upper_bound = parent_upper_bound;
+				} else {
+					upper_key = self.full_key(i);
+					upper_bound = Some(&upper_key[..]);
+				}
 
+				assert_eq!(self.children[i as usize].apply(MemNode::limits), self.limits,
+					"child limits differ from parent limits");
+				assert!(self.dirty || !self.children[i as usize].apply(MemNode::is_dirty), "clean node has dirty child");
+				if should_check(lower_bound, upper_bound) {
 					self.children[i as usize].deref().apply(
-						|n| n.check_invariants_helper(lower_bound, upper_bound, is_hot, recurse));
+						|n| n.check_invariants_helper(lower_bound, upper_bound, is_hot, should_check));
 				}
 			}
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use tree::bucketref::BucketRef;
+	use tree::noderef::FatNodeRef;
+	use super::*;
+
+	fn leaf(keys: &[&str]) -> MemNode {
+		let mut r = MemNode::empty(NodeLimits::new(4, None));
+		for (i, k) in keys.iter().enumerate() {
+			r.insert_at(i as u16, BucketRef::transient_from_bytes(k.as_bytes(), b""), None);
+		}
+		r
+	}
+
+	#[test]
+	fn test_prefix_from_bounds() {
+		let arena = NodeArena::new();
+		let left = FatNodeRef::new_transient(&arena, leaf(&["/a/b/1", "/a/b/2"]));
+		let right = FatNodeRef::new_transient(&arena, leaf(&["/a/c/2", "/a/c/3"]));
+		let mut n = MemNode::new_from_two(left, BucketRef::transient_from_bytes(b"/a/c/1", b""), right);
+
+		// With no outer bounds, children use their own edge keys.
+		assert_eq!(n.children[0].apply(|c| c.prefix().to_vec()), b"/a/".to_vec());
+		assert_eq!(n.children[1].apply(|c| c.prefix().to_vec()), b"/a/c/".to_vec());
+		assert_eq!(n.children[1].apply(|c| c.full_key(1)), b"/a/c/3".to_vec());
+		assert_eq!(n.children[1].apply(|c| c.key(1).to_vec()), b"3".to_vec());
+
+		// Keys outside the prefix sort before or after every key.
+		n.children[1].apply(|c| {
+			assert_eq!(c.find(b"/a/c/3"), Ok(1));
+			assert_eq!(c.find(b"/a/b"), Err(0));
+			assert_eq!(c.find(b"/a/d"), Err(2));
+		});
+
+		n.check_invariants_helper(None, None, true, &|_, _| true);
+	}
+
+	#[test]
+	fn test_prefix_shrinks() {
+		let mut n = leaf(&["/a/b/1"]);
+		n.set_prefix(b"/a/b/");
+		n.insert_at(1, BucketRef::transient_from_bytes(b"/a/b/2", b""), None);
+		assert_eq!(n.prefix(), b"/a/b/");
+
+		n.insert_at(2, BucketRef::transient_from_bytes(b"/a/c", b""), None);
+		assert_eq!(n.prefix(), b"/a/");
+		assert_eq!(n.full_key(0), b"/a/b/1".to_vec());
+		assert_eq!(n.full_key(2), b"/a/c".to_vec());
+
+		let removed = n.remove_at(0);
+		assert_eq!(removed.key(), b"/a/b/1");
+	}
+}
@@ -6,12 +6,17 @@
 // #[macro_use]
 // extern crate quickcheck;
 
+mod allocator;
 mod bucketref;
+mod chunks;
 
 mod memnode;
 mod node;
+mod nodecache;
 mod noderef;
 
+pub mod page;
+
 mod util;
 
 pub mod btree;
@@ -0,0 +1,229 @@
+//! A bounded cache of persistent nodes loaded from a BlockStore.
+//!
+//! Nodes loaded through the cache refer to their children by address, so a cached node doesn't pin its subtree.
+//! Chunked values are loaded when they are first read. Each node is charged the size of its page, plus its chunked
+//! values once they are loaded. When trimmed, the cache evicts nodes with the CLOCK algorithm until it fits its
+//! capacity: nodes used since the hand last passed them get a second chance.
+//! Evicted nodes are freed once nothing else pins them, and are faulted back in when next used.
+//!
+//! Tree operations hold weak references into the nodes they visit, so the cache only evicts when trimmed,
+//! between operations.
+//!
+//! Tree algorithms can't fail, so a node that can't be loaded reads as an empty leaf, and a chunk that can't be
+//! loaded reads as an empty value. Either poisons the cache.
+//! Trees sharing a poisoned cache return the error from then on, rather than trusting what they read.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::rc::{Rc, Weak};
+
+use futures::Future;
+
+use counter::Counter;
+
+use data::RcBytes;
+
+use storage::{BlockAddress, BlockStore};
+
+use traits::TreeError;
+
+use tree::chunks::ChunkStore;
+use tree::memnode::{MemNode, NodeLimits};
+use tree::noderef::{FatNodeRef, PersistentNode};
+use tree::page::{NodeAddress, Page};
+
+/// The default capacity of a NodeCache, in bytes.
+pub const DEFAULT_CACHE_CAPACITY: usize = 64 << 20;
+
+pub struct NodeCache {
+    fetch: Box<dyn Fn(BlockAddress) -> Result<RcBytes, TreeError>>,
+    limits: NodeLimits,
+    chunks: Rc<ChunkStore>,
+    /// The number of bytes of nodes the cache holds before it evicts.
+    capacity: usize,
+    clock: RefCell<Clock>,
+    /// The first error loading a node, if any. io::Error isn't Clone, so we keep its kind and message.
+    error: RefCell<Option<(io::ErrorKind, String)>>,
+}
+
+struct Clock {
+    entries: Vec<CacheEntry>,
+    /// The index of each cached node in entries.
+    index: HashMap<NodeAddress, usize>,
+    hand: usize,
+    bytes: usize,
+    faults: usize,
+}
+
+struct CacheEntry {
+    addr: NodeAddress,
+    node: Rc<PersistentNode>,
+    size: usize,
+    referenced: bool,
+}
+
+impl NodeCache {
+    /// Creates a cache of nodes in the given store, which puts loaded chunks into the given chunk store.
+    pub fn new<S: BlockStore + 'static>(store: Rc<S>, limits: NodeLimits, chunks: Rc<ChunkStore>, capacity: usize)
+        -> Rc<NodeCache> {
+        Rc::new(NodeCache {
+            fetch: Box::new(move |addr| store.get(addr).wait()),
+            limits: limits,
+            chunks: chunks,
+            capacity: capacity,
+            clock: RefCell::new(Clock {
+                entries: Vec::new(),
+                index: HashMap::new(),
+                hand: 0,
+                bytes: 0,
+                faults: 0,
+            }),
+            error: RefCell::new(None),
+        })
+    }
+
+    /// Gets the node at the given address, loading it if it isn't cached.
+    pub fn get(cache: &Rc<NodeCache>, addr: NodeAddress) -> Result<Rc<PersistentNode>, TreeError> {
+        if let Some(node) = cache.clock.borrow_mut().get(addr) {
+            return Ok(node)
+        }
+
+        let block = (cache.fetch)(addr.0)?;
+        let page = Page::decode(&block)?;
+        if page.buckets.len() >= cache.limits.capacity as usize {
+            return Err(TreeError::RuntimeError(format!("node at {:?} does not fit this tree's node limits", addr)))
+        }
+
+        let chunks = page.chunk_addresses().into_iter().map(|chunk_addr| {
+            let weak = Rc::downgrade(cache);
+            (chunk_addr, ChunkStore::put_saved(&cache.chunks, chunk_addr, Box::new(move |chunk_addr| {
+                NodeCache::load_chunk(&weak, addr, chunk_addr)
+            })))
+        }).collect();
+
+        let children = page.children.iter().map(|&child| FatNodeRef::new_stored(child, cache)).collect();
+        let txid = page.txid;
+        let node = Rc::new(PersistentNode::loaded(page.into_node(cache.limits, children, &chunks), txid, addr));
+
+        cache.clock.borrow_mut().insert(addr, node.clone(), block.len());
+        Ok(node)
+    }
+
+    /// Loads a chunked value of the node at the given address, charging it to that node if it is still cached.
+    /// A value that can't be loaded poisons the cache, and reads as empty.
+    fn load_chunk(cache: &Weak<NodeCache>, addr: NodeAddress, chunk_addr: BlockAddress) -> RcBytes {
+        let cache = cache.upgrade().expect("chunk outlived its node cache");
+        match (cache.fetch)(chunk_addr) {
+            Ok(v) => {
+                cache.clock.borrow_mut().charge(addr, v.len());
+                v
+            }
+            Err(e) => {
+                cache.poison(format!("failed to load chunk at {:?}", chunk_addr), e);
+                RcBytes::new(&[][..])
+            }
+        }
+    }
+
+    /// Like get, but for tree algorithms, which can't fail. A node that can't be loaded poisons the cache,
+    /// and reads as an empty leaf.
+    pub fn get_or_poison(cache: &Rc<NodeCache>, addr: NodeAddress) -> Rc<PersistentNode> {
+        NodeCache::get(cache, addr).unwrap_or_else(|e| {
+            cache.poison(format!("failed to load node at {:?}", addr), e);
+            Rc::new(PersistentNode::loaded(MemNode::empty(cache.limits), Counter::new(0), addr))
+        })
+    }
+
+    /// Records the given error, unless an earlier one poisoned this cache already.
+    fn poison(&self, what: String, e: TreeError) {
+        let (kind, msg) = match e {
+            TreeError::IoError(e) => (e.kind(), e.to_string()),
+            e => (io::ErrorKind::InvalidData, format!("{:?}", e)),
+        };
+        let mut error = self.error.borrow_mut();
+        if error.is_none() {
+            *error = Some((kind, format!("{}: {}", what, msg)));
+        }
+    }
+
+    /// Returns the error that poisoned this cache, if any.
+    pub fn check(&self) -> Result<(), TreeError> {
+        match *self.error.borrow() {
+            Some((kind, ref msg)) => Err(io::Error::new(kind, msg.clone()).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Evicts nodes until the cache fits its capacity.
+    pub fn trim(&self) {
+        // Evicted nodes are dropped after the clock is released, since dropping them frees their chunks.
+        let _evicted = self.clock.borrow_mut().evict(self.capacity);
+    }
+
+    /// The number of bytes charged for the nodes in this cache.
+    pub fn byte_size(&self) -> usize {
+        self.clock.borrow().bytes
+    }
+
+    /// The number of nodes loaded from the store, including nodes loaded again after eviction.
+    pub fn faults(&self) -> usize {
+        self.clock.borrow().faults
+    }
+}
+
+impl Clock {
+    fn get(&mut self, addr: NodeAddress) -> Option<Rc<PersistentNode>> {
+        let entries = &mut self.entries;
+        self.index.get(&addr).map(|&i| {
+            entries[i].referenced = true;
+            entries[i].node.clone()
+        })
+    }
+
+    /// Charges the given number of bytes to the node at the given address, if it is cached.
+    fn charge(&mut self, addr: NodeAddress, size: usize) {
+        if let Some(&i) = self.index.get(&addr) {
+            self.entries[i].size += size;
+            self.bytes += size;
+        }
+    }
+
+    fn insert(&mut self, addr: NodeAddress, node: Rc<PersistentNode>, size: usize) {
+        self.index.insert(addr, self.entries.len());
+        self.entries.push(CacheEntry {
+            addr: addr,
+            node: node,
+            size: size,
+            referenced: false,
+        });
+        self.bytes += size;
+        self.faults += 1;
+    }
+
+    /// Evicts nodes until at most the given number of bytes are cached, returning the evicted nodes.
+    fn evict(&mut self, capacity: usize) -> Vec<Rc<PersistentNode>> {
+        let mut evicted = Vec::new();
+
+        while self.bytes > capacity {
+            if self.hand >= self.entries.len() {
+                self.hand = 0;
+            }
+
+            if self.entries[self.hand].referenced {
+                self.entries[self.hand].referenced = false;
+                self.hand += 1;
+            } else {
+                let entry = self.entries.swap_remove(self.hand);
+                self.index.remove(&entry.addr);
+                if self.hand < self.entries.len() {
+                    self.index.insert(self.entries[self.hand].addr, self.hand);
+                }
+                self.bytes -= entry.size;
+                evicted.push(entry.node);
+            }
+        }
+
+        evicted
+    }
+}
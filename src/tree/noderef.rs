@@ -1,20 +1,24 @@
 //! Multiple related kinds of 'fat' tagged pointers to different kinds of nodes.
 
-use std::cell::RefCell;
+use std::cell::Cell;
 use std::rc::{Rc, Weak};
-use std::mem;
-use std::ops::{Deref, DerefMut};
+use std::ops::Deref;
 
 use counter::Counter;
 
+
+use tree::allocator::*;
 use tree::memnode::*;
+use tree::nodecache::NodeCache;
+use tree::page::NodeAddress;
 
 /// A handle to a hot node which can be quickly dereferenced. Note that it's lifetimed--
 /// HotHandles are intended to be ephemeral.
 // TODO: HotHandle -> TransientRef
 pub enum HotHandle {
-    Existing(Weak<RefCell<MemNode>>),
-    New(Rc<RefCell<MemNode>>),
+    Existing(NodePtr),
+    /// A node forked from a persistent node, which has yet to be reassigned into its parent.
+    New(NodePtr),
 }
 
 impl HotHandle {
@@ -22,31 +26,29 @@ impl HotHandle {
     pub fn apply_mut<F, R> (&mut self, f: F) -> R where F: FnOnce(&mut MemNode) -> R
     {
         match *self {
-            // Same call, different objects. Necessary because of the monomorphism restriction.
-            HotHandle::Existing(ref mut w_rfc_hn) => {
-                // borrow checker tricks
-                let strong = w_rfc_hn.upgrade().unwrap();
-                let r = f(strong.borrow_mut().deref_mut());
-                r
-            }
-            HotHandle::New(ref mut rc_rfc_hn) => f(Rc::get_mut(rc_rfc_hn).unwrap().borrow_mut().deref_mut()),
+            HotHandle::Existing(ref p) | HotHandle::New(ref p) => apply_node_mut(p, f),
         }
     }
 }
 
 /// A fat pointer to a Node or node address. Used externally by tree algorithms.
-/// These NodeRefs are 'weak' and must be reloaded after context switches.
+/// These NodeRefs are 'weak' and must be reloaded after context switches. Transient NodeRefs
+/// are checked against their transaction's arena.
 #[derive(Clone)]
 pub enum NodeRef {
-    Transient(Weak<RefCell<MemNode>>),
+    Transient(NodePtr),
     Persistent(Weak<PersistentNode>),
+    Stored(Rc<StoredNode>),
 }
 
 impl NodeRef {
+    /// Pins the referenced node. Stored nodes are faulted in from their store if they were evicted.
     pub fn upgrade(&self) -> FatNodeRef {
         match *self {
-            NodeRef::Transient(ref rc_rfc_hn) => FatNodeRef::Transient(rc_rfc_hn.upgrade().unwrap()),
-            NodeRef::Persistent(ref rc_pn) => FatNodeRef::Persistent(rc_pn.upgrade().unwrap()),
+            NodeRef::Transient(ref p) => FatNodeRef::Transient(p.clone()),
+            NodeRef::Persistent(ref rc_pn) =>
+                FatNodeRef::Persistent(rc_pn.upgrade().expect("persistent node was dropped")),
+            NodeRef::Stored(ref s) => FatNodeRef::Persistent(s.load()),
         }
     }
 
@@ -56,6 +58,16 @@ impl NodeRef {
         self.upgrade().apply(f)
     }
 
+    /// Do something to the referenced MemNode, which must be transient.
+    pub fn apply_mut<F, R> (&self, f: F) -> R where
+    F: FnOnce(&mut MemNode) -> R
+    {
+        match *self {
+            NodeRef::Transient(ref p) => apply_node_mut(p, f),
+            NodeRef::Persistent(_) | NodeRef::Stored(_) => panic!("node is not transient"),
+        }
+    }
+
     // TODO: figure out the 'apply' story.
     pub fn apply_persistent<F, R> (&self, f: F) -> R where
     F: Fn(&PersistentNode) -> R
@@ -63,13 +75,40 @@ impl NodeRef {
         self.upgrade().apply_persistent(f)
     }
 
-    /// Returns a hot NodeRef which may be modified, together with a reference to that node. May return self.
-    pub fn heat(&self) -> (HotHandle, bool) {
+    pub fn is_transient(&self) -> bool {
         match *self {
-            NodeRef::Transient(ref rc_rfc_hn) => (HotHandle::Existing(rc_rfc_hn.clone()), false),
-            NodeRef::Persistent(ref rc_pn) => {
-                let newnode = rc_pn.upgrade().unwrap().deref().fork();
-                (HotHandle::New(Rc::new(RefCell::new(newnode))), true)
+            NodeRef::Transient(_) => true,
+            NodeRef::Persistent(_) | NodeRef::Stored(_) => false,
+        }
+    }
+
+    /// Returns true if this node was written after the given txid. Transient nodes are always newer.
+    /// Since writes copy every node on their path, no descendant of an older node is newer.
+    pub fn is_newer_than(&self, txid: Counter) -> bool {
+        self.is_transient() || self.apply_persistent(|pnode| txid.circle_lt(pnode.txid()))
+    }
+
+    /// Returns true if both refer to the same node. Persistent nodes are the same if they were saved
+    /// to the same address, even if one of them was loaded again since.
+    pub fn is_same_node(&self, other: &NodeRef) -> bool {
+        match (self, other) {
+            (&NodeRef::Transient(ref a), &NodeRef::Transient(ref b)) => a.ptr_eq(b),
+            (&NodeRef::Transient(_), _) | (_, &NodeRef::Transient(_)) => false,
+            _ => match (self.upgrade(), other.upgrade()) {
+                (FatNodeRef::Persistent(ref a), FatNodeRef::Persistent(ref b)) =>
+                    Rc::ptr_eq(a, b) || a.address().map_or(false, |addr| b.address() == Some(addr)),
+                _ => false,
+            },
+        }
+    }
+
+    /// Returns a hot NodeRef which may be modified, together with true if it was forked into the given arena.
+    pub fn heat(&self, arena: &Rc<NodeArena>) -> (HotHandle, bool) {
+        match *self {
+            NodeRef::Transient(ref p) => (HotHandle::Existing(p.clone()), false),
+            NodeRef::Persistent(_) | NodeRef::Stored(_) => {
+                let newnode = self.apply_persistent(PersistentNode::fork);
+                (HotHandle::New(NodeArena::alloc(arena, newnode)), true)
             }
         }
     }
@@ -81,9 +120,20 @@ pub struct PersistentNode {
     txid: Counter,
     // We recycle MemNode as persistent nodes.
     pub node: MemNode,
+    /// Where this node was saved, if it was saved to a BlockStore.
+    addr: Cell<Option<NodeAddress>>,
 }
 
 impl PersistentNode {
+    /// Wraps a node loaded from the given address.
+    pub fn loaded(n: MemNode, txid: Counter, addr: NodeAddress) -> PersistentNode {
+        PersistentNode {
+            txid: txid,
+            node: n,
+            addr: Cell::new(Some(addr)),
+        }
+    }
+
     fn fork(&self) -> MemNode {
         self.node.fork()
     }
@@ -91,6 +141,34 @@ impl PersistentNode {
     pub fn txid(&self) -> Counter {
         self.txid
     }
+
+    pub fn address(&self) -> Option<NodeAddress> {
+        self.addr.get()
+    }
+
+    pub fn set_address(&self, addr: NodeAddress) {
+        self.addr.set(Some(addr));
+    }
+}
+
+/// A persistent node saved to a BlockStore, which is loaded through its tree's NodeCache when needed.
+/// Parents don't pin stored children, so the cache may evict them.
+pub struct StoredNode {
+    addr: NodeAddress,
+    cache: Weak<NodeCache>,
+}
+
+impl StoredNode {
+    pub fn address(&self) -> NodeAddress {
+        self.addr
+    }
+
+    /// Loads this node from the cache, faulting it in from the store if needed. Since tree algorithms can't fail,
+    /// a node that can't be read poisons the cache; see `NodeCache::get_or_poison`.
+    pub fn load(&self) -> Rc<PersistentNode> {
+        let cache = self.cache.upgrade().expect("stored node outlived its cache");
+        NodeCache::get_or_poison(&cache, self.addr)
+    }
 }
 
 /// A fat pointer to a Node. If hot, may pin underlying unique or shared resources.
@@ -99,17 +177,30 @@ impl PersistentNode {
 // TODO: move all this shit into NodeRef, move FatNodeRef into MemNode
 // Doesn't implement Clone. Although cloneable, we want to disallow clones of the transient variant.
 pub enum FatNodeRef {
-    // Hot(RefCell<Rc<MemNode>>),
-    // TODO shouldn't be RC
-    Transient(Rc<RefCell<MemNode>>),
+    /// A node owned by its transaction's arena.
+    Transient(NodePtr),
     Persistent(Rc<PersistentNode>),
+    /// A persistent node that may not be in memory.
+    Stored(Rc<StoredNode>),
 }
 
 impl FatNodeRef {
     /* Constructors */
-    // TODO shouldn't need
-    pub fn new_transient(n: MemNode) -> Self {
-        FatNodeRef::Transient(Rc::new(RefCell::new(n)))
+    pub fn new_transient(arena: &Rc<NodeArena>, n: MemNode) -> Self {
+        FatNodeRef::Transient(NodeArena::alloc(arena, n))
+    }
+
+    /// Wraps a node loaded from the given address.
+    pub fn new_persistent(n: MemNode, txid: Counter, addr: NodeAddress) -> Self {
+        FatNodeRef::Persistent(Rc::new(PersistentNode::loaded(n, txid, addr)))
+    }
+
+    /// Refers to the node at the given address, to be loaded through the given cache.
+    pub fn new_stored(addr: NodeAddress, cache: &Rc<NodeCache>) -> Self {
+        FatNodeRef::Stored(Rc::new(StoredNode {
+            addr: addr,
+            cache: Rc::downgrade(cache),
+        }))
     }
 
     /* Accessors */
@@ -117,8 +208,9 @@ impl FatNodeRef {
     F: FnOnce(&MemNode) -> R
     {
         match *self {
-            FatNodeRef::Transient(ref rc_rfc_hn) => f(rc_rfc_hn.deref().borrow().deref()),
+            FatNodeRef::Transient(ref p) => apply_node(p, f),
             FatNodeRef::Persistent(ref rc_pn) => f(&rc_pn.deref().node),
+            FatNodeRef::Stored(ref s) => f(&s.load().node),
         }
     }
 
@@ -128,13 +220,42 @@ impl FatNodeRef {
         match *self {
             FatNodeRef::Transient(_) => panic!("node is not persistent"),
             FatNodeRef::Persistent(ref rc_pn) => f(&rc_pn.deref()),
+            FatNodeRef::Stored(ref s) => f(&s.load()),
+        }
+    }
+
+    /// Replaces this FatNodeRef with a transient fork in the given arena, if it is persistent.
+    pub fn heat(&mut self, arena: &Rc<NodeArena>) {
+        if !self.is_transient() {
+            let newnode = self.apply_persistent(PersistentNode::fork);
+            *self = FatNodeRef::new_transient(arena, newnode);
+        }
+    }
+
+    /// Do something to the referenced MemNode, which must be transient.
+    pub fn apply_mut<F, R>(&mut self, f: F) -> R where
+    F: FnOnce(&mut MemNode) -> R
+    {
+        match *self {
+            FatNodeRef::Transient(ref p) => apply_node_mut(p, f),
+            FatNodeRef::Persistent(_) | FatNodeRef::Stored(_) => panic!("node is not transient"),
         }
     }
 
     pub fn noderef(&self) -> NodeRef {
         match *self {
-            FatNodeRef::Transient(ref rc_) => NodeRef::Transient(Rc::downgrade(&rc_)),
+            FatNodeRef::Transient(ref p) => NodeRef::Transient(p.clone()),
             FatNodeRef::Persistent(ref rc_) => NodeRef::Persistent(Rc::downgrade(&rc_)),
+            FatNodeRef::Stored(ref s) => NodeRef::Stored(s.clone()),
+        }
+    }
+
+    /// Gets the address this node was saved to, if any. Doesn't load stored nodes.
+    pub fn address(&self) -> Option<NodeAddress> {
+        match *self {
+            FatNodeRef::Transient(_) => None,
+            FatNodeRef::Persistent(ref rc_pn) => rc_pn.address(),
+            FatNodeRef::Stored(ref s) => Some(s.address()),
         }
     }
 
@@ -143,133 +264,89 @@ impl FatNodeRef {
     // TODO: better implementations for this
     pub fn reassign(&mut self, h: HotHandle) {
         match h {
-            HotHandle::Existing(_) => {
-                // TODO: impl a safety check?
-
+            HotHandle::Existing(p) => {
                 // Safety check: A HotHandle::Existing may only be reassigned to itself.
-                // (Safety check disabled because perf consequences)
-                // if let &mut FatNodeRef::Transient(ref rc_rfc_hn) = self {
-                //     let tgt = rc_rfc_hn.deref().as_ptr();
-                //     let src = hn_ref.deref() as *const _;
-                //     debug_assert!(ptr_eq(tgt, src),
-                //         "Mismatch in node reassignment: target {:p}, source {:p}", tgt, src);
-                // } else {
-                //     debug_assert!(false, "Cannot assign an existing MemNode to a persistent FatNodeRef")
-                // }
-            }
-            HotHandle::New(hn_rc_cell) => {
-                // TODO: can we do self = ?
-                let mut replacement = FatNodeRef::Transient(hn_rc_cell);
-                mem::swap(&mut replacement, self)
+                if let FatNodeRef::Transient(ref tgt) = *self {
+                    debug_assert!(tgt.ptr_eq(&p), "Mismatch in node reassignment");
+                } else {
+                    debug_assert!(false, "Cannot assign an existing MemNode to a persistent FatNodeRef")
+                }
             }
+            HotHandle::New(p) => *self = FatNodeRef::Transient(p),
         }
     }
 
-    /// Immutes this NodeRef, recursively immuting its children.
+    /// Immutes this NodeRef, recursively immuting its transient children. Since every transient node
+    /// has a transient parent, this only visits nodes written since the last immute.
+    /// Immuted nodes are moved out of their arena, leaving it free to be dropped.
     pub fn immute(&mut self, txid: Counter) {
-        // we need to move out of self
-        let mut oldself = unsafe { mem::uninitialized() };
-        mem::swap(self, &mut oldself);
-        // now self is uninitialized. from now on, we can't ever panic
-
-        // destroys oldself
-        let mut newself = match oldself {
-            FatNodeRef::Transient(rc_cell_hn) => {
-                let mut hn = Rc::try_unwrap(rc_cell_hn).ok().unwrap().into_inner();
-                hn.immute(txid);
-                FatNodeRef::Persistent(Rc::new(PersistentNode {
-                    txid: txid,
-                    node: hn,
-                }))
-            }
-            FatNodeRef::Persistent(_x) => FatNodeRef::Persistent(_x),
+        let mut hn = match *self {
+            FatNodeRef::Transient(ref p) => take_node(p),
+            FatNodeRef::Persistent(_) | FatNodeRef::Stored(_) => return,
         };
 
-        mem::swap(self, &mut newself);
-        // now newself is uninitialized
-        mem::forget(newself);
+        hn.immute(txid);
+        *self = FatNodeRef::Persistent(Rc::new(PersistentNode {
+            txid: txid,
+            node: hn,
+            addr: Cell::new(None),
+        }));
+    }
+
+    /// Like immute, but leaves this NodeRef alone and returns a persistent copy. Transient nodes are copied
+    /// with the given txid, and persistent nodes are shared.
+    pub fn immuted_copy(&self, txid: Counter) -> FatNodeRef {
+        match *self {
+            FatNodeRef::Transient(ref p) => FatNodeRef::Persistent(Rc::new(PersistentNode {
+                txid: txid,
+                node: apply_node(p, |n| n.immuted_copy(txid)),
+                addr: Cell::new(None),
+            })),
+            FatNodeRef::Persistent(_) | FatNodeRef::Stored(_) => self.shallow_clone(),
+        }
+    }
+
+    /// Drops this reference to a node nothing else points to. Transient nodes are freed, so their arena
+    /// can reuse the slot.
+    pub fn discard(self, arena: &NodeArena) {
+        if let FatNodeRef::Transient(p) = self {
+            arena.free(p);
+        }
     }
 
     pub fn shallow_clone(&self) -> FatNodeRef {
         match self {
             &FatNodeRef::Transient(ref _x) => panic!("cannot shallow_clone a hot node"),
             &FatNodeRef::Persistent(ref _x) => FatNodeRef::Persistent(_x.clone()),
+            &FatNodeRef::Stored(ref s) => FatNodeRef::Stored(s.clone()),
         }
     }
 
-    // // For the edge case where head has 1 child.
-    // pub fn disown_only_child(&mut self) -> NodePtr {
-    //  if self.bucket_count() != 0 || self.is_leaf() {
-    //      panic!("called disown_only_child when buckets are present")
-    //  }
-    //  let mut r = NodePtr::empty();
-    //  mem::swap(&mut r, &mut self.children[0]);
-    //  r
-    // }
-
-    // /// Postcondition: May leave this node deficient.
-    // pub fn delete(&mut self, k: &[u8]) -> bool {
-    //  // Unlike in insert, we rebalance *after* delete.
-    //  match self.find(k) {
-    //      Ok(idx) => {
-    //          if self.is_leaf() {
-    //              Some(Self::cool(self.to_hot().delete_bucket(idx)));
-    //              true
-    //          } else {
-    //              if idx > 0 {
-    //                  // get leftmost descendant from right child
-    //                  let new_child = self.get_child(idx + 1).heat();
-    //                  let new_bucket = new_child.yank_leftmost_bucket();
-    //                  let hn = self.heat();
-    //                  hn.replace_bucket(idx, new_bucket);
-    //                  hn.replace_child(idx + 1, new_child);
-    //                  hn.check_deficient_child(idx + 1);
-    //                  true
-    //              } else {
-    //                  // get rightmost descendant from left child
-    //                  let new_child = self.get_child(idx).heat();
-    //                  let new_bucket = new_child.yank_rightmost_bucket();
-    //                  r.replace_bucket(idx, new_bucket);
-    //                  r.replace_child(idx, new_child);
-    //                  r.check_deficient_child(idx);
-    //                  true
-    //              }
-    //          }
-    //      },
-    //      Err(idx) => if !self.is_leaf() {
-    //          match self.get_child_mut(idx).delete(k) {
-    //              Some(newchild) => {
-    //                  let r = self.heat();
-    //                  r.check_deficient_child(idx);
-    //                  Some(Self::cool(r))
-    //              }
-    //              None => None
-    //          }
-    //      } else {
-    //          None
-    //      },
-    //  }
-    // }
-
-    fn is_transient(&self) -> bool {
+    pub fn is_transient(&self) -> bool {
         match *self {
             FatNodeRef::Transient(_) => true,
-            FatNodeRef::Persistent(_) => false,
+            FatNodeRef::Persistent(_) | FatNodeRef::Stored(_) => false,
         }
     }
 
     pub fn check_invariants(&self) {
-        self.check_invariants_helper(None, None, self.is_transient(), true)
+        self.check_invariants_in(&|_, _| true)
+    }
+
+    /// Like check_invariants, but skips any subtree for which should_check, given the bounds
+    /// of that subtree, returns false.
+    pub fn check_invariants_in<F: Fn(Option<&[u8]>, Option<&[u8]>) -> bool>(&self, should_check: &F) {
+        self.check_invariants_helper(None, None, self.is_transient(), should_check)
     }
 
-    pub fn check_invariants_helper(&self, parent_lower_bound: Option<&[u8]>, parent_upper_bound: Option<&[u8]>,
-        is_transient: bool, recurse: bool) {
+    pub fn check_invariants_helper<F: Fn(Option<&[u8]>, Option<&[u8]>) -> bool>(&self, parent_lower_bound: Option<&[u8]>, parent_upper_bound: Option<&[u8]>,
+        is_transient: bool, should_check: &F) {
 
         if !is_transient && self.is_transient() {
             panic!("failed invariant: child of immutable node is hot");
         } else {
             self.noderef().apply(|n| n.check_invariants_helper(parent_lower_bound, parent_upper_bound,
-                self.is_transient(), recurse));
+                self.is_transient(), should_check));
         }
     }
 }
@@ -0,0 +1,538 @@
+//! The on-disk page format of persistent nodes.
+//!
+//! Pages follow the layout sketched in hitchhiker/hotnode.rs. Every integer is big-endian.
+//!
+//! - Header: magic `HTPG`, version (u16), flags (u8: 1 if dirty), a reserved zero byte, txid (u64),
+//!   bucket count, buffer count and child count (u16 each), and prefix length (u32).
+//! - Prefix: the prefix shared by every key in the node.
+//! - Key table: one entry per bucket, then one per buffered write. Each entry holds flags (u8: 1 for tombstones,
+//!   2 for deltas), txid (u64), and the offset and length (u32 each) of its key in the key section.
+//! - Keys: keys without the prefix.
+//! - Child pointer table: the NodeAddress of each child (u64). Children live in pages of their own.
+//! - Value address table: one entry per key table entry. Each holds a kind (u8: 0 inline, 1 chunked),
+//!   an offset into the value section or the address of a chunk (u64), and a length (u32, 0 for chunks).
+//! - Values: inline values.
+//!
+//! Pages can be encoded and decoded on their own, without a tree or a store.
+//!
+//! A root record points at the head page of a saved or committed tree, and holds the settings its pages were
+//! written with. It holds flags (u8: 1 if the tree has a head, 2 if it has a byte limit, 4 if it has a chunk
+//! threshold, 8 if it has a merge operator), the NodeAddress of the head (u64, 0 if none), the tree's txid (u64),
+//! its node capacity (u16), its byte limit (u64, 0 if none), its buffer capacity (u16), and its chunk threshold
+//! (u64, 0 if none).
+
+use std::collections::HashMap;
+use std::io;
+use std::rc::Rc;
+
+use counter::Counter;
+
+use data::RcBytes;
+
+use storage::BlockAddress;
+
+use tree::bucketref::BucketRef;
+use tree::chunks::Chunk;
+use tree::memnode::{MemNode, NodeLimits};
+use tree::noderef::{FatNodeRef, PersistentNode};
+
+const MAGIC: &'static [u8; 4] = b"HTPG";
+/// The current page version. Pages of newer versions are rejected.
+pub const PAGE_VERSION: u16 = 1;
+
+const HEADER_SIZE: usize = 26;
+const KEY_ENTRY_SIZE: usize = 17;
+const VALUE_ENTRY_SIZE: usize = 13;
+
+const DIRTY: u8 = 1;
+const TOMBSTONE: u8 = 1;
+const DELTA: u8 = 2;
+const INLINE: u8 = 0;
+const CHUNKED: u8 = 1;
+
+const ROOT_RECORD_SIZE: usize = 37;
+const HAS_HEAD: u8 = 1;
+const HAS_MAX_BYTES: u8 = 2;
+const HAS_CHUNK_THRESHOLD: u8 = 4;
+const HAS_MERGE_OPERATOR: u8 = 8;
+
+/// The address of a node's page in a BlockStore.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeAddress(pub BlockAddress);
+
+/// A decoded page.
+pub struct Page {
+    pub txid: Counter,
+    /// True if the node or any of its descendants has buffered writes.
+    pub dirty: bool,
+    pub prefix: RcBytes,
+    pub buckets: Vec<PageBucket>,
+    pub buffer: Vec<PageBucket>,
+    pub children: Vec<NodeAddress>,
+}
+
+/// A bucket or buffered write in a page. Keys are stored without their node's prefix.
+pub struct PageBucket {
+    pub key: RcBytes,
+    pub value: PageValue,
+    pub tombstone: bool,
+    pub delta: bool,
+    pub txid: Counter,
+}
+
+pub enum PageValue {
+    Inline(RcBytes),
+    /// A value stored off-tree, in a block of its own.
+    Chunked(BlockAddress),
+}
+
+impl Page {
+    /// Makes a page for the given node. Panics if any of its children or chunks has not been saved.
+    pub fn from_node(pnode: &PersistentNode) -> Page {
+        let n = &pnode.node;
+        let page_bucket = |b: &BucketRef| PageBucket {
+            key: RcBytes::new(b.key()),
+            value: match b.chunk() {
+                Some(c) => PageValue::Chunked(c.address().expect("chunk was not saved")),
+                None => PageValue::Inline(b.value()),
+            },
+            tombstone: b.is_tombstone(),
+            delta: b.is_delta(),
+            txid: b.txid(),
+        };
+
+        Page {
+            txid: pnode.txid(),
+            dirty: n.is_dirty(),
+            prefix: RcBytes::new(n.prefix()),
+            buckets: (0..n.bucket_count()).map(|i| page_bucket(n.stored_bucket(i))).collect(),
+            buffer: n.buffered().iter().map(page_bucket).collect(),
+            children: (0..n.child_count())
+                .map(|i| n.child(i).address().expect("child was not saved"))
+                .collect(),
+        }
+    }
+
+    /// The addresses of this page's chunked values.
+    pub fn chunk_addresses(&self) -> Vec<BlockAddress> {
+        self.buckets.iter().chain(&self.buffer).filter_map(|b| match b.value {
+            PageValue::Chunked(addr) => Some(addr),
+            PageValue::Inline(_) => None,
+        }).collect()
+    }
+
+    /// Rebuilds the node in this page, given its loaded children and chunks.
+    pub fn into_node(self, limits: NodeLimits, children: Vec<FatNodeRef>,
+        chunks: &HashMap<BlockAddress, Rc<Chunk>>) -> MemNode {
+        debug_assert_eq!(children.len(), self.children.len());
+        let bucket = |b: PageBucket| {
+            let mut r = match b.value {
+                _ if b.tombstone => BucketRef::transient_tombstone(&b.key),
+                PageValue::Chunked(addr) => BucketRef::transient_chunked(b.key, chunks[&addr].clone()),
+                PageValue::Inline(ref v) if b.delta => BucketRef::transient_delta(&b.key, v),
+                PageValue::Inline(v) => BucketRef::transient_from_rcbytes(b.key, v),
+            };
+            r.immute(b.txid);
+            r
+        };
+
+        let buckets = self.buckets.into_iter().map(&bucket).collect();
+        let buffer = self.buffer.into_iter().map(&bucket).collect();
+        MemNode::from_parts(limits, self.prefix, buckets, children, buffer, self.dirty)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let entries: Vec<_> = self.buckets.iter().chain(&self.buffer).collect();
+
+        let mut r = Vec::new();
+        r.extend_from_slice(MAGIC);
+        r.extend_from_slice(&PAGE_VERSION.to_be_bytes());
+        r.push(if self.dirty { DIRTY } else { 0 });
+        r.push(0);
+        r.extend_from_slice(&self.txid.to_bytes());
+        r.extend_from_slice(&(self.buckets.len() as u16).to_be_bytes());
+        r.extend_from_slice(&(self.buffer.len() as u16).to_be_bytes());
+        r.extend_from_slice(&(self.children.len() as u16).to_be_bytes());
+        r.extend_from_slice(&(self.prefix.len() as u32).to_be_bytes());
+        r.extend_from_slice(&self.prefix);
+
+        let mut offset = 0;
+        for b in &entries {
+            let flags = if b.tombstone { TOMBSTONE } else { 0 } | if b.delta { DELTA } else { 0 };
+            r.push(flags);
+            r.extend_from_slice(&b.txid.to_bytes());
+            r.extend_from_slice(&(offset as u32).to_be_bytes());
+            r.extend_from_slice(&(b.key.len() as u32).to_be_bytes());
+            offset += b.key.len();
+        }
+        for b in &entries {
+            r.extend_from_slice(&b.key);
+        }
+
+        for child in &self.children {
+            r.extend_from_slice(&(child.0).0.to_be_bytes());
+        }
+
+        let mut offset = 0;
+        for b in &entries {
+            match b.value {
+                PageValue::Inline(ref v) => {
+                    r.push(INLINE);
+                    r.extend_from_slice(&(offset as u64).to_be_bytes());
+                    r.extend_from_slice(&(v.len() as u32).to_be_bytes());
+                    offset += v.len();
+                }
+                PageValue::Chunked(addr) => {
+                    r.push(CHUNKED);
+                    r.extend_from_slice(&addr.0.to_be_bytes());
+                    r.extend_from_slice(&0u32.to_be_bytes());
+                }
+            }
+        }
+        for b in &entries {
+            if let PageValue::Inline(ref v) = b.value {
+                r.extend_from_slice(v);
+            }
+        }
+
+        r
+    }
+
+    pub fn decode(block: &[u8]) -> io::Result<Page> {
+        let mut r = PageReader { block: block, pos: 0 };
+
+        if r.take(4)? != &MAGIC[..] {
+            return Err(invalid("not a page"))
+        }
+        let version = r.u16()?;
+        if version > PAGE_VERSION {
+            return Err(invalid(&format!("unsupported page version {}", version)))
+        }
+        let dirty = r.u8()? & DIRTY != 0;
+        r.u8()?;
+        let txid = r.counter()?;
+        let bucket_count = r.u16()? as usize;
+        let buffer_count = r.u16()? as usize;
+        let child_count = r.u16()? as usize;
+        let prefix_len = r.u32()? as usize;
+        debug_assert_eq!(r.pos, HEADER_SIZE);
+        if child_count != 0 && child_count != bucket_count + 1 {
+            return Err(invalid(&format!("page with {} buckets has {} children", bucket_count, child_count)))
+        }
+        let prefix = RcBytes::new(r.take(prefix_len)?);
+
+        let entry_count = bucket_count + buffer_count;
+        let mut key_table = r.section(entry_count * KEY_ENTRY_SIZE)?;
+        let key_entries = (0..entry_count)
+            .map(|_| Ok((key_table.u8()?, key_table.counter()?, key_table.u32()? as usize, key_table.u32()? as usize)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let keys_len = key_entries.iter()
+            .try_fold(0, |max, &(_, _, off, len)| entry_end(off, len).map(|end| max.max(end)))?;
+        let keys = r.take(keys_len)?;
+
+        let mut child_table = r.section(child_count * 8)?;
+        let children = (0..child_count)
+            .map(|_| child_table.u64().map(|addr| NodeAddress(BlockAddress(addr))))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut value_table = r.section(entry_count * VALUE_ENTRY_SIZE)?;
+        let value_entries = (0..entry_count)
+            .map(|_| Ok((value_table.u8()?, value_table.u64()?, value_table.u32()? as usize)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let values_len = value_entries.iter()
+            .try_fold(0, |max, &(kind, off, len)| {
+                if kind == INLINE { entry_end(off as usize, len).map(|end| max.max(end)) } else { Ok(max) }
+            })?;
+        let values = r.take(values_len)?;
+
+        if r.pos != block.len() {
+            return Err(invalid("trailing bytes in page"))
+        }
+
+        let mut entries = key_entries.into_iter().zip(value_entries).map(|((flags, txid, key_off, key_len), value)| {
+            Ok(PageBucket {
+                key: RcBytes::new(&keys[key_off..key_off + key_len]),
+                value: match value {
+                    (INLINE, off, len) => PageValue::Inline(RcBytes::new(&values[off as usize..off as usize + len])),
+                    (CHUNKED, addr, _) => PageValue::Chunked(BlockAddress(addr)),
+                    (kind, _, _) => return Err(invalid(&format!("unknown value kind {}", kind))),
+                },
+                tombstone: flags & TOMBSTONE != 0,
+                delta: flags & DELTA != 0,
+                txid: txid,
+            })
+        }).collect::<io::Result<Vec<_>>>()?;
+        let buffer = entries.split_off(bucket_count);
+
+        Ok(Page {
+            txid: txid,
+            dirty: dirty,
+            prefix: prefix,
+            buckets: entries,
+            buffer: buffer,
+            children: children,
+        })
+    }
+}
+
+/// The root record of a saved or committed tree.
+pub struct RootRecord {
+    pub head: Option<NodeAddress>,
+    pub txid: Counter,
+    pub settings: TreeSettings,
+}
+
+/// The settings a tree's pages were written with. Pages only make sense to a tree with the same settings:
+/// buffered writes and deltas in them are ignored by trees without buffers or a merge operator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TreeSettings {
+    pub node_capacity: u16,
+    pub max_bytes: Option<usize>,
+    pub buffer_capacity: u16,
+    pub chunk_threshold: Option<usize>,
+    pub has_merge_operator: bool,
+}
+
+impl RootRecord {
+    pub fn encode(&self) -> Vec<u8> {
+        let settings = &self.settings;
+        let flags = [
+            (self.head.is_some(), HAS_HEAD),
+            (settings.max_bytes.is_some(), HAS_MAX_BYTES),
+            (settings.chunk_threshold.is_some(), HAS_CHUNK_THRESHOLD),
+            (settings.has_merge_operator, HAS_MERGE_OPERATOR),
+        ].iter().fold(0, |flags, &(set, flag)| if set { flags | flag } else { flags });
+
+        let mut r = Vec::with_capacity(ROOT_RECORD_SIZE);
+        r.push(flags);
+        r.extend_from_slice(&self.head.map_or(0, |addr| (addr.0).0).to_be_bytes());
+        r.extend_from_slice(&self.txid.to_bytes());
+        r.extend_from_slice(&settings.node_capacity.to_be_bytes());
+        r.extend_from_slice(&(settings.max_bytes.unwrap_or(0) as u64).to_be_bytes());
+        r.extend_from_slice(&settings.buffer_capacity.to_be_bytes());
+        r.extend_from_slice(&(settings.chunk_threshold.unwrap_or(0) as u64).to_be_bytes());
+        r
+    }
+
+    pub fn decode(block: &[u8]) -> io::Result<RootRecord> {
+        if block.len() != ROOT_RECORD_SIZE {
+            return Err(invalid("not a root record"))
+        }
+
+        let mut r = PageReader { block: block, pos: 0 };
+        let flags = r.u8()?;
+        let head = r.u64()?;
+        let txid = r.counter()?;
+        let node_capacity = r.u16()?;
+        let max_bytes = r.u64()? as usize;
+        let buffer_capacity = r.u16()?;
+        let chunk_threshold = r.u64()? as usize;
+        Ok(RootRecord {
+            head: if flags & HAS_HEAD != 0 { Some(NodeAddress(BlockAddress(head))) } else { None },
+            txid: txid,
+            settings: TreeSettings {
+                node_capacity: node_capacity,
+                max_bytes: if flags & HAS_MAX_BYTES != 0 { Some(max_bytes) } else { None },
+                buffer_capacity: buffer_capacity,
+                chunk_threshold: if flags & HAS_CHUNK_THRESHOLD != 0 { Some(chunk_threshold) } else { None },
+                has_merge_operator: flags & HAS_MERGE_OPERATOR != 0,
+            },
+        })
+    }
+}
+
+/// Gets the end of a key or value at the given offset, failing if it overflows.
+fn entry_end(off: usize, len: usize) -> io::Result<usize> {
+    off.checked_add(len).ok_or_else(|| invalid("page entry out of bounds"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+struct PageReader<'a> {
+    block: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PageReader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.block.len() - self.pos < len {
+            return Err(invalid("page is truncated"))
+        }
+
+        let r = &self.block[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(r)
+    }
+
+    /// Takes a reader over the next given number of bytes.
+    fn section(&mut self, len: usize) -> io::Result<PageReader<'a>> {
+        self.take(len).map(|block| PageReader { block: block, pos: 0 })
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let mut r = [0; 2];
+        r.copy_from_slice(self.take(2)?);
+        Ok(u16::from_be_bytes(r))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut r = [0; 4];
+        r.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(r))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut r = [0; 8];
+        r.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(r))
+    }
+
+    fn counter(&mut self) -> io::Result<Counter> {
+        let mut r = [0; 8];
+        r.copy_from_slice(self.take(8)?);
+        Ok(Counter::from_bytes(r))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use counter::Counter;
+    use data::RcBytes;
+    use storage::BlockAddress;
+
+    use super::*;
+
+    fn bucket(k: &str, value: PageValue, txid: u64) -> PageBucket {
+        PageBucket {
+            key: RcBytes::new(k.as_bytes()),
+            value: value,
+            tombstone: false,
+            delta: false,
+            txid: Counter::new(txid),
+        }
+    }
+
+    fn inline(v: &str) -> PageValue {
+        PageValue::Inline(RcBytes::new(v.as_bytes()))
+    }
+
+    fn summary(b: &PageBucket) -> (Vec<u8>, Result<Vec<u8>, u64>, bool, bool, [u8; 8]) {
+        let value = match b.value {
+            PageValue::Inline(ref v) => Ok(v.to_vec()),
+            PageValue::Chunked(addr) => Err(addr.0),
+        };
+        (b.key.to_vec(), value, b.tombstone, b.delta, b.txid.to_bytes())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut tombstone = bucket("c", inline(""), 5);
+        tombstone.tombstone = true;
+        let mut delta = bucket("e", inline("+1"), 6);
+        delta.delta = true;
+        let page = Page {
+            txid: Counter::new(7),
+            dirty: true,
+            prefix: RcBytes::new(&b"/a/"[..]),
+            buckets: vec![bucket("b", inline("x"), 3), bucket("d", PageValue::Chunked(BlockAddress(99)), 4)],
+            buffer: vec![tombstone, delta],
+            children: (10..13).map(|i| NodeAddress(BlockAddress(i))).collect(),
+        };
+
+        let decoded = Page::decode(&page.encode()).unwrap();
+        assert!(decoded.txid == page.txid);
+        assert!(decoded.dirty);
+        assert_eq!(&*decoded.prefix, b"/a/");
+        assert_eq!(decoded.buckets.iter().map(summary).collect::<Vec<_>>(),
+            page.buckets.iter().map(summary).collect::<Vec<_>>());
+        assert_eq!(decoded.buffer.iter().map(summary).collect::<Vec<_>>(),
+            page.buffer.iter().map(summary).collect::<Vec<_>>());
+        assert_eq!(decoded.children, page.children);
+        assert_eq!(decoded.chunk_addresses(), vec![BlockAddress(99)]);
+    }
+
+    #[test]
+    fn test_root_record_round_trip() {
+        for &(head, settings) in &[
+            (Some(NodeAddress(BlockAddress(300))), TreeSettings {
+                node_capacity: 16,
+                max_bytes: Some(4096),
+                buffer_capacity: 4,
+                chunk_threshold: Some(0),
+                has_merge_operator: true,
+            }),
+            (None, TreeSettings {
+                node_capacity: 4,
+                max_bytes: None,
+                buffer_capacity: 0,
+                chunk_threshold: None,
+                has_merge_operator: false,
+            }),
+        ] {
+            let record = RootRecord { head: head, txid: Counter::new(9), settings: settings };
+            let decoded = RootRecord::decode(&record.encode()).unwrap();
+            assert_eq!(decoded.head, head);
+            assert!(decoded.txid == record.txid);
+            assert_eq!(decoded.settings, settings);
+        }
+        assert!(RootRecord::decode(&[0; 19]).is_err());
+    }
+
+    #[test]
+    fn test_encoding_is_stable() {
+        let page = Page {
+            txid: Counter::new(0x0102),
+            dirty: false,
+            prefix: RcBytes::new(&b"p"[..]),
+            buckets: vec![bucket("k", inline("v"), 3)],
+            buffer: Vec::new(),
+            children: Vec::new(),
+        };
+
+        let expected: &[u8] = &[
+            b'H', b'T', b'P', b'G', 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, // magic, version, flags, txid
+            0, 1, 0, 0, 0, 0, 0, 0, 0, 1, b'p', // counts, prefix
+            0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 1, b'k', // key table, keys
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, b'v', // value table, values
+        ];
+        assert_eq!(&page.encode()[..], expected);
+    }
+
+    #[test]
+    fn test_rejects_bad_pages() {
+        let page = Page {
+            txid: Counter::new(1),
+            dirty: false,
+            prefix: RcBytes::new(&b""[..]),
+            buckets: vec![bucket("k", inline("v"), 1)],
+            buffer: Vec::new(),
+            children: Vec::new(),
+        };
+        let block = page.encode();
+
+        let mut bad_magic = block.clone();
+        bad_magic[0] = b'X';
+        let mut newer = block.clone();
+        newer[5] = 2;
+        let mut trailing = block.clone();
+        trailing.push(0);
+        // The value table follows the header, the key table and the key, and starts with the value's kind.
+        let value_off = HEADER_SIZE + KEY_ENTRY_SIZE + 1 + 1;
+        let mut overflowing = block.clone();
+        overflowing[value_off..value_off + 8].copy_from_slice(&[0xff; 8]);
+        let one_child = Page {
+            children: vec![NodeAddress(BlockAddress(2))],
+            ..page
+        }.encode();
+
+        for bad in &[bad_magic, newer, block[..block.len() - 1].to_vec(), trailing, overflowing, one_child] {
+            assert_eq!(Page::decode(bad).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -1,68 +1,65 @@
-// //! A test library for btrees.
-//
-// use std::borrow::*;
-// use std::collections::*;
-//
-// use rand::*;
-//
-// use data::*;
-// use tree::btree::*;
-//
-// /// A Testable is anything that has a name, can be set up, and can be torn down.
-// pub trait Testable {
-// 	fn name() -> String;
-// 	fn setup() -> Self;
-// 	fn teardown(self) -> ();
-// }
-//
-// impl Testable for PersistentBTree {
-// 	fn name() -> String {
-// 		String::from("PBTree")
-// 	}
-//
-// 	fn setup() -> Self {
-// 		Self::new()
-// 	}
-//
-// 	fn teardown(self) {
-// 		self.check_invariants();
-// 	}
-// }
-//
-// /// An empty struct Testable. Its name is "(n/a)".
-// pub struct DummyTestable {}
-//
-// impl Testable for DummyTestable {
-// 	fn name() -> String {
-// 		String::from("(n/a)")
-// 	}
-//
-// 	fn setup() -> Self {
-// 		DummyTestable {}
-// 	}
-//
-// 	fn teardown(self) {}
-// }
-//
-// // Alas, this macro is verbose, but it's the best we have
-// // (rust doesn't have gensym, dynamic idents, a stable testing interface, &c.)
-// #[macro_export]
-// macro_rules! deftests {
-// 	// TODO: what is $tr for?
-// 	{ $($testable:ty => { $($name:ident, $test:path,)* }, )* } => {
-//         $(
-//         	$(
-//                 #[test]
-//                 fn $name() {
-// 					let mut o = <$testable as Testable>::setup();
-// 					$test(&mut o);
-// 					o.teardown();
-//                 }
-//             )*
-//         )*
-//     };
-// }
-//
+//! A test library for btrees.
+
+use rand::*;
+
+use traits::*;
+use tree::btree::*;
+
+/// A Testable is anything that has a name, can be set up, and can be torn down.
+pub trait Testable {
+	fn name() -> String;
+	fn setup() -> Self;
+	fn teardown(self) -> ();
+}
+
+impl Testable for PersistentBTree {
+	fn name() -> String {
+		String::from("PBTree")
+	}
+
+	fn setup() -> Self {
+		Self::new()
+	}
+
+	fn teardown(self) {
+		self.check_invariants();
+	}
+}
+
+/// An empty struct Testable. Its name is "(n/a)".
+pub struct DummyTestable {}
+
+impl Testable for DummyTestable {
+	fn name() -> String {
+		String::from("(n/a)")
+	}
+
+	fn setup() -> Self {
+		DummyTestable {}
+	}
+
+	fn teardown(self) {}
+}
+
+// Alas, this macro is verbose, but it's the best we have
+// (rust doesn't have gensym, dynamic idents, a stable testing interface, &c.)
+#[macro_export]
+macro_rules! deftests {
+	// TODO: what is $tr for?
+	{ $($testable:ty => { $($name:ident, $test:path,)* }, )* } => {
+        $(
+        	$(
+                #[test]
+                fn $name() {
+					let mut o = <$testable as Testable>::setup();
+					$test(&mut o);
+					o.teardown();
+                }
+            )*
+        )*
+    };
+}
+
 // /// Convenience wrapper around a box of bytes.
 // #[derive(PartialEq, Eq, Hash, PartialOrd, Ord)]
 // pub struct ByteBox {
@@ -196,11 +193,11 @@
 // 	}
 // }
 //
-// /// Generates an Rng from a usize. This fn exists so tests can be consistent in their choice of rng.
-// pub fn rng(seed: usize) -> impl Rng {
-// 	StdRng::from_seed(&[seed])
-// }
-//
+/// Generates an Rng from a usize. This fn exists so tests can be consistent in their choice of rng.
+pub fn rng(seed: usize) -> impl Rng {
+	StdRng::from_seed(&[seed])
+}
+
 // /// Returns one million 8-byte strings.
 // pub fn random_byte_strings(seed: usize) -> Box<[[u8; 8]]> {
 // 	let mut x = rng(seed);
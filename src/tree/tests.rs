@@ -1,167 +1,1761 @@
-// use std::borrow::Borrow;
-// use std::ops::Deref;
-//
-// use data::*;
-// use super::testlib::*;
-// use super::btree::*;
-//
-// fn test_data<D: Datum>(v1: Option<&D>, v2: Option<&str>) {
-// 	// TODO: can we simplify this with API changes?
-// 	assert_eq!(v1.map(|x| x.box_copy()).as_ref().map(Deref::deref), v2.map(str::as_bytes));
-// }
-//
-// fn test_get_str<T: ByteMap>(t: &mut T, key: &str, val: Option<&str>) {
-// 	// borrow checker tricks
-// 	match t.get(key.as_bytes()) {
-// 		Some(b) => test_data(Some(b.borrow()), val),
-// 		None => test_data::<T::GetDatum>(None, val),
-// 	}
-// }
-//
-// fn smoke_test_insert<T: MutableByteMap>(t: &mut T) {
-// 	t.insert("foo".as_bytes(), &"bar".into_datum());
-// 	t.check_invariants();
-// 	t.insert("fop".as_bytes(), &"baz".into_datum());
-// 	t.check_invariants();
-// }
-//
-// fn smoke_test_get<T: MutableByteMap>(t: &mut T) {
-// 	t.insert("foo".as_bytes(), &"bar".into_datum());
-// 	test_get_str(t, "foo", Some("bar"));
-// 	test_get_str(t, "foooo", None);
-// 	test_get_str(t, "fop", None);
-// 	test_get_str(t, "fo", None);
-// 	test_get_str(t, "poo", None);
-// 	t.check_invariants();
-//
-// 	t.insert("fop".as_bytes(), &"baz".into_datum());
-// 	test_get_str(t, "foo", Some("bar"));
-// 	test_get_str(t, "foooo", None);
-// 	test_get_str(t, "fop", Some("baz"));
-// 	test_get_str(t, "fo", None);
-// 	test_get_str(t, "poo", None);
-// 	t.check_invariants();
-// }
-//
-// // fn smoke_test_delete<T: MutableByteMap>(t: &mut T) {
-// // 	t.insert("foo".as_bytes(), &"bar".into_datum());
-// // 	t.insert("sna".as_bytes(), &"foo".into_datum());
-// // 	t.insert("fop".as_bytes(), &"baz".into_datum());
-// // 	test_get_str(t, "foo", Some("bar"));
-// // 	test_get_str(t, "sna", Some("foo"));
-// // 	test_get_str(t, "fop", Some("baz"));
-// // 	t.check_invariants();
-//
-// // 	t.delete("sna".as_bytes());
-// // 	test_get_str(t, "foo", Some("bar"));
-// // 	test_get_str(t, "sna", None);
-// // 	test_get_str(t, "fop", Some("baz"));
-// // 	t.check_invariants();
-//
-// // 	t.delete("fop".as_bytes());
-// // 	test_get_str(t, "foo", Some("bar"));
-// // 	test_get_str(t, "sna", None);
-// // 	test_get_str(t, "fop", None);
-// // 	t.check_invariants();
-// // }
-//
-// fn smoke_test_snapshot<T: FunctionalByteMap>(t: &mut T) {
-// 	t.insert("foo".as_bytes(), &"bar".into_datum());
-// 	test_get_str(t, "foo", Some("bar"));
-// 	t.check_invariants();
-//
-// 	let mut t0 = t.snap();
-// 	test_get_str(t, "foo", Some("bar"));
-// 	test_get_str(&mut t0, "foo", Some("bar"));
-// 	t.check_invariants();
-// 	t0.check_invariants();
-//
-// 	t.insert("fop".as_bytes(), &"baz".into_datum());
-// 	test_get_str(t, "foo", Some("bar"));
-// 	test_get_str(&mut t0, "foo", Some("bar"));
-// 	test_get_str(t, "fop", Some("baz"));
-// 	test_get_str(&mut t0, "fop", None);
-// 	t.check_invariants();
-// 	t0.check_invariants();
-//
-// 	// TODO: test deletion
-// }
-//
-// fn smoke_test_diffs<T: FunctionalByteMap>(t: &mut T) {
-// 	t.insert("foo0".as_bytes(), &"bar0".into_datum());
-// 	let t0 = t.snap();
-// 	t.insert("foo1".as_bytes(), &"bar1".into_datum());
-// 	let t1 = t.snap();
-// 	t.insert("foo2".as_bytes(), &"bar2".into_datum());
-// 	let t2 = t.snap();
-// 	t.insert("foo3".as_bytes(), &"bar3".into_datum());
-//
-// 	let c1 = t1.txid();
-// 	let mut snap12 = t2.diff(c1);
-// 	test_get_str(&mut snap12, "foo0", None); // TODO: failing here. Whoops, it turns out buckets need ptrs.
-// 	test_get_str(&mut snap12, "foo1", None);
-// 	test_get_str(&mut snap12, "foo2", Some("bar2"));
-// 	test_get_str(&mut snap12, "foo3", None);
-//
-// 	let c0 = t0.txid();
-// 	let mut snap02 = t2.diff(c0);
-// 	test_get_str(&mut snap02, "foo0", None);
-// 	test_get_str(&mut snap02, "foo1", Some("bar1"));
-// 	test_get_str(&mut snap02, "foo2", Some("bar2"));
-// 	test_get_str(&mut snap02, "foo3", None);
-//
-// 	let mut snap01 = t1.diff(c0);
-// 	test_get_str(&mut snap01, "foo0", None);
-// 	test_get_str(&mut snap01, "foo1", Some("bar1"));
-// 	test_get_str(&mut snap01, "foo2", None);
-// 	test_get_str(&mut snap01, "foo3", None);
-//
-// 	// TODO: test deletion
-// }
-//
-// fn smoke_test_cursors<T: FunctionalByteMap>(t: &mut T) {
-// 	t.insert("foo".as_bytes(), &"bar".into_datum());
-// 	t.insert("sna".as_bytes(), &"foo".into_datum());
-// 	t.insert("fop".as_bytes(), &"baz".into_datum());
-//
-// 	// Test beginning cursors
-// 	let snap = t.snap();
-// 	let mut c = snap.start_cursor();
-//
-// 	test_data(c.key().as_ref(), Some("foo"));
-// 	test_data(c.value().as_ref().map(Borrow::borrow), Some("bar"));
-//
-// 	c.advance();
-// 	test_data(c.key().as_ref(), Some("fop"));
-// 	test_data(c.value().as_ref().map(Borrow::borrow), Some("baz"));
-//
-// 	c.advance();
-// 	test_data(c.key().as_ref(), Some("sna"));
-// 	test_data(c.value().as_ref().map(Borrow::borrow), Some("foo"));
-//
-// 	c.advance();
-// 	test_data(c.key().as_ref(), None);
-// 	test_data(c.value().as_ref().map(Borrow::borrow), None);
-// 	// Does modifying the tree affect the cursor?
-//
-// 	// Test middle cursors
-//
-// 	// TODO: test deletion
-// }
-//
-// // fn smoke_test_diff_cursors<T: FunctionalByteMap>(t: &mut T) {
-// // 	// TODO
-// // }
-//
+use std::collections::BTreeMap;
+use std::fs;
+use std::mem;
+use std::rc::Rc;
+
+use futures::Future;
+
+use rand::Rng;
+
+use data::Range;
+use storage::*;
+use tree::page::{NodeAddress, Page, RootRecord};
+use traits::*;
+use super::testlib::*;
+use super::btree::*;
+
+fn test_get_str(t: &PersistentBTree, key: &str, val: Option<&str>) {
+	assert_eq!(t.get(key).unwrap(), val.map(str::as_bytes));
+}
+
+/// Checks the given tree against a reference map, both by lookup and by iteration.
+fn test_against_reference(t: &PersistentBTree, reference: &BTreeMap<Vec<u8>, Vec<u8>>) {
+	for (k, v) in reference {
+		assert_eq!(t.get(k).unwrap(), Some(v.as_ref()));
+	}
+
+	let mut c = Tree::cursor(t, []).unwrap();
+	for (_, v) in reference {
+		assert!(c.exists());
+		assert_eq!(c.get(), v.as_slice());
+		c.next().unwrap();
+	}
+	assert!(!c.exists());
+}
+
+fn smoke_test_insert(t: &mut PersistentBTree) {
+	t.put("foo", "bar").unwrap();
+	t.check_invariants();
+	t.put("fop", "baz").unwrap();
+	t.check_invariants();
+}
+
+fn smoke_test_get(t: &mut PersistentBTree) {
+	t.put("foo", "bar").unwrap();
+	test_get_str(t, "foo", Some("bar"));
+	test_get_str(t, "foooo", None);
+	test_get_str(t, "fop", None);
+	test_get_str(t, "fo", None);
+	test_get_str(t, "poo", None);
+	t.check_invariants();
+
+	t.put("fop", "baz").unwrap();
+	test_get_str(t, "foo", Some("bar"));
+	test_get_str(t, "foooo", None);
+	test_get_str(t, "fop", Some("baz"));
+	test_get_str(t, "fo", None);
+	test_get_str(t, "poo", None);
+	t.check_invariants();
+}
+
+fn smoke_test_overwrite(t: &mut PersistentBTree) {
+	t.put("foo", "bar").unwrap();
+	t.put("fop", "baz").unwrap();
+	test_get_str(t, "foo", Some("bar"));
+	t.check_invariants();
+
+	t.put("foo", "sna").unwrap();
+	test_get_str(t, "foo", Some("sna"));
+	test_get_str(t, "fop", Some("baz"));
+	t.check_invariants();
+
+	t.put("foo", "").unwrap();
+	test_get_str(t, "foo", Some(""));
+	t.check_invariants();
+}
+
+fn test_overwrite_many(t: &mut PersistentBTree) {
+	let mut reference = BTreeMap::new();
+
+	// Enough keys that some live in branch nodes.
+	for i in 0..1000 as u32 {
+		let k = [(i >> 8) as u8, i as u8];
+		t.put(k, [0]).unwrap();
+		reference.insert(k.to_vec(), vec![0]);
+	}
+
+	for i in 0..1000 as u32 {
+		let k = [(i >> 8) as u8, i as u8];
+		let v = [1, i as u8];
+		t.put(k, v).unwrap();
+		reference.insert(k.to_vec(), v.to_vec());
+	}
+
+	t.check_invariants();
+	test_against_reference(t, &reference);
+}
+
+fn smoke_test_entry_mut(t: &mut PersistentBTree) {
+	t.put("foo", "bar").unwrap();
+	t.put("fop", "baz").unwrap();
+	assert!(t.entry_mut("fo").unwrap().is_none());
+
+	t.entry_mut("foo").unwrap().unwrap().set("sna");
+	test_get_str(t, "foo", Some("sna"));
+
+	t.get_mut("fop").unwrap().unwrap().copy_from_slice(b"bat");
+	test_get_str(t, "fop", Some("bat"));
+	test_get_str(t, "foo", Some("sna"));
+
+	// Values borrowed mutably stay valid across later writes through the same entry.
+	{
+		let mut e = t.entry_mut("fop").unwrap().unwrap();
+		e.get_mut()[0] = b'c';
+		e.set("xyz");
+		e.get_mut()[0] = b'w';
+		assert_eq!(e.get(), b"wyz");
+	}
+	t.entry_mut("fop").unwrap().unwrap().set("bat");
+
+	t.entry_mut("foo").unwrap().unwrap().delete().unwrap();
+	test_get_str(t, "foo", None);
+	test_get_str(t, "fop", Some("bat"));
+	t.check_invariants();
+}
+
+/// Updates and deletes keys in a single cursor pass.
+fn test_cursor_mut_scan(t: &mut PersistentBTree) {
+	let mut reference = BTreeMap::new();
+
+	for i in 0..1000 as u32 {
+		let k = [(i >> 8) as u8, i as u8];
+		t.put(k, k).unwrap();
+		reference.insert(k.to_vec(), k.to_vec());
+	}
+
+	{
+		let mut c = t.cursor_mut([]).unwrap();
+		let mut i = 0;
+
+		while c.exists() {
+			if i % 3 == 0 {
+				c.remove().unwrap();
+			} else {
+				if i % 2 == 0 {
+					c.set([2, i as u8]);
+				} else {
+					c.get_mut()[0] = 1;
+				}
+				c.next().unwrap();
+			}
+			i += 1;
+		}
+
+		assert_eq!(i, 1000);
+	}
+
+	for i in 0..1000 as u32 {
+		let k = [(i >> 8) as u8, i as u8];
+		if i % 3 == 0 {
+			reference.remove(k.as_ref());
+		} else if i % 2 == 0 {
+			reference.insert(k.to_vec(), vec![2, i as u8]);
+		} else {
+			reference.insert(k.to_vec(), vec![1, i as u8]);
+		}
+	}
+
+	t.check_invariants();
+	test_against_reference(t, &reference);
+}
+
+fn smoke_test_delete(t: &mut PersistentBTree) {
+	t.put("foo", "bar").unwrap();
+	t.put("sna", "foo").unwrap();
+	t.put("fop", "baz").unwrap();
+	test_get_str(t, "foo", Some("bar"));
+	test_get_str(t, "sna", Some("foo"));
+	test_get_str(t, "fop", Some("baz"));
+	t.check_invariants();
+
+	t.delete("sna").unwrap();
+	test_get_str(t, "foo", Some("bar"));
+	test_get_str(t, "sna", None);
+	test_get_str(t, "fop", Some("baz"));
+	t.check_invariants();
+
+	t.delete("fop").unwrap();
+	test_get_str(t, "foo", Some("bar"));
+	test_get_str(t, "sna", None);
+	test_get_str(t, "fop", None);
+	t.check_invariants();
+
+	// Deleting a missing key does nothing.
+	t.delete("fop").unwrap();
+	test_get_str(t, "foo", Some("bar"));
+	t.check_invariants();
+
+	t.delete("foo").unwrap();
+	test_get_str(t, "foo", None);
+	t.check_invariants();
+}
+
+fn test_delete_rebalance(t: &mut PersistentBTree) {
+	let mut x = rng(1);
+	let mut reference = BTreeMap::new();
+	let mut keys = Vec::new();
+
+	for _ in 0..2000 {
+		let mut k = [0 as u8; 8];
+		x.fill_bytes(&mut k);
+		t.put(k, k).unwrap();
+		reference.insert(k.to_vec(), k.to_vec());
+		keys.push(k);
+	}
+	t.check_invariants();
+	test_against_reference(t, &reference);
+
+	x.shuffle(&mut keys);
+
+	for (i, k) in keys.iter().enumerate() {
+		t.delete(k).unwrap();
+		reference.remove(k.as_ref());
+		assert_eq!(t.get(k).unwrap(), None);
+
+		if i % 100 == 0 {
+			t.check_invariants();
+			test_against_reference(t, &reference);
+		}
+	}
+
+	t.check_invariants();
+	test_against_reference(t, &reference);
+}
+
+/// Collects the values visible to a cursor, starting at the given key.
+fn cursor_values(t: &PersistentBTree, k: &str) -> Vec<Vec<u8>> {
+	let mut r = Vec::new();
+	let mut c = Tree::cursor(t, k).unwrap();
+
+	while c.exists() {
+		r.push(c.get().to_vec());
+		c.next().unwrap();
+	}
+
+	r
+}
+
+fn smoke_test_suffix(t: &mut PersistentBTree) {
+	t.put("foo", "bar").unwrap();
+	t.put("fop", "baz").unwrap();
+	t.put("sna", "foo").unwrap();
+	t.put("f", "f").unwrap();
+	t.put("e", "e").unwrap();
+
+	{
+		let s = t.suffix("fo");
+		test_get_str(&s, "o", Some("bar"));
+		test_get_str(&s, "p", Some("baz"));
+		test_get_str(&s, "foo", None);
+		test_get_str(&s, "sna", None);
+		test_get_str(&s, "", None);
+		assert_eq!(cursor_values(&s, ""), vec![b"bar".to_vec(), b"baz".to_vec()]);
+		assert_eq!(cursor_values(&s, "p"), vec![b"baz".to_vec()]);
+		assert_eq!(cursor_values(&s, "q"), Vec::<Vec<u8>>::new());
+
+		let s2 = s.suffix("p");
+		test_get_str(&s2, "", Some("baz"));
+		test_get_str(&s2, "p", None);
+		assert_eq!(cursor_values(&s2, ""), vec![b"baz".to_vec()]);
+
+		let s3 = t.suffix("");
+		test_get_str(&s3, "sna", Some("foo"));
+		assert_eq!(cursor_values(&s3, "").len(), 5);
+
+		assert_eq!(cursor_values(&t.suffix("x"), ""), Vec::<Vec<u8>>::new());
+	}
+
+	// Views are views, not copies.
+	t.put("fa", "fa").unwrap();
+	let mut s = t.suffix("f");
+	test_get_str(&s, "a", Some("fa"));
+	// Only views from suffix_mut are writable.
+	assert!(s.put("b", "x").is_err());
+	assert!(s.delete("a").is_err());
+	assert!(s.cursor_mut("").is_err());
+	assert!(s.suffix_mut("a").put("", "x").is_err());
+	test_get_str(&s, "", Some("f"));
+	assert_eq!(cursor_values(&s, "").len(), 4);
+	t.check_invariants();
+}
+
+fn test_suffix_many(t: &mut PersistentBTree) {
+	for i in 0..256 as u32 {
+		for j in 0..8 as u32 {
+			t.put([i as u8, j as u8], [i as u8, j as u8]).unwrap();
+		}
+	}
+
+	for i in 0..256 as u32 {
+		let s = t.suffix([i as u8]);
+		let expected: Vec<_> = (0..8).map(|j| vec![i as u8, j as u8]).collect();
+		assert_eq!(cursor_values(&s, ""), expected);
+		assert_eq!(s.get([3]).unwrap(), Some(&[i as u8, 3][..]));
+		assert_eq!(s.get([8]).unwrap(), None);
+	}
+
+	// 0xff prefixes have no upper bound.
+	let s = t.suffix([255, 7]);
+	assert_eq!(cursor_values(&s, ""), vec![vec![255, 7]]);
+}
+
+fn smoke_test_subrange(t: &mut PersistentBTree) {
+	t.put("a", "a").unwrap();
+	t.put("b", "b").unwrap();
+	t.put("ba", "ba").unwrap();
+	t.put("c", "c").unwrap();
+	t.put("d", "d").unwrap();
+
+	{
+		let r = t.subrange("b", "d");
+		test_get_str(&r, "a", None);
+		test_get_str(&r, "b", Some("b"));
+		test_get_str(&r, "c", Some("c"));
+		test_get_str(&r, "d", None);
+		assert_eq!(cursor_values(&r, ""), vec![b"b".to_vec(), b"ba".to_vec(), b"c".to_vec()]);
+		assert_eq!(cursor_values(&r, "bb"), vec![b"c".to_vec()]);
+		assert_eq!(cursor_values(&r, "d"), Vec::<Vec<u8>>::new());
+		r.check_invariants();
+
+		// Subranges of subranges are bounded by both.
+		let r2 = r.subrange("a", "c");
+		assert_eq!(cursor_values(&r2, ""), vec![b"b".to_vec(), b"ba".to_vec()]);
+		assert_eq!(cursor_values(&r.subrange("x", "y"), ""), Vec::<Vec<u8>>::new());
+
+		// Backwards or empty bounds make empty views.
+		for &(start, end) in &[("c", "b"), ("b", "b")] {
+			let e = t.subrange(start, end);
+			assert_eq!(cursor_values(&e, ""), Vec::<Vec<u8>>::new());
+			test_get_str(&e, "b", None);
+			e.check_invariants();
+		}
+
+		// Subranges of suffixes use the suffix's keys.
+		let b = t.suffix("b");
+		let s = b.subrange("", "b");
+		assert_eq!(cursor_values(&s, ""), vec![b"b".to_vec(), b"ba".to_vec()]);
+		test_get_str(&s, "a", Some("ba"));
+		test_get_str(&s, "b", None);
+	}
+
+	// Open and closed bounds.
+	let r = t.range(&Range::open(Box::from(&b"a"[..]), Box::from(&b"c"[..])));
+	assert_eq!(cursor_values(&r, ""), vec![b"b".to_vec(), b"ba".to_vec()]);
+	test_get_str(&r, "a", None);
+	let r = t.range(&Range::closed(Box::from(&b"ba"[..]), Box::from(&b"c"[..])));
+	assert_eq!(cursor_values(&r, ""), vec![b"ba".to_vec(), b"c".to_vec()]);
+	test_get_str(&r, "c", Some("c"));
+
+	// Views are views, not copies.
+	t.put("bb", "bb").unwrap();
+	let mut r = t.subrange("b", "d");
+	test_get_str(&r, "bb", Some("bb"));
+	assert!(r.put("bc", "x").is_err());
+	assert!(r.entry_mut("bb").is_err());
+	t.check_invariants();
+}
+
+fn test_subrange_many(t: &mut PersistentBTree) {
+	let mut rng = rng(4);
+	let mut reference = BTreeMap::new();
+
+	for _ in 0..2000 {
+		let k: Vec<u8> = (0..3).map(|_| rng.gen()).collect();
+		t.put(&k, &k).unwrap();
+		reference.insert(k.clone(), k);
+	}
+
+	for _ in 0..50 {
+		let mut start: Vec<u8> = (0..2).map(|_| rng.gen()).collect();
+		let mut end: Vec<u8> = (0..2).map(|_| rng.gen()).collect();
+		if start > end {
+			::std::mem::swap(&mut start, &mut end);
+		}
+		end.push(0);
+
+		let r = t.subrange(&start, &end);
+		let expected: BTreeMap<_, _> = reference.range(start.clone()..end.clone())
+			.map(|(k, v)| (k.clone(), v.clone())).collect();
+		test_against_reference(&r, &expected);
+		r.check_invariants();
+
+		for (k, _) in reference.iter().take(20) {
+			assert_eq!(r.get(k).unwrap().is_some(), expected.contains_key(k));
+		}
+	}
+}
+
+fn smoke_test_suffix_mut(t: &mut PersistentBTree) {
+	t.put("foo", "bar").unwrap();
+
+	{
+		let mut s = t.suffix_mut("f");
+		s.put("oo", "baz").unwrap();
+		s.put("un", "fun").unwrap();
+		s.entry_mut("un").unwrap().unwrap().set("fin");
+		test_get_str(&s, "oo", Some("baz"));
+		s.check_invariants();
+
+		let mut s2 = s.suffix_mut("o");
+		s2.put("p", "fop").unwrap();
+		s2.delete("o").unwrap();
+	}
+
+	test_get_str(t, "foo", None);
+	test_get_str(t, "fop", Some("fop"));
+	test_get_str(t, "fun", Some("fin"));
+	test_get_str(t, "un", None);
+	t.check_invariants();
+}
+
+fn smoke_test_subrange_mut(t: &mut PersistentBTree) {
+	t.put("a", "a").unwrap();
+	t.put("e", "e").unwrap();
+
+	{
+		let mut r = t.subrange_mut("b", "d");
+		r.put("b", "b").unwrap();
+		r.put("cc", "cc").unwrap();
+		assert!(r.put("a", "x").is_err());
+		assert!(r.put("d", "x").is_err());
+		assert!(r.delete("e").is_err());
+		assert_eq!(cursor_values(&r, ""), vec![b"b".to_vec(), b"cc".to_vec()]);
+
+		// Writes through a view of a view are bounded by both views.
+		let mut r2 = r.suffix_mut("c");
+		r2.put("d", "cd").unwrap();
+		assert!(r2.put("", "c").is_ok());
+		assert!(r.suffix_mut("d").put("", "x").is_err());
+
+		let mut c = r.cursor_mut("").unwrap();
+		c.remove().unwrap();
+		assert_eq!(c.get(), b"c");
+	}
+	assert!(t.suffix_mut("d").put("", "x").is_ok());
+
+	assert_eq!(cursor_values(t, ""), vec![b"a".to_vec(), b"c".to_vec(), b"cc".to_vec(),
+		b"cd".to_vec(), b"x".to_vec(), b"e".to_vec()]);
+	t.check_invariants();
+}
+
+fn smoke_test_snapshot(t: &mut PersistentBTree) {
+	t.put("foo", "bar").unwrap();
+	test_get_str(t, "foo", Some("bar"));
+	t.check_invariants();
+
+	let t0 = t.persistent();
+	test_get_str(t, "foo", Some("bar"));
+	test_get_str(&t0, "foo", Some("bar"));
+	t.check_invariants();
+	t0.check_invariants();
+
+	t.put("fop", "baz").unwrap();
+	test_get_str(t, "foo", Some("bar"));
+	test_get_str(&t0, "foo", Some("bar"));
+	test_get_str(t, "fop", Some("baz"));
+	test_get_str(&t0, "fop", None);
+	t.check_invariants();
+	t0.check_invariants();
+
+	let t1 = t.persistent();
+	t.delete("foo").unwrap();
+	t.entry_mut("fop").unwrap().unwrap().get_mut()[0] = b'z';
+	test_get_str(t, "foo", None);
+	test_get_str(t, "fop", Some("zaz"));
+	test_get_str(&t0, "foo", Some("bar"));
+	test_get_str(&t1, "foo", Some("bar"));
+	test_get_str(&t1, "fop", Some("baz"));
+	t.check_invariants();
+	t1.check_invariants();
+
+	// Snapshots are immutable, and their clones are snapshots.
+	let mut t2 = t1.clone();
+	assert!(t2.put("foo", "x").is_err());
+	assert!(t2.delete("foo").is_err());
+	assert!(t2.entry_mut("foo").is_err());
+	test_get_str(&t2, "foo", Some("bar"));
+
+	// Transients of snapshots are copy-on-write.
+	let mut t3 = t1.transient();
+	t3.put("foo", "qux").unwrap();
+	t3.put("sna", "foo").unwrap();
+	test_get_str(&t3, "foo", Some("qux"));
+	test_get_str(&t1, "foo", Some("bar"));
+	test_get_str(&t1, "sna", None);
+	test_get_str(t, "sna", None);
+	t3.check_invariants();
+	t1.check_invariants();
+}
+
+fn test_snapshot_many(t: &mut PersistentBTree) {
+	let mut rng = rng(5);
+	let mut reference = BTreeMap::new();
+	let mut snapshots = Vec::new();
+
+	for i in 0..1000 {
+		let k: Vec<u8> = (0..2).map(|_| rng.gen()).collect();
+		if rng.gen() {
+			t.put(&k, [i as u8]).unwrap();
+			reference.insert(k, vec![i as u8]);
+		} else {
+			t.delete(&k).unwrap();
+			reference.remove(&k);
+		}
+
+		if i % 100 == 0 {
+			snapshots.push((t.persistent(), reference.clone()));
+		}
+	}
+
+	for &(ref snapshot, ref expected) in &snapshots {
+		test_against_reference(snapshot, expected);
+		snapshot.check_invariants();
+	}
+	test_against_reference(t, &reference);
+}
+
+fn smoke_test_diffs(t: &mut PersistentBTree) {
+	t.put("foo0", "bar0").unwrap();
+	let t0 = t.persistent();
+	t.put("foo1", "bar1").unwrap();
+	let t1 = t.persistent();
+	t.put("foo2", "bar2").unwrap();
+	let t2 = t.persistent();
+	t.put("foo3", "bar3").unwrap();
+
+	let c1 = t1.counter();
+	let snap12 = t2.diff(c1);
+	test_get_str(&snap12, "foo0", None);
+	test_get_str(&snap12, "foo1", None);
+	test_get_str(&snap12, "foo2", Some("bar2"));
+	test_get_str(&snap12, "foo3", None);
+
+	let c0 = t0.counter();
+	let snap02 = t2.diff(c0);
+	test_get_str(&snap02, "foo0", None);
+	test_get_str(&snap02, "foo1", Some("bar1"));
+	test_get_str(&snap02, "foo2", Some("bar2"));
+	test_get_str(&snap02, "foo3", None);
+	assert_eq!(cursor_values(&snap02, ""), vec![b"bar1".to_vec(), b"bar2".to_vec()]);
+	assert_eq!(cursor_values(&snap02.suffix("foo2"), ""), vec![b"bar2".to_vec()]);
+
+	let snap01 = t1.diff(c0);
+	test_get_str(&snap01, "foo0", None);
+	test_get_str(&snap01, "foo1", Some("bar1"));
+	test_get_str(&snap01, "foo2", None);
+	test_get_str(&snap01, "foo3", None);
+
+	// Overwrites are changes.
+	t.put("foo0", "baz0").unwrap();
+	let t3 = t.persistent();
+	let snap23 = t3.diff(t2.counter());
+	assert_eq!(cursor_values(&snap23, ""), vec![b"baz0".to_vec(), b"bar3".to_vec()]);
+
+	// Diffs are snapshots.
+	let mut snap23 = snap23;
+	assert!(snap23.put("foo4", "bar4").is_err());
+
+	// Diffs of transient trees include unfrozen writes.
+	t.put("foo4", "bar4").unwrap();
+	assert_eq!(cursor_values(&t.diff(t3.counter()), ""), vec![b"bar4".to_vec()]);
+	assert_eq!(cursor_values(&t.diff(t.counter()), ""), vec![b"bar4".to_vec()]);
+
+	// Diffing a transient tree doesn't snapshot it, and later writes don't reach the diff.
+	let c = t.counter();
+	let snap34 = t.diff(t3.counter());
+	assert!(t.counter() == c);
+	t.put("foo5", "bar5").unwrap();
+	t.check_invariants();
+	assert_eq!(cursor_values(&snap34, ""), vec![b"bar4".to_vec()]);
+	assert_eq!(cursor_values(&t.diff(t3.counter()), ""), vec![b"bar4".to_vec(), b"bar5".to_vec()]);
+}
+
+fn test_diffs_many(t: &mut PersistentBTree) {
+	let mut rng = rng(6);
+
+	for i in 0..1000 as u32 {
+		t.put([(i >> 8) as u8, i as u8], [0]).unwrap();
+	}
+
+	let base = t.persistent();
+	let mut changed = BTreeMap::new();
+	for _ in 0..100 {
+		let k = vec![rng.gen_range(0, 4), rng.gen()];
+		t.put(&k, [1]).unwrap();
+		changed.insert(k, vec![1]);
+	}
+
+	let diff = t.diff(base.counter());
+	test_against_reference(&diff, &changed);
+	diff.check_invariants();
+}
+
+/// Makes one snapshot each time something is inserted, like bench_snapshots_frequent.
+fn test_snapshots_frequent(t: &mut PersistentBTree) {
+	let mut rng = rng(8);
+	let ks: Vec<Vec<u8>> = (0..500).map(|_| (0..4).map(|_| rng.gen()).collect()).collect();
+	let mut snapvec = Vec::with_capacity(ks.len());
+
+	for i in 0..ks.len() {
+		t.put(&ks[i], [i as u8]).unwrap();
+		// Snap number i contains keys 0..i
+		snapvec.push(t.persistent());
+
+		let idx = rng.gen_range(0, ks.len());
+		let snapid = rng.gen_range(0, snapvec.len());
+		let expected = if idx <= snapid { Some(&[idx as u8][..]) } else { None };
+		assert_eq!(snapvec[snapid].get(&ks[idx]).unwrap(), expected);
+	}
+
+	for (i, snap) in snapvec.iter().enumerate() {
+		assert!(Tree::cursor(snap, []).unwrap().exists());
+		assert_eq!(snap.get(&ks[i]).unwrap(), Some(&[i as u8][..]));
+	}
+	snapvec.last().unwrap().check_invariants();
+}
+
+fn smoke_test_merge(t: &mut PersistentBTree) {
+	for k in &["a", "b", "c", "d", "e", "f"] {
+		t.put(k, "base").unwrap();
+	}
+	let base = t.persistent();
+
+	let mut left = base.transient();
+	left.put("a", "left").unwrap();
+	left.put("c", "same").unwrap();
+	left.put("d", "left").unwrap();
+	left.delete("e").unwrap();
+	left.put("x", "left").unwrap();
+
+	let mut right = base.transient();
+	right.put("b", "right").unwrap();
+	right.put("c", "same").unwrap();
+	right.put("d", "right").unwrap();
+	right.delete("f").unwrap();
+	right.put("e", "right").unwrap();
+
+	let mut conflicts = Vec::new();
+	let merged = PersistentBTree::merge(&base, &left, &right, |k, b, l, r| {
+		conflicts.push(k.to_vec());
+		assert_eq!(b, Some(&b"base"[..]));
+		Ok(Some([l.unwrap_or(b"-"), r.unwrap_or(b"-")].concat()))
+	}).unwrap();
+
+	assert_eq!(conflicts, vec![b"d".to_vec(), b"e".to_vec()]);
+	test_get_str(&merged, "a", Some("left"));
+	test_get_str(&merged, "b", Some("right"));
+	test_get_str(&merged, "c", Some("same"));
+	test_get_str(&merged, "d", Some("leftright"));
+	test_get_str(&merged, "e", Some("-right"));
+	test_get_str(&merged, "f", None);
+	test_get_str(&merged, "x", Some("left"));
+	merged.check_invariants();
+
+	// The inputs are unchanged.
+	test_get_str(&left, "b", Some("base"));
+	test_get_str(&right, "a", Some("base"));
+	test_get_str(&base, "d", Some("base"));
+
+	// Resolvers may delete keys or fail the merge.
+	let merged = PersistentBTree::merge(&base, &left, &right, |_, _, _, _| Ok(None)).unwrap();
+	test_get_str(&merged, "d", None);
+	test_get_str(&merged, "e", None);
+	assert!(PersistentBTree::merge(&base, &left, &right,
+		|_, _, _, _| Err(TreeError::RuntimeError(String::from("conflict")))).is_err());
+	assert!(PersistentBTree::merge(&left, &left, &right, |_, _, _, _| Ok(None)).is_err());
+
+	// Merging doesn't snapshot transient inputs.
+	let c = left.counter();
+	PersistentBTree::merge(&base, &left, &right, |_, _, _, _| Ok(None)).unwrap();
+	assert!(left.counter() == c);
+	left.put("y", "left").unwrap();
+	left.check_invariants();
+
+	// Both sides must be forked from the base.
+	let forked = base.transient().persistent();
+	t.put("g", "newer").unwrap();
+	let newer = t.persistent();
+	assert!(PersistentBTree::merge(&newer, &left, &right, |_, _, _, _| Ok(None)).is_err());
+	assert!(PersistentBTree::merge(&base, &left, &PersistentBTree::new(), |_, _, _, _| Ok(None)).is_err());
+	assert!(PersistentBTree::merge(&base, &forked, &right, |_, _, _, _| Ok(None)).is_ok());
+}
+
+fn test_merge_many(t: &mut PersistentBTree) {
+	let mut rng = rng(9);
+	let mut reference = BTreeMap::new();
+
+	for _ in 0..2000 {
+		let k: Vec<u8> = (0..2).map(|_| rng.gen()).collect();
+		t.put(&k, [0]).unwrap();
+		reference.insert(k, vec![0]);
+	}
+	let base = t.persistent();
+
+	// Left writes even keys, right writes odd keys, so there are no conflicts.
+	let mut forks = vec![(base.transient(), 0), (base.transient(), 1)];
+	for &mut (ref mut fork, parity) in &mut forks {
+		for _ in 0..300 {
+			let k = vec![rng.gen(), rng.gen::<u8>() & !1 | parity];
+			if rng.gen() {
+				fork.put(&k, [parity + 1]).unwrap();
+				reference.insert(k, vec![parity + 1]);
+			} else {
+				fork.delete(&k).unwrap();
+				reference.remove(&k);
+			}
+		}
+	}
+
+	let merged = PersistentBTree::merge(&base, &forks[0].0, &forks[1].0, |_, _, _, _| panic!("conflict")).unwrap();
+	test_against_reference(&merged, &reference);
+	merged.check_invariants();
+}
+
+#[test]
+fn test_node_capacities() {
+	for &capacity in &[4, 6, 64] {
+		let mut rng = rng(10);
+		let mut t = PersistentBTree::with_node_capacity(capacity);
+		let mut reference = BTreeMap::new();
+		let mut snapshots = Vec::new();
+
+		for i in 0..3000 {
+			let k: Vec<u8> = (0..2).map(|_| rng.gen()).collect();
+			if rng.gen_weighted_bool(3) {
+				t.delete(&k).unwrap();
+				reference.remove(&k);
+			} else {
+				t.put(&k, &k).unwrap();
+				reference.insert(k.clone(), k);
+			}
+
+			if i % 500 == 0 {
+				snapshots.push((t.persistent(), reference.clone()));
+			}
+		}
+
+		assert_eq!(t.node_capacity(), capacity);
+		test_against_reference(&t, &reference);
+		t.check_invariants();
+		for (snapshot, reference) in snapshots {
+			assert_eq!(snapshot.node_capacity(), capacity);
+			test_against_reference(&snapshot, &reference);
+			snapshot.check_invariants();
+		}
+	}
+}
+
+#[test]
+fn test_node_byte_limits() {
+	let mut rng = rng(11);
+	let mut t = PersistentBTree::with_node_limits(16, Some(256));
+	let mut reference = BTreeMap::new();
+	let mut snapshots = Vec::new();
+
+	for i in 0..3000 {
+		let k: Vec<u8> = (0..2).map(|_| rng.gen()).collect();
+		// Mostly small values, with the occasional value larger than a node.
+		let len = if rng.gen_weighted_bool(20) { rng.gen_range(256, 1024) } else { rng.gen_range(0, 32) };
+		let v = vec![k[0]; len];
+
+		if rng.gen_weighted_bool(3) {
+			t.delete(&k).unwrap();
+			reference.remove(&k);
+		} else if rng.gen_weighted_bool(4) {
+			// Overwrite in place through a cursor.
+			if let Some(mut e) = t.entry_mut(&k).unwrap() {
+				e.set(&v);
+				reference.insert(k, v);
+			}
+		} else {
+			t.put(&k, &v).unwrap();
+			reference.insert(k, v);
+		}
+
+		if i % 100 == 0 {
+			t.check_invariants();
+		}
+		if i % 500 == 0 {
+			snapshots.push((t.persistent(), reference.clone()));
+		}
+	}
+
+	assert_eq!(t.node_max_bytes(), Some(256));
+	test_against_reference(&t, &reference);
+	for (snapshot, reference) in snapshots {
+		test_against_reference(&snapshot, &reference);
+		snapshot.check_invariants();
+	}
+
+	// Deleting branch buckets replaces them with predecessors, which may put the branch over the limit,
+	// and shrinking values may leave nodes deficient.
+	for i in 0..3000 {
+		let k = (rng.gen_range(0, 500) as u16).to_be_bytes().to_vec();
+		let len = if rng.gen_weighted_bool(4) { rng.gen_range(64, 512) } else { rng.gen_range(0, 8) };
+
+		if rng.gen() {
+			t.delete(&k).unwrap();
+			reference.remove(&k);
+		} else if let Some(mut e) = t.entry_mut(&k).unwrap() {
+			e.set(&vec![0; len]);
+			reference.insert(k, vec![0; len]);
+		} else {
+			t.put(&k, vec![1; len]).unwrap();
+			reference.insert(k, vec![1; len]);
+		}
+
+		if i % 10 == 0 {
+			t.check_invariants();
+		}
+	}
+	test_against_reference(&t, &reference);
+}
+
+#[test]
+fn test_chunked_values() {
+	let mut rng = rng(13);
+	let mut t = PersistentBTree::with_node_capacity(8).with_chunk_threshold(64);
+	let mut reference = BTreeMap::new();
+	let mut snapshots = Vec::new();
+
+	t.put(b"chunked", &[1; 64][..]).unwrap();
+	t.entry_mut(b"chunked").unwrap().unwrap().get_mut()[0] = 2;
+	assert_eq!(t.get(b"chunked").unwrap().unwrap()[..2], [2, 1]);
+	assert_eq!((t.chunk_count(), t.chunk_bytes()), (1, 64));
+	{
+		// Chunks shared with a diff are copied on write.
+		let diff = t.diff(t.counter());
+		t.entry_mut(b"chunked").unwrap().unwrap().get_mut()[0] = 3;
+		assert_eq!(diff.get(b"chunked").unwrap().unwrap()[..2], [2, 1]);
+		assert_eq!(t.get(b"chunked").unwrap().unwrap()[..2], [3, 1]);
+	}
+	t.delete(b"chunked").unwrap();
+	assert_eq!(t.chunk_count(), 0);
+
+	for i in 0..3000 {
+		let k: Vec<u8> = (0..2).map(|_| rng.gen()).collect();
+		let len = if rng.gen_weighted_bool(4) { rng.gen_range(64, 512) } else { rng.gen_range(0, 64) };
+		let v = vec![k[0]; len];
+
+		if rng.gen_weighted_bool(3) {
+			t.delete(&k).unwrap();
+			reference.remove(&k);
+		} else if rng.gen_weighted_bool(4) {
+			if let Some(mut e) = t.entry_mut(&k).unwrap() {
+				if rng.gen() {
+					e.set(&v);
+					reference.insert(k, v);
+				} else {
+					for x in e.get_mut() {
+						*x = x.wrapping_add(1);
+					}
+					let rv = reference.get_mut(&k).unwrap();
+					for x in rv.iter_mut() {
+						*x = x.wrapping_add(1);
+					}
+				}
+			}
+		} else {
+			t.put(&k, &v).unwrap();
+			reference.insert(k, v);
+		}
+
+		if i % 500 == 0 {
+			// Snapshots share chunks with the tree.
+			let chunks = t.chunk_count();
+			snapshots.push((t.persistent(), reference.clone()));
+			assert_eq!(t.chunk_count(), chunks);
+			t.check_invariants();
+		}
+	}
+
+	test_against_reference(&t, &reference);
+	for (snapshot, reference) in snapshots {
+		test_against_reference(&snapshot, &reference);
+	}
+
+	// Once the snapshots are gone, only chunks the tree can see stay alive.
+	let large: Vec<_> = reference.values().filter(|v| v.len() >= 64).collect();
+	assert_eq!(t.chunk_count(), large.len());
+	assert_eq!(t.chunk_bytes(), large.iter().map(|v| v.len()).sum::<usize>());
+}
+
+#[test]
+fn test_buffered_writes() {
+	let mut rng = rng(14);
+	// Small nodes and buffers, so writes are flushed through several levels.
+	let mut t = PersistentBTree::with_node_capacity(4).with_buffer_capacity(4);
+	let mut reference = BTreeMap::new();
+	let mut snapshots = Vec::new();
+
+	for i in 0..3000 {
+		let k: Vec<u8> = (0..2).map(|_| rng.gen()).collect();
+		if rng.gen_weighted_bool(3) {
+			t.delete(&k).unwrap();
+			reference.remove(&k);
+		} else {
+			let v = vec![rng.gen()];
+			t.put(&k, &v).unwrap();
+			reference.insert(k, v);
+		}
+
+		// Lookups see writes still buffered in branches, without flushing them.
+		let k: Vec<u8> = (0..2).map(|_| rng.gen()).collect();
+		assert_eq!(t.get(&k).unwrap(), reference.get(&k).map(Vec::as_slice));
+
+		if i % 300 == 0 {
+			t.check_invariants();
+			snapshots.push((t.persistent(), reference.clone()));
+		}
+	}
+
+	// Entries pointing to buffered writes can still advance.
+	t.put([0x80, 0x80], [1]).unwrap();
+	reference.insert(vec![0x80, 0x80], vec![1]);
+	{
+		let mut e = t.entry([0x80, 0x80]).unwrap().unwrap();
+		e.next().unwrap();
+		assert_eq!(e.key(), reference.range(vec![0x80, 0x81]..).next().map(|(k, _)| k.clone()));
+	}
+
+	// Diffs see buffered writes too.
+	let base = t.persistent();
+	let mut changed = BTreeMap::new();
+	for _ in 0..100 {
+		let k: Vec<u8> = (0..2).map(|_| rng.gen()).collect();
+		t.put(&k, [2]).unwrap();
+		changed.insert(k.clone(), vec![2]);
+		reference.insert(k, vec![2]);
+	}
+	test_against_reference(&t.diff(base.counter()), &changed);
+
+	test_against_reference(&t, &reference);
+	t.compact().unwrap();
+	t.check_invariants();
+	test_against_reference(&t, &reference);
+	for (snapshot, reference) in snapshots {
+		test_against_reference(&snapshot, &reference);
+		snapshot.check_invariants();
+	}
+
+	// Without snapshots, buffered deletes remove their keys once flushed, rather than leaving tombstones.
+	let mut t = PersistentBTree::with_node_capacity(4).with_buffer_capacity(4);
+	for i in 0..2000 as u32 {
+		t.put(i.to_be_bytes(), [0]).unwrap();
+	}
+	for i in 0..2000 as u32 {
+		t.delete(i.to_be_bytes()).unwrap();
+		if i % 500 == 0 {
+			t.check_invariants();
+		}
+	}
+	assert_eq!(t.compact().unwrap(), 0);
+	assert_eq!(cursor_values(&t, ""), Vec::<Vec<u8>>::new());
+	t.check_invariants();
+
+	// Buffered writes to branch buckets, or to keys that shorten a branch's prefix, can grow a branch past
+	// its byte limit. They split it like unbuffered writes do.
+	let mut t = PersistentBTree::with_node_limits(4, Some(64)).with_buffer_capacity(2);
+	let mut reference = BTreeMap::new();
+	for i in 0..3000 {
+		// Keys share long prefixes, so branches compress them away, and mostly ascend, so writes past
+		// the rightmost branch shorten its prefix.
+		let k = if rng.gen_weighted_bool(4) {
+			format!("{:08}", rng.gen_range(0, i + 1)).into_bytes()
+		} else {
+			format!("{:08}", i).into_bytes()
+		};
+		if rng.gen_weighted_bool(4) {
+			t.delete(&k).unwrap();
+			reference.remove(&k);
+		} else {
+			let v = vec![i as u8; rng.gen_range(0, 24)];
+			t.put(&k, &v).unwrap();
+			reference.insert(k, v);
+		}
+
+		if i % 100 == 0 {
+			t.check_invariants();
+		}
+	}
+	t.check_invariants();
+	test_against_reference(&t, &reference);
+}
+
+#[test]
+fn test_alloc_values() {
+	use std::io::Write;
+	use alloc::ScopedMut;
+
+	// Inline and chunked values, with buffered writes flushed through several levels.
+	let mut t = PersistentBTree::with_node_capacity(4).with_buffer_capacity(4).with_chunk_threshold(64);
+	let mut reference = BTreeMap::new();
+	let mut ptrs = Vec::new();
+
+	for i in 0..300u32 {
+		let k = format!("{:04}", i).into_bytes();
+		let mut kbuf = t.alloc_key(4);
+		kbuf.writer().write_all(&k).unwrap();
+		let mut v = t.alloc_value(if i % 3 == 0 { 100 } else { 12 });
+		write!(v.writer(), "value {}", i).unwrap();
+		{
+			let bytes: &mut [u8] = v.get_mut().unwrap();
+			bytes[0] = b'V';
+		}
+		let expected = v.as_ref().to_vec();
+		ptrs.push((k.clone(), v.as_ref().as_ptr()));
+
+		t.put_alloc(kbuf, v).unwrap();
+		reference.insert(k, expected);
+	}
+
+	// The tree holds the very bytes the caller filled.
+	let snapshot = t.persistent();
+	for (k, p) in ptrs {
+		assert_eq!(t.get(&k).unwrap().unwrap().as_ptr(), p);
+		assert_eq!(snapshot.get(&k).unwrap().unwrap().as_ptr(), p);
+	}
+
+	test_against_reference(&t, &reference);
+	assert_eq!(t.get("0003").unwrap().unwrap()[..9], b"Value 3\0\0"[..]);
+	assert_eq!(t.chunk_count(), 100);
+	t.check_invariants();
+
+	// Buffers only go into the transaction that allocated them.
+	let other = PersistentBTree::new();
+	assert!(t.put_alloc(other.alloc_key(1), t.alloc_value(1)).is_err());
+	assert!(t.put_alloc(t.alloc_key(1), other.alloc_value(1)).is_err());
+	let (stale_key, stale_value) = (t.alloc_key(1), t.alloc_value(1));
+	let _snapshot = t.persistent();
+	assert!(t.put_alloc(stale_key, stale_value).is_err());
+
+	// Keys allocated by views start with their prefix. Writes through views are bounds-checked like any other put.
+	let outer_key = t.alloc_key(2);
+	{
+		let mut view = t.suffix_mut("01");
+		let mut k = view.alloc_key(2);
+		assert_eq!(k.given(), b"01");
+		k.writer().write_all(b"99").unwrap();
+		let v = view.alloc_value(1);
+		assert!(view.put_alloc(k, v).is_ok());
+		let v = view.alloc_value(1);
+		assert!(view.put_alloc(outer_key, v).is_err());
+	}
+	test_get_str(&t, "0199", Some("\0"));
+	let mut range = t.subrange_mut("1", "2");
+	let mut k = range.alloc_key(4);
+	k.writer().write_all(b"0500").unwrap();
+	let v = range.alloc_value(1);
+	assert!(range.put_alloc(k, v).is_err());
+}
+
+fn save_load_tree() -> PersistentBTree {
+	PersistentBTree::with_node_capacity(4).with_buffer_capacity(4).with_chunk_threshold(64)
+}
+
+/// Gets the head of the tree saved at the given address.
+fn saved_head<S: BlockStore + 'static>(store: &Rc<S>, addr: BlockAddress) -> Option<NodeAddress> {
+	RootRecord::decode(&store.get(addr).wait().unwrap()).unwrap().head
+}
+
+/// Writes random keys to a new tree, saving a snapshot to the given store every 500 writes.
+fn save_snapshots<S: BlockStore + 'static>(store: &Rc<S>)
+	-> Vec<(PersistentBTree, BlockAddress, BTreeMap<Vec<u8>, Vec<u8>>)> {
+	let mut rng = rng(16);
+	let mut t = save_load_tree();
+	let empty = t.save(store).wait().unwrap();
+	assert_eq!(saved_head(store, empty), None);
+	assert!(save_load_tree().load(store, empty).wait().unwrap().get([0]).unwrap().is_none());
+	let mut reference = BTreeMap::new();
+	let mut r = Vec::new();
+
+	for i in 1..2001 {
+		let k: Vec<u8> = (0..2).map(|_| rng.gen()).collect();
+		if rng.gen_weighted_bool(3) {
+			t.delete(&k).unwrap();
+			reference.remove(&k);
+		} else {
+			let v = vec![k[1]; if rng.gen_weighted_bool(8) { 100 } else { 4 }];
+			t.put(&k, &v).unwrap();
+			reference.insert(k, v);
+		}
+
+		if i % 500 == 0 {
+			// Only snapshots can be saved.
+			assert!(t.save(store).wait().is_err());
+
+			let snapshot = t.persistent();
+			// Cursors see a snapshot's buffered writes without draining it, so it can still be saved.
+			assert_eq!(cursor_values(&snapshot, ""), reference.values().cloned().collect::<Vec<_>>());
+			let addr = snapshot.save(store).wait().unwrap();
+			let head = saved_head(store, addr);
+			assert!(head.is_some());
+			assert_eq!(saved_head(store, snapshot.save(store).wait().unwrap()), head);
+			r.push((snapshot, addr, reference.clone()));
+		}
+	}
+
+	r
+}
+
+#[test]
+fn test_save_load() {
+	let mem_store = Rc::new(MemStore::new());
+	let saved = save_snapshots(&mem_store);
+
+	// Later saves write only what changed since the last one.
+	let mut t = saved[3].0.thaw();
+	t.put([0x80, 0], [1]).unwrap();
+	let blocks = mem_store.len();
+	t.persistent().save(&mem_store).wait().unwrap();
+	assert!(mem_store.len() - blocks < 10);
+
+	let path = temp_path("test_save_load");
+	let file_addrs: Vec<_> = save_snapshots(&Rc::new(FileStore::open(&path).unwrap())).iter()
+		.map(|&(_, addr, _)| addr).collect();
+	let file_store = Rc::new(FileStore::open(&path).unwrap());
+
+	// Pages can be read without loading a tree.
+	let (ref snapshot, addr, _) = saved[0];
+	let head = saved_head(&mem_store, addr).unwrap();
+	let page = Page::decode(&mem_store.get(head.0).wait().unwrap()).unwrap();
+	assert!(page.txid == snapshot.counter());
+	assert!(!page.children.is_empty());
+
+	let mut loaded = Vec::new();
+	for (&(ref snapshot, addr, ref reference), &file_addr) in saved.iter().zip(&file_addrs) {
+		for t in vec![save_load_tree().load(&mem_store, addr).wait().unwrap(),
+			save_load_tree().load(&file_store, file_addr).wait().unwrap()] {
+			test_against_reference(&t, reference);
+			assert!(t.counter() == snapshot.counter());
+			assert!(t.is_frozen());
+			t.check_invariants();
+		}
+		loaded.push(save_load_tree().load(&file_store, file_addr).wait().unwrap());
+	}
+
+	// Trees can only be loaded with the settings they were saved with.
+	let addr = saved[0].1;
+	for t in vec![PersistentBTree::with_node_capacity(4).with_chunk_threshold(64),
+		save_load_tree().with_merge_operator(counter_operator()), save_load_tree().with_chunk_threshold(32)] {
+		assert!(t.load(&mem_store, addr).wait().is_err());
+	}
+
+	// Loaded snapshots keep their txids, so diffs between them work.
+	let diff = loaded[3].diff(loaded[2].counter());
+	for (k, v) in &saved[3].2 {
+		if saved[2].2.get(k) != Some(v) {
+			assert_eq!(diff.get(k).unwrap(), Some(&v[..]));
+		}
+	}
+
+	// Loaded snapshots can be thawed and written.
+	let mut t = loaded[3].thaw();
+	t.put([0, 0], [1]).unwrap();
+	t.delete([0xff, 0xff]).unwrap();
+	test_get_str(&t, "\0\0", Some("\u{1}"));
+	t.check_invariants();
+	test_against_reference(&loaded[3], &saved[3].2);
+	fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_open_commit() {
+	let path = temp_path("test_open_commit");
+	let mut rng = rng(17);
+	let mut reference = BTreeMap::new();
+
+	let mut t = save_load_tree().open(&path).unwrap();
+	test_against_reference(&t, &reference);
+	assert!(PersistentBTree::new().commit().is_err());
+
+	for round in 0..4 {
+		for _ in 0..500 {
+			let k: Vec<u8> = (0..2).map(|_| rng.gen()).collect();
+			if rng.gen_weighted_bool(3) {
+				t.delete(&k).unwrap();
+				reference.remove(&k);
+			} else {
+				let v = vec![k[1]; if rng.gen_weighted_bool(8) { 100 } else { 4 }];
+				t.put(&k, &v).unwrap();
+				reference.insert(k, v);
+			}
+		}
+		t.commit().unwrap();
+		let counter = t.counter();
+
+		// Uncommitted writes are replayed from the log on reopen.
+		t.put([0xff, 0xff, round], [round]).unwrap();
+		reference.insert(vec![0xff, 0xff, round], vec![round]);
+		t = save_load_tree().open(&path).unwrap();
+		test_against_reference(&t, &reference);
+		assert!(t.counter() == counter);
+		assert!(!t.is_frozen());
+		t.check_invariants();
+	}
+
+	// Files can only be opened with the settings they were committed with. Without buffers, writes buffered
+	// in the file's nodes would be lost.
+	assert!(PersistentBTree::with_node_capacity(8).open(&path).is_err());
+	assert!(PersistentBTree::with_node_capacity(4).with_chunk_threshold(64).open(&path).is_err());
+	assert!(save_load_tree().with_merge_operator(counter_operator()).open(&path).is_err());
+	fs::remove_file(&path).unwrap();
+	fs::remove_file(wal_path(&path)).unwrap();
+}
+
+#[test]
+fn test_node_cache() {
+	let path = temp_path("test_node_cache");
+	let mut rng = rng(18);
+	let mut reference = BTreeMap::new();
+
+	let mut t = save_load_tree().with_cache_capacity(4096).open(&path).unwrap();
+	for _ in 0..2000 {
+		let k: Vec<u8> = (0..2).map(|_| rng.gen()).collect();
+		let v = vec![k[1]; if rng.gen_weighted_bool(8) { 100 } else { 4 }];
+		t.put(&k, &v).unwrap();
+		reference.insert(k, v);
+	}
+	t.commit().unwrap();
+
+	// Chunked values are loaded when read, and charged to the cache then.
+	let t = save_load_tree().with_cache_capacity(4096).open(&path).unwrap();
+	let large_key = reference.iter().find(|&(_, v)| v.len() == 100).unwrap().0.clone();
+	{
+		let e = t.entry(&large_key).unwrap().unwrap();
+		let bytes = t.cache_bytes();
+		assert_eq!(t.chunk_count(), 0);
+		assert_eq!(e.get(), &reference[&large_key][..]);
+		assert_eq!(t.chunk_count(), 1);
+		assert_eq!(t.cache_bytes(), bytes + 100);
+	}
+
+	test_against_reference(&t, &reference);
+	let faults = t.cache_faults();
+	test_against_reference(&t, &reference);
+	// The tree is larger than the cache, so nodes were evicted and loaded again.
+	assert!(t.cache_faults() > faults);
+	t.get([0, 0]).unwrap();
+	assert!(t.cache_bytes() < 8192);
+
+	// Values and cursors outlive the nodes they were read from.
+	let values: Vec<_> = reference.keys().map(|k| t.get(k).unwrap().unwrap()).collect();
+	let mut c = Tree::cursor(&t, []).unwrap();
+	for (k, v) in reference.keys().zip(values) {
+		assert_eq!(v, &reference[k][..]);
+		assert_eq!(c.get(), v);
+		t.get(k).unwrap();
+		c.next().unwrap();
+	}
+
+	let mut t = t.thaw();
+	t.put([0, 0, 0], [1]).unwrap();
+	t.commit().unwrap();
+	reference.insert(vec![0, 0, 0], vec![1]);
+	let t = save_load_tree().with_cache_capacity(4096).open(&path).unwrap();
+	test_against_reference(&t, &reference);
+	t.check_invariants();
+	drop(t);
+
+	// So do chunked values that can't be read.
+	let t = save_load_tree().with_cache_capacity(4096).open(&path).unwrap();
+	let contents = fs::read(&path).unwrap();
+	{
+		let e = t.entry(&large_key).unwrap().unwrap();
+		fs::write(&path, vec![0xff; contents.len()]).unwrap();
+		// Entries can't fail, so the value reads as empty, and the tree returns the error from then on.
+		assert!(e.get().is_empty());
+	}
+	assert!(t.get(&large_key).is_err());
+	assert!(Tree::cursor(&t, []).is_err());
+	drop(t);
+	fs::write(&path, contents).unwrap();
+
+	// Nodes that can't be read make the tree fail from then on. Children are written before their parents,
+	// so corrupting the middle of the file leaves the head and the first leaf readable.
+	{
+		use std::io::{Seek, SeekFrom, Write};
+
+		let len = fs::metadata(&path).unwrap().len();
+		let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+		file.seek(SeekFrom::Start(len / 4)).unwrap();
+		file.write_all(&vec![0xff; (len / 4) as usize]).unwrap();
+	}
+	let mut t = save_load_tree().open(&path).unwrap();
+	// Cursors report the nodes they can't read, rather than stopping early.
+	let mut c = Tree::cursor(&t, []).unwrap();
+	let mut count = 0;
+	let mut step = Ok(c.exists());
+	while let Ok(true) = step {
+		count += 1;
+		step = c.next();
+	}
+	assert!(count < reference.len());
+	assert!(step.is_err());
+	assert!(!c.exists());
+	assert!(t.get([0, 0]).is_err());
+	assert!(Tree::cursor(&t, []).is_err());
+	assert!(t.put([0, 0], [1]).is_err());
+	assert!(t.commit().is_err());
+
+	fs::remove_file(&path).unwrap();
+	fs::remove_file(wal_path(&path)).unwrap();
+}
+
+#[test]
+fn test_write_ahead_log() {
+	let path = temp_path("test_write_ahead_log");
+	let log_tree = || save_load_tree().with_log_group_size(4);
+	let mut reference = BTreeMap::new();
+
+	let mut t = log_tree().open(&path).unwrap();
+	for i in 0..10u8 {
+		t.put([i], [i]).unwrap();
+		reference.insert(vec![i], vec![i]);
+	}
+	t.delete([3]).unwrap();
+	reference.remove(&vec![3]);
+	t.entry_mut([4]).unwrap().unwrap().get_mut()[0] = 40;
+	t.cursor_mut([5]).unwrap().set([50]);
+	reference.insert(vec![4], vec![40]);
+	reference.insert(vec![5], vec![50]);
+	t.sync_log().unwrap();
+
+	// Without a sync, a crash loses the writes of the current group.
+	t.put([20], [20]).unwrap();
+	mem::forget(t);
+	let mut t = log_tree().open(&path).unwrap();
+	test_against_reference(&t, &reference);
+
+	t.commit().unwrap();
+	assert_eq!(fs::metadata(wal_path(&path)).unwrap().len(), 0);
+	t.put([30], [30]).unwrap();
+	t.sync_log().unwrap();
+	let stale_log = fs::read(wal_path(&path)).unwrap();
+	t.commit().unwrap();
+	t.delete([30]).unwrap();
+	t.commit().unwrap();
+	drop(t);
+
+	// Records already committed are skipped, even if we crashed before truncating the log.
+	fs::write(wal_path(&path), stale_log).unwrap();
+	let t = log_tree().open(&path).unwrap();
+	test_against_reference(&t, &reference);
+	t.check_invariants();
+
+	fs::remove_file(&path).unwrap();
+	fs::remove_file(wal_path(&path)).unwrap();
+}
+
+#[test]
+fn test_transient_arena() {
+	let mut t = PersistentBTree::with_node_capacity(4);
+	assert_eq!(t.transient_node_count(), 0);
+	for i in 0..200u32 {
+		t.put(format!("{:04}", i), "x").unwrap();
+	}
+	assert!(t.transient_node_count() > 0);
+
+	// Snapshots move every transient node out of the arena and free it.
+	let snapshot = t.persistent();
+	assert_eq!(t.transient_node_count(), 0);
+
+	// Writes after a snapshot fork only the path to the written key.
+	t.put("0100", "y").unwrap();
+	let forked = t.transient_node_count();
+	assert!(forked > 0 && forked < 10);
+	t.put("0100", "z").unwrap();
+	assert_eq!(t.transient_node_count(), forked);
+
+	test_get_str(&snapshot, "0100", Some("x"));
+	test_get_str(&t, "0100", Some("z"));
+	t.check_invariants();
+	snapshot.check_invariants();
+
+	// Copies of a transient tree leave its nodes alone, so cursors into it stay valid.
+	let mut c = Tree::cursor(&t, "0198").unwrap();
+	let copy = t.clone();
+	let thawed = t.thaw();
+	assert_eq!(t.transient_node_count(), forked);
+	assert!(c.next().unwrap());
+	assert_eq!(c.get(), b"x");
+	assert!(!c.next().unwrap());
+	t.put("0100", "w").unwrap();
+	test_get_str(&copy, "0100", Some("z"));
+	test_get_str(&thawed, "0100", Some("z"));
+	copy.check_invariants();
+
+	// Nodes discarded by deletes are reused, so one long transaction only grows with its live nodes.
+	let mut t = PersistentBTree::with_node_capacity(4);
+	let mut counts = Vec::new();
+	for _ in 0..5 {
+		for i in 0..2000u32 {
+			t.put(i.to_be_bytes(), "x").unwrap();
+		}
+		for i in 0..2000u32 {
+			t.delete(i.to_be_bytes()).unwrap();
+		}
+		counts.push(t.transient_node_count());
+	}
+	assert!(counts.iter().all(|&count| count == counts[0]), "{:?}", counts);
+	t.check_invariants();
+}
+
+fn encode_u64(n: u64) -> Vec<u8> {
+	(0..8).map(|i| (n >> (i * 8)) as u8).collect()
+}
+
+fn decode_u64(v: &[u8]) -> u64 {
+	v.iter().rev().fold(0, |n, &b| (n << 8) | b as u64)
+}
+
+/// A merge operator for counters, where deltas are added to values.
+fn counter_operator() -> MergeOperator {
+	fn apply(_: &[u8], v: Option<&[u8]>, delta: &[u8]) -> Vec<u8> {
+		encode_u64(v.map_or(0, decode_u64).wrapping_add(decode_u64(delta)))
+	}
+	fn combine(_: &[u8], older: &[u8], newer: &[u8]) -> Vec<u8> {
+		encode_u64(decode_u64(older).wrapping_add(decode_u64(newer)))
+	}
+
+	MergeOperator { apply: apply, combine: combine }
+}
+
+#[test]
+fn test_upserts() {
+	// Trees without a merge operator reject deltas.
+	assert!(PersistentBTree::new().upsert("foo", encode_u64(1)).is_err());
+
+	// Without buffers, deltas are applied as they are written.
+	for &buffer_capacity in &[0, 4] {
+		let mut rng = rng(15);
+		let mut t = PersistentBTree::with_node_capacity(4).with_buffer_capacity(buffer_capacity)
+			.with_merge_operator(counter_operator());
+		let mut reference = BTreeMap::new();
+		let mut snapshots = Vec::new();
+
+		for i in 0..3000 {
+			let k = vec![rng.gen_range(0, 2), rng.gen()];
+			if rng.gen_weighted_bool(10) {
+				t.delete(&k).unwrap();
+				reference.remove(&k);
+			} else if rng.gen_weighted_bool(10) {
+				let n = rng.gen_range(0, 100);
+				t.put(&k, encode_u64(n)).unwrap();
+				reference.insert(k, encode_u64(n));
+			} else {
+				let n = rng.gen_range(0, 100);
+				t.upsert(&k, encode_u64(n)).unwrap();
+				let old = reference.get(&k).map_or(0, |v| decode_u64(v));
+				reference.insert(k, encode_u64(old + n));
+			}
+
+			// Lookups merge deltas still buffered in branches.
+			let k = vec![rng.gen_range(0, 2), rng.gen()];
+			assert_eq!(t.get(&k).unwrap(), reference.get(&k).map(Vec::as_slice));
+
+			if i % 300 == 0 {
+				t.check_invariants();
+				snapshots.push((t.persistent(), reference.clone()));
+			}
+		}
+
+		// Deltas merged after a snapshot are not changes since that snapshot.
+		let base = t.persistent();
+		test_against_reference(&t, &reference);
+		// Lookups merge deltas without writing, so snapshots stay unchanged and can still be saved.
+		test_against_reference(&base, &reference);
+		assert!(base.save(&Rc::new(MemStore::new())).wait().is_ok());
+		assert!(!Tree::cursor(&t.diff(base.counter()), []).unwrap().exists());
+
+		// Diffs see new deltas.
+		let mut changed = BTreeMap::new();
+		for _ in 0..100 {
+			let k = vec![0, rng.gen()];
+			t.upsert(&k, encode_u64(1)).unwrap();
+			let v = encode_u64(reference.get(&k).map_or(0, |v| decode_u64(v)) + 1);
+			changed.insert(k.clone(), v.clone());
+			reference.insert(k, v);
+		}
+		test_against_reference(&t.diff(base.counter()), &changed);
+
+		test_against_reference(&t, &reference);
+		t.compact().unwrap();
+		t.check_invariants();
+		test_against_reference(&t, &reference);
+		for (snapshot, reference) in snapshots {
+			test_against_reference(&snapshot, &reference);
+			snapshot.check_invariants();
+		}
+	}
+}
+
+fn test_hierarchical_keys(t: &mut PersistentBTree) {
+	let mut rng = rng(12);
+	let mut reference = BTreeMap::new();
+	let mut snapshots = Vec::new();
+
+	// Long keys with heavy prefix overlap, mixed with keys outside the hierarchy.
+	for i in 0..4000 {
+		let k = if rng.gen_weighted_bool(10) {
+			format!("{}", rng.gen_range(0, 100))
+		} else {
+			format!("/users/{:03}/posts/{:02}", rng.gen_range(0, 100), rng.gen_range(0, 30))
+		}.into_bytes();
+
+		if rng.gen_weighted_bool(3) {
+			t.delete(&k).unwrap();
+			reference.remove(&k);
+		} else {
+			t.put(&k, &k).unwrap();
+			reference.insert(k.clone(), k);
+		}
+
+		if i % 1000 == 0 {
+			snapshots.push((t.persistent(), reference.clone()));
+		}
+	}
+
+	test_against_reference(&t, &reference);
+	let mut c = Tree::cursor(t, "/users/050").unwrap();
+	for (k, _) in reference.range(b"/users/050".to_vec()..).take(50) {
+		assert_eq!(c.key().unwrap(), *k);
+		c.next().unwrap();
+	}
+
+	let posts = t.suffix("/users/042/");
+	let expected: BTreeMap<_, _> = reference.iter()
+		.filter(|&(k, _)| k.starts_with(b"/users/042/"))
+		.map(|(k, v)| (k[11..].to_vec(), v.clone())).collect();
+	test_against_reference(&posts, &expected);
+
+	for (snapshot, reference) in snapshots {
+		test_against_reference(&snapshot, &reference);
+		snapshot.check_invariants();
+	}
+}
+
+fn smoke_test_cursors(t: &mut PersistentBTree) {
+	t.put("foo", "bar").unwrap();
+	t.put("sna", "foo").unwrap();
+	t.put("fop", "baz").unwrap();
+
+	// Test beginning cursors
+	let snap = t.persistent();
+	{
+		let mut c = Tree::cursor(&snap, "").unwrap();
+		assert_eq!(c.get(), b"bar");
+		assert!(c.next().unwrap());
+		assert_eq!(c.get(), b"baz");
+		assert!(c.next().unwrap());
+		assert_eq!(c.get(), b"foo");
+		assert!(!c.next().unwrap());
+		assert!(!c.exists());
+	}
+
+	// Modifying the tree does not affect cursors over snapshots.
+	let mut c = Tree::cursor(&snap, "").unwrap();
+	t.put("foo", "qux").unwrap();
+	t.delete("fop").unwrap();
+	assert_eq!(c.get(), b"bar");
+	assert!(c.next().unwrap());
+	assert_eq!(c.get(), b"baz");
+
+	// Test middle cursors
+	assert_eq!(cursor_values(&snap, "fop"), vec![b"baz".to_vec(), b"foo".to_vec()]);
+	assert_eq!(cursor_values(&snap, "foq"), vec![b"foo".to_vec()]);
+	assert_eq!(cursor_values(t, "fop"), vec![b"foo".to_vec()]);
+}
+
+fn smoke_test_diff_cursors(t: &mut PersistentBTree) {
+	for i in 0..500 as u32 {
+		t.put([(i >> 8) as u8, i as u8], "old").unwrap();
+	}
+	let t0 = t.persistent();
+
+	t.put([0, 10], "new").unwrap();
+	t.put([1, 200], "new").unwrap();
+	t.put([9], "new").unwrap();
+	let t1 = t.persistent();
+
+	let diff = t1.diff(t0.counter());
+	assert_eq!(cursor_values(&diff, ""), vec![b"new".to_vec(); 3]);
+
+	let mut c = Tree::cursor(&diff, [0, 11]).unwrap();
+	assert_eq!(c.get(), b"new");
+	assert!(c.next().unwrap());
+	assert_eq!(c.get(), b"new");
+	assert!(!c.next().unwrap());
+
+	// Nothing changed.
+	assert_eq!(cursor_values(&t1.diff(t1.counter()), ""), Vec::<Vec<u8>>::new());
+	assert_eq!(cursor_values(&t1.diff(t0.counter()).suffix([1]), ""), vec![b"new".to_vec()]);
+}
+
+/// Collects the keys visible to a cursor, with their values, or None if the key was removed.
+fn diff_entries(t: &PersistentBTree, k: &str) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+	let mut r = Vec::new();
+	let mut c = Tree::cursor(t, k).unwrap();
+
+	while c.exists() {
+		let v = if c.is_removed() { None } else { Some(c.get().to_vec()) };
+		r.push((c.key().unwrap(), v));
+		c.next().unwrap();
+	}
+
+	r
+}
+
+fn test_diff_cursors_many(t: &mut PersistentBTree) {
+	let mut rng = rng(7);
+	let mut reference = BTreeMap::new();
+
+	for _ in 0..2000 {
+		let k: Vec<u8> = (0..2).map(|_| rng.gen()).collect();
+		t.put(&k, [0]).unwrap();
+		reference.insert(k, Some(vec![0]));
+	}
+	let t0 = t.persistent();
+
+	// Deletes rebalance nodes, moving unchanged keys into new nodes.
+	for round in 1..4 {
+		for _ in 0..200 {
+			let k: Vec<u8> = (0..2).map(|_| rng.gen()).collect();
+			if rng.gen_weighted_bool(3) {
+				t.put(&k, [round]).unwrap();
+				reference.insert(k, Some(vec![round]));
+			} else {
+				t.delete(&k).unwrap();
+				if let Some(&Some(_)) = reference.get(&k) {
+					reference.insert(k, None);
+				}
+			}
+		}
+		t.persistent();
+	}
+
+	let changed: Vec<_> = reference.into_iter().filter(|&(_, ref v)| v != &Some(vec![0])).collect();
+	assert_eq!(diff_entries(&t.diff(t0.counter()), ""), changed);
+}
+
+fn smoke_test_tombstones(t: &mut PersistentBTree) {
+	t.put("foo", "bar").unwrap();
+	t.put("fop", "baz").unwrap();
+	t.put("sna", "foo").unwrap();
+
+	// Without snapshots, deletes leave nothing behind.
+	t.delete("sna").unwrap();
+	let t0 = t.persistent();
+
+	t.delete("foo").unwrap();
+	t.delete("nonexistent").unwrap();
+	test_get_str(t, "foo", None);
+	assert_eq!(cursor_values(t, ""), vec![b"baz".to_vec()]);
+	let t1 = t.persistent();
+
+	let diff = t1.diff(t0.counter());
+	assert_eq!(diff_entries(&diff, ""), vec![(b"foo".to_vec(), None)]);
+	let e = diff.entry("foo").unwrap().unwrap();
+	assert!(e.is_removed());
+	assert!(diff.entry("fop").unwrap().is_none());
+	assert_eq!(diff_entries(&diff.suffix("f"), ""), vec![(b"oo".to_vec(), None)]);
+
+	// Deleting a tombstone does nothing; overwriting one revives the key.
+	t.delete("foo").unwrap();
+	t.put("foo", "qux").unwrap();
+	let t2 = t.persistent();
+	assert_eq!(diff_entries(&t2.diff(t1.counter()), ""), vec![(b"foo".to_vec(), Some(b"qux".to_vec()))]);
+	assert_eq!(diff_entries(&t2.diff(t0.counter()), ""), vec![(b"foo".to_vec(), Some(b"qux".to_vec()))]);
+
+	// Older snapshots are unaffected.
+	test_get_str(&t0, "foo", Some("bar"));
+	test_get_str(&t1, "foo", None);
+	t.check_invariants();
+}
+
+fn smoke_test_compact(t: &mut PersistentBTree) {
+	for i in 0..100 as u8 {
+		t.put([i], [i]).unwrap();
+	}
+	let t0 = t.persistent();
+
+	for i in 0..50 as u8 {
+		t.delete([i]).unwrap();
+	}
+	let t1 = t.persistent();
+
+	// Unfrozen tombstones are kept.
+	t.delete([50]).unwrap();
+	assert_eq!(t.compact().unwrap(), 0);
+
+	// t0 can still see every tombstone.
+	let t2 = t.persistent();
+	assert_eq!(t.compact().unwrap(), 0);
+	assert_eq!(diff_entries(&t.diff(t0.counter()), "").len(), 51);
+
+	// Without t0, the tombstones frozen in t1 are invisible to every diff.
+	drop(t0);
+	assert_eq!(t.compact().unwrap(), 50);
+	assert_eq!(diff_entries(&t.diff(t1.counter()), ""), vec![(vec![50], None)]);
+	assert_eq!(cursor_values(t, "").len(), 49);
+	t.check_invariants();
+
+	// Snapshots are unaffected by compaction, and diffs pin tombstones too.
+	let d = t2.diff(t1.counter());
+	drop(t1);
+	drop(t2);
+	assert_eq!(t.compact().unwrap(), 0);
+	assert_eq!(diff_entries(&d, ""), vec![(vec![50], None)]);
+	drop(d);
+	assert_eq!(t.compact().unwrap(), 1);
+	assert!(t.freeze().compact().is_err());
+	t.check_invariants();
+
+	// Once every snapshot is gone, deletes stop leaving tombstones.
+	t.delete([60]).unwrap();
+	assert_eq!(t.compact().unwrap(), 0);
+	t.check_invariants();
+}
+
 // // TODO: maybe these should just be normal tests? are we going with only one type of tree or multiple?
-// deftests! {
-// 	PersistentBTree => {
-// 		pbtree_smoke_test_insert, smoke_test_insert,
-// 		pbtree_smoke_test_get, smoke_test_get,
-// 		// pbtree_smoke_test_delete, smoke_test_delete,
-// 		pbtree_smoke_test_snapshot, smoke_test_snapshot,
-// 		pbtree_smoke_test_diffs, smoke_test_diffs,
-// 		pbtree_smoke_test_cursors, smoke_test_cursors,
-// 		// pbtree_smoke_test_diff_cursors, smoke_test_diff_cursors,
-// 	},
-// }
+deftests! {
+	PersistentBTree => {
+		pbtree_smoke_test_insert, smoke_test_insert,
+		pbtree_smoke_test_get, smoke_test_get,
+		pbtree_smoke_test_overwrite, smoke_test_overwrite,
+		pbtree_test_overwrite_many, test_overwrite_many,
+		pbtree_smoke_test_entry_mut, smoke_test_entry_mut,
+		pbtree_test_cursor_mut_scan, test_cursor_mut_scan,
+		pbtree_smoke_test_delete, smoke_test_delete,
+		pbtree_test_delete_rebalance, test_delete_rebalance,
+		pbtree_smoke_test_suffix, smoke_test_suffix,
+		pbtree_test_suffix_many, test_suffix_many,
+		pbtree_smoke_test_subrange, smoke_test_subrange,
+		pbtree_test_subrange_many, test_subrange_many,
+		pbtree_smoke_test_suffix_mut, smoke_test_suffix_mut,
+		pbtree_smoke_test_subrange_mut, smoke_test_subrange_mut,
+		pbtree_smoke_test_snapshot, smoke_test_snapshot,
+		pbtree_test_snapshot_many, test_snapshot_many,
+		pbtree_test_snapshots_frequent, test_snapshots_frequent,
+		pbtree_smoke_test_diffs, smoke_test_diffs,
+		pbtree_test_diffs_many, test_diffs_many,
+		pbtree_smoke_test_cursors, smoke_test_cursors,
+		pbtree_smoke_test_merge, smoke_test_merge,
+		pbtree_test_hierarchical_keys, test_hierarchical_keys,
+		pbtree_test_merge_many, test_merge_many,
+		pbtree_smoke_test_diff_cursors, smoke_test_diff_cursors,
+		pbtree_test_diff_cursors_many, test_diff_cursors_many,
+		pbtree_smoke_test_tombstones, smoke_test_tombstones,
+		pbtree_smoke_test_compact, smoke_test_compact,
+	},
+}
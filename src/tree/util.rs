@@ -20,12 +20,35 @@ pub fn rotate_right<T>(arr: &mut [T], pos: usize) {
 	// arr = deabc
 }
 
-// /// Checked rotate left. The array [1, 2, 3] rotated by 1 becomes [2, 3, 1].
-// pub fn rotate_left<T>(arr: &mut [T], pos: usize) {
-// 	// borrow checker...
-// 	let len = arr.len() - pos;
-// 	rotate_right(arr, len);
-// }
+/// Checked rotate left. The array [1, 2, 3] rotated by 1 becomes [2, 3, 1].
+pub fn rotate_left<T>(arr: &mut [T], pos: usize) {
+	if arr.len() < pos {
+		panic!("{} out of bounds of array length {}", pos, arr.len())
+	}
+	// borrow checker...
+	let len = arr.len() - pos;
+	rotate_right(arr, len);
+}
+
+/// Returns the least key greater than every key with the given prefix, or None if there is no such key
+/// (viz. the prefix is empty or all 0xff).
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+	let mut r = prefix.to_vec();
+
+	while let Some(last) = r.pop() {
+		if last < u8::max_value() {
+			r.push(last + 1);
+			return Some(r)
+		}
+	}
+
+	None
+}
+
+/// Returns the length of the longest common prefix of the given byte strings.
+pub fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+	a.iter().zip(b).take_while(|&(x, y)| x == y).count()
+}
 
 /// Checked swap of two slices of identical length.
 pub fn swap<T>(a: &mut [T], b: &mut [T]) {
@@ -54,15 +77,22 @@ pub fn rotate_in<T>(item: &mut T, arr: &mut [T]) {
 	mem::forget(dummy);
 }
 
-// pub fn rotate_out<T>(arr: &mut [T], item: &mut T) {
-// 	// Unfortunately we must be a little unsafe here, even though this is supposed to be a foolproof fn
-// 	// Optimizer should remove the extra mem copies
-// 	let mut dummy: [T; 1] = unsafe { mem::uninitialized() };
-// 	mem::swap(item, &mut dummy[0]);
-// 	rotate_out_slice(arr, dummy.as_mut());
-// 	mem::swap(item, &mut dummy[0]);
-// 	mem::forget(dummy); // How odd this isn't unsafe...
-// }
+/// Removes the first T from the given slice, moving each element over by 1.
+/// The removed T is swapped into the given T reference, and the given T is moved to the end of the slice.
+pub fn rotate_out<T>(arr: &mut [T], item: &mut T) {
+	if arr.len() == 0 {
+		// because rotate_out_slice can't panic
+		panic!("cannot rotate out of array length 0")
+	}
+
+	// Unfortunately we must be a little unsafe here, even though this is supposed to be a foolproof fn
+	// Optimizer should remove the extra mem copies
+	let mut dummy: [T; 1] = unsafe { mem::uninitialized() };
+	mem::swap(item, &mut dummy[0]);
+	rotate_out_slice(arr, dummy.as_mut());
+	mem::swap(item, &mut dummy[0]);
+	mem::forget(dummy); // How odd this isn't unsafe...
+}
 
 /// Inserts the given source slice into the beginning of the given destination slice, moving each element over
 /// by the required amount. The excess elements at the end of the destination slice
@@ -75,12 +105,13 @@ pub fn rotate_in_slice<T>(src: &mut [T], dst: &mut [T]) {
 	swap(src, &mut dst[..srclen]);
 }
 
-// pub fn rotate_out_slice<T>(src: &mut [T], dst: &mut [T]) {
-// 	// Borrow checker tricks
-// 	let dstlen = dst.len();
-// 	// Rotating forward len - n is equivalent to rotating backward n
-// 	// let len = src.len() - dstlen;
+/// Removes elements from the beginning of the given source slice into the given destination slice,
+/// moving each remaining element over by the required amount. The elements previously in the destination slice
+/// are swapped into the end of the source slice, in order.
+pub fn rotate_out_slice<T>(src: &mut [T], dst: &mut [T]) {
+	// Borrow checker tricks
+	let dstlen = dst.len();
 
-// 	swap(&mut src[..dstlen], dst);
-// 	rotate_left(src, dstlen);
-// }
+	swap(&mut src[..dstlen], dst);
+	rotate_left(src, dstlen);
+}